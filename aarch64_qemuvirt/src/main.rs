@@ -18,7 +18,7 @@ use log::info;
 extern "C" fn k_main(_device_tree_ptr: usize) -> ! {
     kernel::hal::cpu::disable_fp_trapping();
 
-    static PL011: Pl011 = Pl011::new(0x0900_0000);
+    static PL011: Pl011 = Pl011::new(kernel::hal::mm::phys_to_virt(0x0900_0000));
     kernel::kernel_console::set_earlyinit_console(&PL011);
 
     kernel::kernel_console::init_logging().unwrap();
@@ -34,7 +34,8 @@ extern "C" fn k_main(_device_tree_ptr: usize) -> ! {
         asm!("dmb SY");
     }
 
-    let device_tree = kernel::device_tree::DeviceTree::new(DTB_ADDR).unwrap();
+    let device_tree =
+        kernel::device_tree::DeviceTree::new(kernel::hal::mm::phys_to_virt(DTB_ADDR)).unwrap();
 
     kernel::generic_main::generic_main::<LAUNCH_TESTS>(device_tree, &[&PL011]);
 }
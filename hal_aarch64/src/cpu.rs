@@ -1,5 +1,22 @@
 use cortex_a::{asm, registers::*};
-use tock_registers::interfaces::{ReadWriteable, Writeable};
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+use tock_registers::LocalRegisterCopy;
+
+/// How many cpus the kernel supports, see [`cpu_index`].
+pub const MAX_CPUS: usize = 16;
+
+/// The index of the calling cpu, from 0 to `MAX_CPUS - 1`: the qemu virt machine numbers its cpus
+/// in MPIDR_EL1.Aff0 and leaves the other affinity levels at 0.
+pub fn cpu_index() -> usize {
+    let mpidr = MPIDR_EL1.get() & 0xff_00ff_ffff;
+    assert!(
+        mpidr < MAX_CPUS as u64,
+        "cpu with MPIDR {:#x} isn't supported",
+        mpidr
+    );
+
+    mpidr as usize
+}
 
 pub fn disable_fp_trapping() {
     // Disable trapping of FP instructions.
@@ -24,3 +41,28 @@ pub fn clear_physical_timer() {
 pub fn unmask_interrupts() {
     DAIF.write(DAIF::A::Unmasked + DAIF::I::Unmasked + DAIF::F::Unmasked);
 }
+
+pub fn mask_interrupts() {
+    DAIF.modify(DAIF::I::Masked + DAIF::F::Masked);
+}
+
+pub fn interrupts_enabled() -> bool {
+    !DAIF.is_set(DAIF::I)
+}
+
+/// DAIF as [`save_and_mask_interrupts`] found it, only its I and F bits are put back.
+#[derive(Clone, Copy)]
+pub struct InterruptState(LocalRegisterCopy<u64, DAIF::Register>);
+
+/// Mask the interrupts of the cpu, returning how they were for [`restore_interrupts`].
+pub fn save_and_mask_interrupts() -> InterruptState {
+    let daif = DAIF.extract();
+    mask_interrupts();
+
+    InterruptState(daif)
+}
+
+/// Put DAIF.I and DAIF.F back as they were, the debug and SError masks are left alone.
+pub fn restore_interrupts(state: InterruptState) {
+    DAIF.modify(DAIF::I.val(state.0.read(DAIF::I)) + DAIF::F.val(state.0.read(DAIF::F)));
+}
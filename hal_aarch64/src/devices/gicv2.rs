@@ -2,6 +2,7 @@ use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 use tock_registers::register_bitfields;
 use tock_registers::registers::{ReadOnly, ReadWrite};
 
+use hal_core::irq::{IrqChip, Trigger};
 use hal_core::Error;
 
 pub struct GicV2 {
@@ -17,36 +18,25 @@ impl GicV2 {
                 .unwrap()
         };
         let cpu = unsafe { (cpu_base as *const GicCpu).as_ref().unwrap() };
-        let mut gic = Self { distributor, cpu };
+        let gic = Self { distributor, cpu };
 
         gic.init_distributor();
 
         gic
     }
 
-    pub fn disable_interrupts(&mut self) {
+    pub fn disable_interrupts(&self) {
         self.distributor
             .CTLR
             .modify(GICD_CTLR::EnableGrp0::Disable + GICD_CTLR::EnableGrp1::Disable);
     }
 
-    pub fn enable_interrupts(&mut self) {
+    pub fn enable_interrupts(&self) {
         self.distributor
             .CTLR
             .modify(GICD_CTLR::EnableGrp0::Enable + GICD_CTLR::EnableGrp1::Enable);
     }
 
-    pub fn get_int(&mut self) -> Result<u32, Error> {
-        let intno = self.cpu.IAR.get();
-
-        Ok(intno)
-    }
-
-    pub fn clear_int(&mut self, int: u32) {
-        // TODO: check (maybe in the TRM) if this could fail / give an error.
-        self.cpu.EOIR.modify(GICC_EOIR::EOIINTID.val(int));
-    }
-
     pub fn nlines(&self) -> usize {
         let n = self.distributor.TYPER.read(GICD_TYPER::ITLinesNumber) as usize;
 
@@ -54,7 +44,7 @@ impl GicV2 {
     }
 
     /// Put the Gic in a known state.
-    fn init_distributor(&mut self) {
+    fn init_distributor(&self) {
         self.disable_interrupts();
 
         for i in 0..(self.nlines() / 32) {
@@ -98,17 +88,93 @@ impl GicV2 {
         );
     }
 
-    pub fn enable_line(&mut self, line: u32) -> Result<(), Error> {
-        let line = line as usize;
-        let enable_reg_index = line >> 5;
-        let enable_bit: u32 = 1u32 << (line % 32);
+    fn check_line(&self, line: u32) -> Result<usize, Error> {
+        if (line as usize) < self.nlines() {
+            Ok(line as usize)
+        } else {
+            Err(Error::InvalidIrqLine(line))
+        }
+    }
+}
+
+impl IrqChip for GicV2 {
+    fn enable(&self, line: u32) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+
+        // Writing 0 to the other bits doesn't change them.
+        self.distributor.ISENABLER[line / 32].set(1 << (line % 32));
+
+        Ok(())
+    }
+
+    fn disable(&self, line: u32) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+
+        self.distributor.ICENABLER[line / 32].set(1 << (line % 32));
+
+        Ok(())
+    }
+
+    fn set_pending(&self, line: u32) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+        // The SGIs are made pending through GICD_SGIR.
+        if line < 16 {
+            return Err(Error::InvalidIrqLine(line as u32));
+        }
+
+        self.distributor.ISPENDR[line / 32].set(1 << (line % 32));
+
+        Ok(())
+    }
+
+    fn set_priority(&self, line: u32, priority: u8) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+
+        // Lower values are more urgent for the Gic and 0xff is masked by the PMR.
+        self.distributor.IPRIORITYR[line].set(0xff - priority);
+
+        Ok(())
+    }
+
+    fn set_trigger(&self, line: u32, trigger: Trigger) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+        // The trigger of the SGIs is fixed.
+        if line < 16 {
+            return Err(Error::InvalidIrqLine(line as u32));
+        }
 
-        self.distributor.ISENABLER[enable_reg_index]
-            .set(self.distributor.ISENABLER[enable_reg_index].get() | enable_bit);
-        self.distributor.IPRIORITYR[line].set(0x80);
+        // 2 bits per line, the upper one is set for edge triggered lines.
+        let edge_bit = 1 << ((line % 16) * 2 + 1);
+        let cfg = self.distributor.ICFGR[line / 16].get();
+        self.distributor.ICFGR[line / 16].set(match trigger {
+            Trigger::Level => cfg & !edge_bit,
+            Trigger::Edge => cfg | edge_bit,
+        });
 
         Ok(())
     }
+
+    fn claim(&self) -> Option<u32> {
+        let line = self.cpu.IAR.read(GICC_IAR::InterruptID);
+
+        // 1023 is the spurious interrupt, there is nothing to handle.
+        if line == 1023 {
+            None
+        } else {
+            Some(line)
+        }
+    }
+
+    fn complete(&self, line: u32) {
+        self.cpu.EOIR.write(GICC_EOIR::EOIINTID.val(line));
+    }
+
+    fn decode_interrupt(&self, cells: &[u32]) -> Result<(u32, Trigger), Error> {
+        let (line, trigger) = super::decode_gic_interrupt(cells)?;
+        self.check_line(line)?;
+
+        Ok((line, trigger))
+    }
 }
 
 #[repr(C)]
@@ -210,7 +276,7 @@ pub struct GicCpu {
     /// Binary Point Register
     pub BPR: ReadWrite<u32>,
     /// Interrupt Acknowledge Register
-    pub IAR: ReadWrite<u32>,
+    pub IAR: ReadWrite<u32, GICC_IAR::Register>,
     /// End of Interrupt Register
     pub EOIR: ReadWrite<u32, GICC_EOIR::Register>,
     /// Running Priority Register
@@ -0,0 +1,447 @@
+//! The cpu interface of the Gic v3 is made of the ICC_* system registers, the SGIs and PPIs of a
+//! cpu are handled by its redistributor and the distributor only deals with the SPIs.
+
+use core::arch::asm;
+
+use cortex_a::registers::MPIDR_EL1;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+use tock_registers::register_bitfields;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+use hal_core::irq::{IrqChip, Trigger};
+use hal_core::Error;
+
+/// A redistributor is made of 2 of these frames, 4 with the virtual LPIs of the Gic v4.
+const REDISTRIBUTOR_FRAME_SIZE: usize = 0x1_0000;
+
+pub struct GicV3 {
+    pub distributor: &'static GicDistributor,
+    /// The redistributor of the cpu that set up the Gic.
+    pub redistributor: &'static GicRedistributor,
+    pub redistributor_sgi: &'static GicRedistributorSgi,
+}
+
+/// The affinity of the calling cpu, laid out as in the GICD_IROUTER registers.
+fn cpu_affinity() -> u64 {
+    MPIDR_EL1.get() & 0xff_00ff_ffff
+}
+
+impl GicV3 {
+    /// The redistributor of the calling cpu is looked for in the `redistributors` regions, given
+    /// as (address, size). `stride` separates two redistributors when the device tree says so.
+    pub fn new(
+        distributor_base: usize,
+        redistributors: impl Iterator<Item = (usize, usize)>,
+        stride: Option<usize>,
+    ) -> Result<Self, Error> {
+        let distributor = unsafe {
+            (distributor_base as *const GicDistributor)
+                .as_ref()
+                .unwrap()
+        };
+        let redistributor_base = Self::find_redistributor(redistributors, stride)
+            .ok_or(Error::InvalidDeviceTreeNode("reg"))?;
+        let redistributor = unsafe {
+            (redistributor_base as *const GicRedistributor)
+                .as_ref()
+                .unwrap()
+        };
+        let redistributor_sgi = unsafe {
+            ((redistributor_base + REDISTRIBUTOR_FRAME_SIZE) as *const GicRedistributorSgi)
+                .as_ref()
+                .unwrap()
+        };
+        let gic = Self {
+            distributor,
+            redistributor,
+            redistributor_sgi,
+        };
+
+        gic.init_distributor();
+
+        // TODO: this should be moved somewhere else so other cores can run it.
+        gic.init_redistributor();
+        gic.init_cpu();
+
+        Ok(gic)
+    }
+
+    fn find_redistributor(
+        regions: impl Iterator<Item = (usize, usize)>,
+        stride: Option<usize>,
+    ) -> Option<usize> {
+        // GICR_TYPER packs the 4 affinity levels in 32 bits.
+        let mpidr = cpu_affinity();
+        let affinity = ((mpidr >> 8) & 0xff00_0000) | (mpidr & 0xff_ffff);
+
+        for (base, size) in regions {
+            let mut redistributor_base = base;
+
+            while redistributor_base + 2 * REDISTRIBUTOR_FRAME_SIZE <= base + size {
+                let redistributor = unsafe { &*(redistributor_base as *const GicRedistributor) };
+                if redistributor.TYPER.read(GICR_TYPER::AffinityValue) == affinity {
+                    return Some(redistributor_base);
+                }
+                if redistributor.TYPER.is_set(GICR_TYPER::Last) {
+                    break;
+                }
+
+                redistributor_base +=
+                    stride.unwrap_or(if redistributor.TYPER.is_set(GICR_TYPER::VLPIS) {
+                        4 * REDISTRIBUTOR_FRAME_SIZE
+                    } else {
+                        2 * REDISTRIBUTOR_FRAME_SIZE
+                    });
+            }
+        }
+
+        None
+    }
+
+    pub fn nlines(&self) -> usize {
+        let n = self.distributor.TYPER.read(GICD_TYPER::ITLinesNumber) as usize;
+
+        // 1020 to 1023 are special, the LPIs start at 8192.
+        (32 * (n + 1)).min(1020)
+    }
+
+    fn wait_distributor(&self) {
+        while self.distributor.CTLR.is_set(GICD_CTLR::RWP) {}
+    }
+
+    fn wait_redistributor(&self) {
+        while self.redistributor.CTLR.is_set(GICR_CTLR::RWP) {}
+    }
+
+    /// Put the distributor in a known state and route all the SPIs to the calling cpu.
+    fn init_distributor(&self) {
+        self.distributor.CTLR.set(0);
+        self.wait_distributor();
+
+        // The first 32 lines are in the redistributors.
+        for i in 1..(self.nlines() / 32) {
+            // Group 1 is the one of the kernel.
+            self.distributor.IGROUPR[i].set(0xffff_ffff);
+
+            // Disable, clear the pending and active interrupts, 1 bit per line.
+            self.distributor.ICENABLER[i].set(0xffff_ffff);
+            self.distributor.ICPENDR[i].set(0xffff_ffff);
+            self.distributor.ICACTIVER[i].set(0xffff_ffff);
+        }
+        self.wait_distributor();
+
+        for i in 2..(self.nlines() / 16) {
+            // Set all interrupts to level-triggered.
+            self.distributor.ICFGR[i].set(0);
+        }
+
+        self.distributor
+            .CTLR
+            .write(GICD_CTLR::ARE::SET + GICD_CTLR::EnableGrp1::SET);
+        self.wait_distributor();
+
+        // Only taken into account with affinity routing enabled.
+        let affinity = cpu_affinity();
+        for line in 32..self.nlines() {
+            self.distributor.IROUTER[line].set(affinity);
+        }
+    }
+
+    fn init_redistributor(&self) {
+        // The cpu interface doesn't get any interrupt while the redistributor sleeps.
+        self.redistributor
+            .WAKER
+            .modify(GICR_WAKER::ProcessorSleep::CLEAR);
+        while self.redistributor.WAKER.is_set(GICR_WAKER::ChildrenAsleep) {}
+
+        let sgi = self.redistributor_sgi;
+        sgi.IGROUPR0.set(0xffff_ffff);
+        sgi.ICENABLER0.set(0xffff_ffff);
+        self.wait_redistributor();
+        sgi.ICPENDR0.set(0xffff_ffff);
+        sgi.ICACTIVER0.set(0xffff_ffff);
+
+        // The PPIs are level-triggered, the configuration of the SGIs is fixed.
+        sgi.ICFGR[1].set(0);
+    }
+
+    fn init_cpu(&self) {
+        unsafe {
+            // Use the system registers instead of the memory mapped cpu interface.
+            asm!(
+                "mrs {tmp}, icc_sre_el1",
+                "orr {tmp}, {tmp}, #1",
+                "msr icc_sre_el1, {tmp}",
+                "isb",
+                tmp = out(reg) _,
+            );
+
+            // Accept ALL interrupts.
+            asm!("msr icc_pmr_el1, {}", in(reg) 0xffu64);
+
+            // Set maximum amount of bits to be used for Group priority field.
+            asm!("msr icc_bpr1_el1, xzr");
+
+            asm!("msr icc_igrpen1_el1, {}", "isb", in(reg) 1u64);
+        }
+    }
+
+    fn check_line(&self, line: u32) -> Result<usize, Error> {
+        if (line as usize) < self.nlines() {
+            Ok(line as usize)
+        } else {
+            Err(Error::InvalidIrqLine(line))
+        }
+    }
+}
+
+impl IrqChip for GicV3 {
+    fn enable(&self, line: u32) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+
+        // Writing 0 to the other bits doesn't change them.
+        if line < 32 {
+            self.redistributor_sgi.ISENABLER0.set(1 << line);
+        } else {
+            self.distributor.ISENABLER[line / 32].set(1 << (line % 32));
+        }
+
+        Ok(())
+    }
+
+    fn disable(&self, line: u32) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+
+        if line < 32 {
+            self.redistributor_sgi.ICENABLER0.set(1 << line);
+            self.wait_redistributor();
+        } else {
+            self.distributor.ICENABLER[line / 32].set(1 << (line % 32));
+            self.wait_distributor();
+        }
+
+        Ok(())
+    }
+
+    fn set_pending(&self, line: u32) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+        // The SGIs are made pending through ICC_SGI1R_EL1.
+        if line < 16 {
+            return Err(Error::InvalidIrqLine(line as u32));
+        }
+
+        if line < 32 {
+            self.redistributor_sgi.ISPENDR0.set(1 << line);
+        } else {
+            self.distributor.ISPENDR[line / 32].set(1 << (line % 32));
+        }
+
+        Ok(())
+    }
+
+    fn set_priority(&self, line: u32, priority: u8) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+
+        // Lower values are more urgent for the Gic and 0xff is masked by the PMR.
+        if line < 32 {
+            self.redistributor_sgi.IPRIORITYR[line].set(0xff - priority);
+        } else {
+            self.distributor.IPRIORITYR[line].set(0xff - priority);
+        }
+
+        Ok(())
+    }
+
+    fn set_trigger(&self, line: u32, trigger: Trigger) -> Result<(), Error> {
+        let line = self.check_line(line)?;
+        // The trigger of the SGIs is fixed.
+        if line < 16 {
+            return Err(Error::InvalidIrqLine(line as u32));
+        }
+
+        let icfgr = if line < 32 {
+            &self.redistributor_sgi.ICFGR[1]
+        } else {
+            &self.distributor.ICFGR[line / 16]
+        };
+
+        // 2 bits per line, the upper one is set for edge triggered lines.
+        let edge_bit = 1 << ((line % 16) * 2 + 1);
+        let cfg = icfgr.get();
+        icfgr.set(match trigger {
+            Trigger::Level => cfg & !edge_bit,
+            Trigger::Edge => cfg | edge_bit,
+        });
+
+        Ok(())
+    }
+
+    fn claim(&self) -> Option<u32> {
+        let iar: u64;
+        unsafe {
+            asm!("mrs {}, icc_iar1_el1", out(reg) iar);
+        }
+        let line = (iar & 0xff_ffff) as u32;
+
+        // 1020 to 1023 are special, 1023 being the spurious interrupt.
+        if (1020..=1023).contains(&line) {
+            None
+        } else {
+            Some(line)
+        }
+    }
+
+    fn complete(&self, line: u32) {
+        unsafe {
+            asm!("msr icc_eoir1_el1, {}", in(reg) line as u64);
+        }
+    }
+
+    fn decode_interrupt(&self, cells: &[u32]) -> Result<(u32, Trigger), Error> {
+        let (line, trigger) = super::decode_gic_interrupt(cells)?;
+        self.check_line(line)?;
+
+        Ok((line, trigger))
+    }
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct GicDistributor {
+    /// Distributor Control Register
+    pub CTLR: ReadWrite<u32, GICD_CTLR::Register>,
+    /// Interrupt Controller Type Register
+    pub TYPER: ReadOnly<u32, GICD_TYPER::Register>,
+    /// Distributor Implementer Identification Register
+    pub IIDR: ReadOnly<u32>,
+    /// Interrupt Controller Type Register 2
+    pub TYPER2: ReadOnly<u32>,
+    /// Error Reporting Status Register, optional
+    pub STATUSR: ReadWrite<u32>,
+    _reserved1: [u32; 27],
+    /// Interrupt Group Registers
+    pub IGROUPR: [ReadWrite<u32>; 32],
+    /// Interrupt Set-Enable Registers
+    pub ISENABLER: [ReadWrite<u32>; 32],
+    /// Interrupt Clear-Enable Registers
+    pub ICENABLER: [ReadWrite<u32>; 32],
+    /// Interrupt Set-Pending Registers
+    pub ISPENDR: [ReadWrite<u32>; 32],
+    /// Interrupt Clear-Pending Registers
+    pub ICPENDR: [ReadWrite<u32>; 32],
+    /// Interrupt Set-Active Registers
+    pub ISACTIVER: [ReadWrite<u32>; 32],
+    /// Interrupt Clear-Active Registers
+    pub ICACTIVER: [ReadWrite<u32>; 32],
+    /// Interrupt Priority Registers
+    pub IPRIORITYR: [ReadWrite<u8>; 1024],
+    /// Interrupt Processor Targets Registers, unused with affinity routing
+    _legacy_itargetsr: [u32; 256],
+    /// Interrupt Configuration Registers
+    pub ICFGR: [ReadWrite<u32>; 64],
+    /// Interrupt Group Modifier Registers
+    pub IGRPMODR: [ReadWrite<u32>; 32],
+    _reserved2: [u32; 32],
+    /// Non-secure Access Control Registers
+    pub NSACR: [ReadWrite<u32>; 64],
+    _reserved3: [u32; 5184],
+    /// Interrupt Routing Registers, from line 32
+    pub IROUTER: [ReadWrite<u64>; 1020],
+}
+
+register_bitfields! {u32,
+    pub GICD_CTLR [
+        EnableGrp0 OFFSET(0) NUMBITS(1) [],
+        /// Group 1 for the non-secure world.
+        EnableGrp1 OFFSET(1) NUMBITS(1) [],
+        /// Affinity routing for the non-secure world.
+        ARE OFFSET(4) NUMBITS(1) [],
+        /// Register Write Pending
+        RWP OFFSET(31) NUMBITS(1) [],
+    ],
+
+    pub GICD_TYPER [
+        ITLinesNumber OFFSET(0) NUMBITS(5) [],
+        CPUNumber OFFSET(5) NUMBITS(3) [],
+        SecurityExtn OFFSET(10) NUMBITS(1) [
+            NotImplemented = 0,
+            Implemented = 1,
+        ],
+        IDbits OFFSET(19) NUMBITS(5) [],
+    ],
+}
+
+/// The RD_base frame of a redistributor.
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct GicRedistributor {
+    /// Redistributor Control Register
+    pub CTLR: ReadWrite<u32, GICR_CTLR::Register>,
+    /// Implementer Identification Register
+    pub IIDR: ReadOnly<u32>,
+    /// Redistributor Type Register
+    pub TYPER: ReadOnly<u64, GICR_TYPER::Register>,
+    /// Error Reporting Status Register, optional
+    pub STATUSR: ReadWrite<u32>,
+    /// Redistributor Wake Register
+    pub WAKER: ReadWrite<u32, GICR_WAKER::Register>,
+}
+
+/// The SGI_base frame of a redistributor, the registers of the SGIs and PPIs of its cpu.
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct GicRedistributorSgi {
+    _reserved1: [u32; 32],
+    /// Interrupt Group Register 0
+    pub IGROUPR0: ReadWrite<u32>,
+    _reserved2: [u32; 31],
+    /// Interrupt Set-Enable Register 0
+    pub ISENABLER0: ReadWrite<u32>,
+    _reserved3: [u32; 31],
+    /// Interrupt Clear-Enable Register 0
+    pub ICENABLER0: ReadWrite<u32>,
+    _reserved4: [u32; 31],
+    /// Interrupt Set-Pending Register 0
+    pub ISPENDR0: ReadWrite<u32>,
+    _reserved5: [u32; 31],
+    /// Interrupt Clear-Pending Register 0
+    pub ICPENDR0: ReadWrite<u32>,
+    _reserved6: [u32; 31],
+    /// Interrupt Set-Active Register 0
+    pub ISACTIVER0: ReadWrite<u32>,
+    _reserved7: [u32; 31],
+    /// Interrupt Clear-Active Register 0
+    pub ICACTIVER0: ReadWrite<u32>,
+    _reserved8: [u32; 31],
+    /// Interrupt Priority Registers
+    pub IPRIORITYR: [ReadWrite<u8>; 32],
+    _reserved9: [u32; 504],
+    /// SGI and PPI Configuration Registers
+    pub ICFGR: [ReadWrite<u32>; 2],
+    _reserved10: [u32; 62],
+    /// Interrupt Group Modifier Register 0
+    pub IGRPMODR0: ReadWrite<u32>,
+}
+
+register_bitfields! {u32,
+    pub GICR_CTLR [
+        /// Register Write Pending
+        RWP OFFSET(3) NUMBITS(1) [],
+    ],
+
+    pub GICR_WAKER [
+        ProcessorSleep OFFSET(1) NUMBITS(1) [],
+        ChildrenAsleep OFFSET(2) NUMBITS(1) [],
+    ],
+}
+
+register_bitfields! {u64,
+    pub GICR_TYPER [
+        /// Virtual LPIs, the redistributor has 2 more frames.
+        VLPIS OFFSET(1) NUMBITS(1) [],
+        /// Last redistributor of the region.
+        Last OFFSET(4) NUMBITS(1) [],
+        ProcessorNumber OFFSET(8) NUMBITS(16) [],
+        AffinityValue OFFSET(32) NUMBITS(32) [],
+    ],
+}
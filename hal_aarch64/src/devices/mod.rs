@@ -1 +1,26 @@
 pub mod gicv2;
+pub mod gicv3;
+
+use hal_core::irq::Trigger;
+use hal_core::Error;
+
+/// The line and trigger of the `interrupts` cells of a device wired to a Gic, the controller
+/// checks it has the line.
+fn decode_gic_interrupt(cells: &[u32]) -> Result<(u32, Trigger), Error> {
+    let (line, flags) = match *cells {
+        // Shared peripheral interrupts come after the 16 SGIs and 16 PPIs. A 4th cell may follow
+        // with the partition of a PPI.
+        [0, number, flags, ..] => (number + 32, flags),
+        [1, number, flags, ..] => (number + 16, flags),
+        _ => return Err(Error::InvalidDeviceTreeNode("interrupts")),
+    };
+
+    // The lowest 4 bits of the flags are the edges or levels that trigger the interrupt.
+    let trigger = if flags & 0b0011 != 0 {
+        Trigger::Edge
+    } else {
+        Trigger::Level
+    };
+
+    Ok((line, trigger))
+}
@@ -1,23 +1,51 @@
+use core::arch::asm;
 use core::ptr;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
 use crate::cpu;
-use hal_core::{Error, TimerCallbackFn};
+use hal_core::irq::{IrqCallbackFn, IrqChip, DEFAULT_PRIORITY};
+use hal_core::{AddressRange, Error, TimerCallbackFn};
 
-use crate::devices::gicv2::GicV2;
+use crate::devices::{gicv2::GicV2, gicv3::GicV3};
+
+use fdt::{node::FdtNode, standard_nodes::MemoryRegion, Fdt};
 
 use crate::mm;
-use hal_core::mm::{PageAlloc, PageMap, Permissions, VAddr};
+use hal_core::mm::{
+    FaultAccess, MemoryType, PAddr, PageAlloc, PageFault, PageFaultCallbackFn, PageMap,
+    Permissions, VAddr,
+};
 
+use cortex_a::registers::VBAR_EL1;
 use tock_registers::interfaces::Writeable;
 
 const PHYSICAL_TIMER_LINE: u32 = 30;
 
+/// The stacks the traps from EL0 are handled on, one per cpu.
+#[repr(C, align(16))]
+struct TrapStacks([[u8; TRAP_STACK_SIZE]; cpu::MAX_CPUS]);
+
+const TRAP_STACK_SIZE: usize = 0x4000;
+
+static mut TRAP_STACKS: TrapStacks = TrapStacks([[0; TRAP_STACK_SIZE]; cpu::MAX_CPUS]);
+
+/// Set up the exceptions of the calling cpu, each of them has to before it takes one.
 pub unsafe fn init_el1_exception_handlers() {
     extern "Rust" {
         static el1_vector_table: core::cell::UnsafeCell<()>;
     }
-    cortex_a::registers::VBAR_EL1.set(el1_vector_table.get() as u64);
+    VBAR_EL1.set(el1_vector_table.get() as u64);
+
+    // SP_EL1 still points at the boot stack, the kernel itself runs on SP_EL0. Each cpu has its
+    // own SP_EL1, pointing at its own trap stack.
+    let trap_stack_top =
+        ptr::addr_of_mut!(TRAP_STACKS.0[cpu::cpu_index()]) as usize + TRAP_STACK_SIZE;
+    asm!(
+        "msr spsel, #1",
+        "mov sp, {}",
+        "msr spsel, #0",
+        in(reg) trap_stack_top,
+    );
 }
 
 static TIMER_CALLBACK: AtomicPtr<TimerCallbackFn> = AtomicPtr::new(ptr::null_mut());
@@ -26,6 +54,23 @@ pub fn set_timer_handler(h: TimerCallbackFn) {
     TIMER_CALLBACK.store(h as *mut _, Ordering::Relaxed);
 }
 
+static PAGE_FAULT_CALLBACK: AtomicPtr<PageFaultCallbackFn> = AtomicPtr::new(ptr::null_mut());
+
+/// Handles a breakpoint, it has to move `frame.pc` past the `brk`.
+pub type BreakpointCallbackFn = fn(&mut TrapFrame);
+
+static BREAKPOINT_CALLBACK: AtomicPtr<BreakpointCallbackFn> = AtomicPtr::new(ptr::null_mut());
+
+/// `h` is called for the breakpoints, they are fatal without a handler.
+pub fn set_breakpoint_handler(h: BreakpointCallbackFn) {
+    BREAKPOINT_CALLBACK.store(h as *mut _, Ordering::Relaxed);
+}
+
+/// `h` gets a chance to resolve the page faults before they are considered fatal.
+pub fn set_page_fault_handler(h: PageFaultCallbackFn) {
+    PAGE_FAULT_CALLBACK.store(h as *mut _, Ordering::Relaxed);
+}
+
 pub fn set_timer(ticks: usize) -> Result<(), Error> {
     enable_line(PHYSICAL_TIMER_LINE)?;
     super::cpu::set_physical_timer(ticks);
@@ -34,157 +79,376 @@ pub fn set_timer(ticks: usize) -> Result<(), Error> {
     Ok(())
 }
 
-enum IrqChip {
-    NoChip,
-    GicV2(GicV2),
+static mut GICV2: Option<GicV2> = None;
+static mut GICV3: Option<GicV3> = None;
+static mut IRQ_CHIP: Option<&'static dyn IrqChip> = None;
+
+/// The compatible strings of the interrupt controllers [`GicV2`] drives.
+const GICV2_COMPATIBLES: &[&str] = &[
+    "arm,gic-400",
+    "arm,cortex-a15-gic",
+    "arm,cortex-a9-gic",
+    "arm,cortex-a7-gic",
+];
+
+/// The compatible strings of the interrupt controllers [`GicV3`] drives.
+const GICV3_COMPATIBLES: &[&str] = &["arm,gic-v3"];
+
+/// The most regions of redistributors looked at.
+const MAX_REDISTRIBUTOR_REGIONS: usize = 8;
+
+fn is_compatible(dt_node: &FdtNode, compatibles: &[&str]) -> bool {
+    dt_node
+        .property("compatible")
+        .and_then(|prop| prop.as_str())
+        .unwrap_or("")
+        .split('\0')
+        .any(|compatible| compatibles.contains(&compatible))
 }
 
-impl IrqChip {
-    fn get_int(&mut self) -> Result<u32, Error> {
-        match self {
-            Self::NoChip => unreachable!("does not support this"),
-            Self::GicV2(gic) => gic.get_int(),
-        }
-    }
+fn property_usize(dt_node: &FdtNode, name: &'static str) -> Result<usize, Error> {
+    dt_node
+        .property(name)
+        .and_then(|prop| prop.as_usize())
+        .ok_or(Error::InvalidDeviceTreeNode(name))
+}
+
+/// Map the registers of a device in the direct map, returns where they are mapped.
+fn map_device_region(
+    region: Option<MemoryRegion>,
+    allocator: &impl PageAlloc,
+) -> Result<AddressRange, Error> {
+    let region = region.ok_or(Error::InvalidDeviceTreeNode("reg"))?;
+    let base = region.starting_address as usize;
+    let size = region.size.ok_or(Error::InvalidDeviceTreeNode("reg"))?;
+
+    let range = AddressRange::with_size(mm::phys_to_virt(base), size);
+
+    mm::current().map_addressrange(
+        range.round_up_to_page(mm::PAGE_SIZE),
+        PAddr::new(base),
+        Permissions::READ | Permissions::WRITE,
+        MemoryType::DeviceNGnRE,
+        allocator,
+    )?;
 
-    fn clear_int(&mut self, int: u32) {
-        match self {
-            Self::NoChip => unreachable!("does not support this"),
-            Self::GicV2(gic) => gic.clear_int(int),
+    Ok(range)
+}
+
+/// Set up the interrupt controller described by `dt_node`, the driver is picked from its
+/// compatible strings. The GICs are described by their node alone, `_device_tree` isn't needed.
+pub fn init_irq_chip(
+    dt_node: &FdtNode,
+    _device_tree: &Fdt,
+    allocator: &impl PageAlloc,
+) -> Result<(), Error> {
+    // A 4th cell is allowed for the partitions of the PPIs.
+    if !(3..=4).contains(&property_usize(dt_node, "#interrupt-cells")?) {
+        return Err(Error::InvalidDeviceTreeNode("#interrupt-cells"));
+    }
+    let mut reg = dt_node.reg().ok_or(Error::InvalidDeviceTreeNode("reg"))?;
+
+    let chip: &'static dyn IrqChip = if is_compatible(dt_node, GICV3_COMPATIBLES) {
+        let gicd = map_device_region(reg.next(), allocator)?;
+
+        // The redistributor regions follow the distributor.
+        let nregions = property_usize(dt_node, "#redistributor-regions")
+            .unwrap_or(1)
+            .min(MAX_REDISTRIBUTOR_REGIONS);
+        let mut redistributors = [(0, 0); MAX_REDISTRIBUTOR_REGIONS];
+        for redistributor in &mut redistributors[..nregions] {
+            let region = map_device_region(reg.next(), allocator)?;
+            *redistributor = (region.start, region.size());
         }
+        let stride = property_usize(dt_node, "redistributor-stride").ok();
+
+        unsafe {
+            GICV3 = Some(GicV3::new(
+                gicd.start,
+                redistributors[..nregions].iter().copied(),
+                stride,
+            )?);
+            GICV3.as_ref().unwrap()
+        }
+    } else if is_compatible(dt_node, GICV2_COMPATIBLES) {
+        // The distributor then the cpu interface, the virtualization extensions may follow.
+        let gicd = map_device_region(reg.next(), allocator)?;
+        let gicc = map_device_region(reg.next(), allocator)?;
+
+        unsafe {
+            GICV2 = Some(GicV2::new(gicd.start, gicc.start));
+            GICV2.as_ref().unwrap()
+        }
+    } else {
+        return Err(Error::NoMatchingIrqChip);
+    };
+
+    unsafe {
+        IRQ_CHIP = Some(chip);
     }
+    Ok(())
+}
+
+/// The interrupt controller set up by [`init_irq_chip`].
+pub fn irq_chip() -> Result<&'static dyn IrqChip, Error> {
+    unsafe { IRQ_CHIP }.ok_or(Error::NoIrqChip)
+}
+
+static IRQ_CALLBACK: AtomicPtr<IrqCallbackFn> = AtomicPtr::new(ptr::null_mut());
+
+/// `h` handles the interrupts of every line but the timer's.
+pub fn set_irq_handler(h: IrqCallbackFn) {
+    IRQ_CALLBACK.store(h as *mut _, Ordering::Relaxed);
+}
+
+fn enable_line(line: u32) -> Result<(), Error> {
+    let chip = irq_chip()?;
+    chip.set_priority(line, DEFAULT_PRIORITY)?;
+    chip.enable(line)
+}
+
+fn timer_interrupt() {
+    // Clear the timer in order to EOI it.
+    cpu::clear_physical_timer();
 
-    fn enable_int(&mut self, int: u32) -> Result<(), Error> {
-        match self {
-            Self::NoChip => unreachable!("does not support"),
-            Self::GicV2(gic) => gic.enable_line(int),
+    let timer_cb = TIMER_CALLBACK.load(Ordering::Relaxed);
+    if !timer_cb.is_null() {
+        unsafe {
+            // Cannot simply dereference TIMER_CALLBACK here.
+            // We are using an AtomicPtr and TIMER_CALLBACK already holds the fn().
+            core::mem::transmute::<_, fn()>(timer_cb)();
         }
     }
 }
 
-static mut IRQ_CHIP: IrqChip = IrqChip::NoChip;
+/// Set while [`write_faults`] waits for its store to fault.
+static PROBING_WRITE: AtomicBool = AtomicBool::new(false);
+static PROBED_WRITE_FAULTED: AtomicBool = AtomicBool::new(false);
 
-pub fn init_irq_chip(_dt_node: (), allocator: &impl PageAlloc) -> Result<(), Error> {
-    let (gicd_base, gicc_base) = (0x800_0000, 0x801_0000);
-    mm::current().identity_map_range(
-        VAddr::new(gicd_base),
-        0x0001_0000 / mm::PAGE_SIZE,
-        Permissions::READ | Permissions::WRITE,
-        allocator,
-    )?;
-    mm::current().identity_map_range(
-        VAddr::new(gicc_base),
-        0x0001_0000 / mm::PAGE_SIZE,
-        Permissions::READ | Permissions::WRITE,
-        allocator,
-    )?;
+/// Returns whether writing to `addr` faults. The value already at `addr` is written back, nothing
+/// changes if the write goes through.
+pub fn write_faults(addr: usize) -> bool {
+    PROBED_WRITE_FAULTED.store(false, Ordering::Relaxed);
+    PROBING_WRITE.store(true, Ordering::SeqCst);
 
     unsafe {
-        IRQ_CHIP = IrqChip::GicV2(GicV2::new(gicd_base, gicc_base));
+        asm!(
+            "ldr {tmp}, [{addr}]",
+            "str {tmp}, [{addr}]",
+            addr = in(reg) addr,
+            tmp = out(reg) _,
+        );
     }
-    Ok(())
+
+    PROBING_WRITE.store(false, Ordering::SeqCst);
+    PROBED_WRITE_FAULTED.load(Ordering::Relaxed)
 }
 
-fn enable_line(line: u32) -> Result<(), Error> {
-    unsafe { IRQ_CHIP.enable_int(line) }
+/// Exception classes of the aborts, taken from a lower exception level or without changing it.
+const ESR_EC_INSTRUCTION_ABORT_LOWER_EL: u64 = 0b10_0000;
+const ESR_EC_INSTRUCTION_ABORT_CURRENT_EL: u64 = 0b10_0001;
+const ESR_EC_DATA_ABORT_LOWER_EL: u64 = 0b10_0100;
+const ESR_EC_DATA_ABORT_CURRENT_EL: u64 = 0b10_0101;
+const ESR_EC_BRK: u64 = 0b11_1100;
+
+/// The state of the trapped context, saved on the kernel stack by the stubs of `exceptions.S` and
+/// restored from there when the trap returns, changes made by the handlers take effect then.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// x0 to x30, x29 is the frame pointer and x30 the link register.
+    pub regs: [u64; 31],
+    /// The stack pointer the trap happened with, SP_EL0 for the traps from EL0. It is restored as
+    /// well.
+    pub sp: u64,
+    /// ELR_EL1, where execution resumes.
+    pub pc: u64,
+    /// SPSR_EL1
+    pub status: u64,
+    /// ESR_EL1
+    pub cause: u64,
+    /// FAR_EL1
+    pub fault_address: u64,
 }
 
-#[no_mangle]
-extern "C" fn sync_current_el_sp0() {
-    panic!("hit sync_current_el_sp0");
+// The offsets used by exceptions.S.
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == 0x120);
+
+impl TrapFrame {
+    /// The exception class of ESR_EL1.
+    fn exception_class(&self) -> u64 {
+        (self.cause >> 26) & 0b11_1111
+    }
+
+    /// The instruction specific syndrome of ESR_EL1.
+    fn syndrome(&self) -> u64 {
+        self.cause & 0x1ff_ffff
+    }
+}
+
+/// Decode the abort described by the frame, returns None if it isn't caused by a translation,
+/// access flag or permission fault.
+fn decode_page_fault(frame: &TrapFrame) -> Option<PageFault> {
+    let iss = frame.syndrome();
+    let (access, user) = match frame.exception_class() {
+        ESR_EC_INSTRUCTION_ABORT_LOWER_EL => (FaultAccess::Execute, true),
+        ESR_EC_INSTRUCTION_ABORT_CURRENT_EL => (FaultAccess::Execute, false),
+        ec @ (ESR_EC_DATA_ABORT_LOWER_EL | ESR_EC_DATA_ABORT_CURRENT_EL) => {
+            // WnR tells stores from loads.
+            let access = if iss & (1 << 6) != 0 {
+                FaultAccess::Write
+            } else {
+                FaultAccess::Read
+            };
+            (access, ec == ESR_EC_DATA_ABORT_LOWER_EL)
+        }
+        _ => return None,
+    };
+
+    // The fault status code, its level is in the 2 lowest bits.
+    match (iss & 0b11_1111) >> 2 {
+        // Translation, access flag or permission fault.
+        0b0001..=0b0011 => Some(PageFault {
+            addr: VAddr::new(frame.fault_address as usize),
+            access,
+            user,
+        }),
+        _ => None,
+    }
+}
+
+/// Let the page fault handler resolve the fault, there is nothing else to fall back on if it
+/// can't. The faulting instruction runs again once the handler returns.
+fn page_fault(fault: PageFault) {
+    let page_fault_cb = PAGE_FAULT_CALLBACK.load(Ordering::Relaxed);
+    let resolved = !page_fault_cb.is_null()
+        && unsafe { core::mem::transmute::<_, PageFaultCallbackFn>(page_fault_cb)(&fault) };
+
+    if !resolved {
+        // There is no user context to kill yet, everything runs on behalf of the kernel.
+        panic!("unhandled page fault: {:X?}", fault);
+    }
 }
 
 #[no_mangle]
-extern "C" fn irq_current_el_sp0() {
-    let int = unsafe { IRQ_CHIP.get_int() };
+extern "C" fn sync_current_el_sp0(frame: &mut TrapFrame) {
+    if frame.exception_class() == ESR_EC_DATA_ABORT_CURRENT_EL
+        && PROBING_WRITE.swap(false, Ordering::SeqCst)
+    {
+        PROBED_WRITE_FAULTED.store(true, Ordering::Relaxed);
+        // Resume after the faulting store.
+        frame.pc += 4;
+        return;
+    }
 
-    match int {
-        Ok(PHYSICAL_TIMER_LINE) => {
-            // Clear the timer in order to EOI it.
-            cpu::clear_physical_timer();
+    let breakpoint_cb = BREAKPOINT_CALLBACK.load(Ordering::Relaxed);
+    if frame.exception_class() == ESR_EC_BRK && !breakpoint_cb.is_null() {
+        unsafe { core::mem::transmute::<_, BreakpointCallbackFn>(breakpoint_cb)(frame) };
+        return;
+    }
 
-            let timer_cb = TIMER_CALLBACK.load(Ordering::Relaxed);
-            if !timer_cb.is_null() {
-                unsafe {
-                    // Cannot simply dereference TIMER_CALLBACK here.
-                    // We are using an AtomicPtr and TIMER_CALLBACK already holds the fn().
-                    core::mem::transmute::<_, fn()>(timer_cb)();
-                }
-            }
+    match decode_page_fault(frame) {
+        Some(fault) => page_fault(fault),
+        None => panic!("hit sync_current_el_sp0: {:X?}", frame),
+    }
+}
+
+#[no_mangle]
+extern "C" fn irq_current_el_sp0(_frame: &mut TrapFrame) {
+    dispatch_irqs();
+}
 
-            unsafe { IRQ_CHIP.clear_int(int.unwrap()) };
+/// Claim the pending lines from the interrupt controller, run their handlers and complete them,
+/// whether the kernel or EL0 got interrupted.
+fn dispatch_irqs() {
+    let chip = irq_chip().expect("got an irq without an interrupt controller");
+
+    while let Some(line) = chip.claim() {
+        if line == PHYSICAL_TIMER_LINE {
+            timer_interrupt();
+        } else {
+            let irq_cb = IRQ_CALLBACK.load(Ordering::Relaxed);
+            if irq_cb.is_null() {
+                panic!("got an irq on line {} but nobody handles it", line);
+            }
+            unsafe { core::mem::transmute::<_, IrqCallbackFn>(irq_cb)(line) };
         }
-        _ => panic!("got an irq but fuck knows"),
+
+        chip.complete(line);
     }
 }
+
 #[no_mangle]
-extern "C" fn fiq_current_el_sp0() {
-    panic!("hit fiq_current_el_sp0");
+extern "C" fn fiq_current_el_sp0(frame: &mut TrapFrame) {
+    panic!("hit fiq_current_el_sp0: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn serror_current_el_sp0() {
-    panic!("hit serror_current_el_sp0");
+extern "C" fn serror_current_el_sp0(frame: &mut TrapFrame) {
+    panic!("hit serror_current_el_sp0: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn sync_current_el_spx() {
-    panic!("hit sync_current_el_spx");
+extern "C" fn sync_current_el_spx(frame: &mut TrapFrame) {
+    panic!("hit sync_current_el_spx: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn irq_current_el_spx() {
-    panic!("hit irq_current_el_spx");
+extern "C" fn irq_current_el_spx(frame: &mut TrapFrame) {
+    panic!("hit irq_current_el_spx: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn fiq_current_el_spx() {
-    panic!("hit fiq_current_el_spx");
+extern "C" fn fiq_current_el_spx(frame: &mut TrapFrame) {
+    panic!("hit fiq_current_el_spx: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn serror_current_el_spx() {
-    panic!("hit serror_current_el_spx");
+extern "C" fn serror_current_el_spx(frame: &mut TrapFrame) {
+    panic!("hit serror_current_el_spx: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn sync_lower_el() {
-    panic!("hit sync_lower_el");
+extern "C" fn sync_lower_el(frame: &mut TrapFrame) {
+    match decode_page_fault(frame) {
+        Some(fault) => page_fault(fault),
+        None => panic!("hit sync_lower_el: {:X?}", frame),
+    }
 }
 
 #[no_mangle]
-extern "C" fn irq_lower_el() {
-    panic!("hit irq_lower_el");
+extern "C" fn irq_lower_el(_frame: &mut TrapFrame) {
+    dispatch_irqs();
 }
 
 #[no_mangle]
-extern "C" fn fiq_lower_el() {
-    panic!("hit fiq_lower_el");
+extern "C" fn fiq_lower_el(frame: &mut TrapFrame) {
+    panic!("hit fiq_lower_el: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn serror_lower_el() {
-    panic!("hit serror_lower_el");
+extern "C" fn serror_lower_el(frame: &mut TrapFrame) {
+    panic!("hit serror_lower_el: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn sync_lower_el_aarch32() {
-    panic!("hit sync_lower_el_aarch32");
+extern "C" fn sync_lower_el_aarch32(frame: &mut TrapFrame) {
+    panic!("hit sync_lower_el_aarch32: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn irq_lower_el_aarch32() {
-    panic!("hit irq_lower_el_aarch32");
+extern "C" fn irq_lower_el_aarch32(frame: &mut TrapFrame) {
+    panic!("hit irq_lower_el_aarch32: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn fiq_lower_el_aarch32() {
-    panic!("hit fiq_lower_el_aarch32");
+extern "C" fn fiq_lower_el_aarch32(frame: &mut TrapFrame) {
+    panic!("hit fiq_lower_el_aarch32: {:X?}", frame);
 }
 
 #[no_mangle]
-extern "C" fn serror_lower_el_aarch32() {
-    panic!("hit serror_lower_el_aarch32");
+extern "C" fn serror_lower_el_aarch32(frame: &mut TrapFrame) {
+    panic!("hit serror_lower_el_aarch32: {:X?}", frame);
 }
 
 core::arch::global_asm!(include_str!("exceptions.S"));
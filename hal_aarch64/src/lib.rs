@@ -28,16 +28,154 @@ pub fn panic_info() -> PanicInfo {
     }
 }
 
+/// Tables used from `_start` until the kernel builds its own pagetable, they always use the 4KiB
+/// granule whatever the kernel was built with.
+#[repr(C, align(4096))]
+struct BootPageTable([u64; 512]);
+
+static mut BOOT_TTBR0_L0: BootPageTable = BootPageTable([0; 512]);
+static mut BOOT_TTBR1_L0: BootPageTable = BootPageTable([0; 512]);
+static mut BOOT_DIRECT_L1: BootPageTable = BootPageTable([0; 512]);
+static mut BOOT_KERNEL_L1: BootPageTable = BootPageTable([0; 512]);
+
+/// Called from `_start` with the MMU off, see [`hal_core::kaslr::relocate`].
+unsafe extern "C" fn relocate_kernel(
+    device_tree: usize,
+    phys_start: usize,
+    link_start: usize,
+    rela_start: usize,
+    rela_end: usize,
+) -> usize {
+    const R_AARCH64_RELATIVE: u32 = 1027;
+
+    hal_core::kaslr::relocate(
+        device_tree,
+        phys_start,
+        link_start,
+        (rela_start, rela_end),
+        R_AARCH64_RELATIVE,
+    )
+}
+
+/// Entered at EL1 with the MMU off at the load address of the kernel. The kernel is relocated,
+/// then it and the direct map are mapped with 1GiB blocks before jumping to the upper half, the
+/// kernel mustn't cross a gigabyte boundary and its link and load addresses must have the same
+/// offset in their gigabyte.
 #[naked]
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
     asm!(
         "
+        // x0 is left for k_main.
+        mov x19, x0
+
+        // The compiler may use the FP/SIMD registers in the relocation code.
+        mov x10, #(0b11 << 20)
+        msr cpacr_el1, x10
+        isb
+
+        // Until the jump to the upper half, the boot stack is used at its physical address.
+        adrp x10, STACK_START
+        mov sp, x10
+        // Booting an ELF, QEMU leaves x0 at 0, the board says where the device tree is then.
+        cbnz x0, 5f
+        ldr x0, 6f
+    5:
+        adrp x1, KERNEL_START
+        ldr x2, 3f
+        adrp x3, RELA_START
+        add x3, x3, :lo12:RELA_START
+        adrp x4, RELA_END
+        add x4, x4, :lo12:RELA_END
+        bl {relocate_kernel}
+        mov x0, x19
+
+        // From now on the addresses stored in the image, 3f included, are the slid ones.
+        adrp x9, KERNEL_START
+        adrp x10, {kernel_phys_start}
+        str x9, [x10, :lo12:{kernel_phys_start}]
+
+        // Block attributes: AttrIndx 1 (normal non-cacheable), EL1 RW, inner shareable, access
+        // flag, UXN. Device registers are in there too, the caches stay off until the kernel's
+        // own pagetable maps them as device memory.
+        mov x15, #0x705
+        movk x15, #0x40, lsl #48
+
+        // The first 512GiB with 1GiB blocks, both identity mapped in TTBR0 and as the direct map at
+        // the bottom of TTBR1.
+        adrp x10, {direct_l1}
+        mov x11, x15
+        mov x12, #512
+        mov x13, #0x40000000
+    1:
+        str x11, [x10], #8
+        add x11, x11, x13
+        subs x12, x12, #1
+        b.ne 1b
+
+        // The gigabyte holding the kernel where it runs, at its link address plus the slide.
+        adrp x10, {kernel_l1}
+        ldr x11, 3f
+        ubfx x12, x11, #30, #9
+        lsr x13, x9, #30
+        orr x13, x15, x13, lsl #30
+        str x13, [x10, x12, lsl #3]
+
+        adrp x12, {direct_l1}
+        orr x12, x12, #3
+        adrp x10, {ttbr0_l0}
+        str x12, [x10]
+        adrp x14, {ttbr1_l0}
+        str x12, [x14]
+        ubfx x13, x11, #39, #9
+        adrp x12, {kernel_l1}
+        orr x12, x12, #3
+        str x12, [x14, x13, lsl #3]
+
+        // The same attributes as mm::MAIR.
+        mov x12, #0x44ff
+        movk x12, #0x4, lsl #16
+        msr mair_el1, x12
+        // 48-bit virtual addresses in both halves with 4KiB granules, 48-bit physical addresses.
+        mov x12, #0x10
+        movk x12, #0x8010, lsl #16
+        movk x12, #0x5, lsl #32
+        msr tcr_el1, x12
+        msr ttbr0_el1, x10
+        msr ttbr1_el1, x14
+        isb
+        tlbi vmalle1
+        dsb ish
+        isb
+
+        mrs x12, sctlr_el1
+        orr x12, x12, #1
+        msr sctlr_el1, x12
+        isb
+
+        ldr x12, 4f
+        br x12
+
+        .balign 8
+    3:
+        .quad KERNEL_START
+    4:
+        .quad 2f
+    6:
+        .quad BOOT_DEVICE_TREE
+
+    2:
         adrp x9, STACK_START
         msr spsel, xzr
         mov sp, x9
         b k_main
         ",
+        relocate_kernel = sym relocate_kernel,
+        kernel_phys_start = sym mm::KERNEL_PHYS_START,
+        direct_l1 = sym BOOT_DIRECT_L1,
+        kernel_l1 = sym BOOT_KERNEL_L1,
+        ttbr0_l0 = sym BOOT_TTBR0_L0,
+        ttbr1_l0 = sym BOOT_TTBR1_L0,
         options(noreturn)
     );
 }
@@ -0,0 +1,34 @@
+use hal_core::{
+    mm::{self, AddressSpaceTable, Asid, AsidAllocator, PageAlloc, PageMap},
+    Error,
+};
+
+use super::pgt48::{self, PageTable};
+use super::{set_ttbr0, virt_to_phys};
+
+static ASIDS: AsidAllocator = AsidAllocator::new();
+
+/// The root table of an address space only translates the lower half.
+///
+/// The kernel lives in the upper half which is translated through TTBR1_EL1, it is the same for
+/// all the address spaces.
+pub type AddressSpace = mm::AddressSpace<PageTable>;
+
+impl AddressSpaceTable for PageTable {
+    fn asids() -> &'static AsidAllocator {
+        &ASIDS
+    }
+
+    fn new_root(allocator: &impl PageAlloc) -> Result<&'static mut Self, Error> {
+        PageTable::new(allocator)
+    }
+
+    fn activate(&self, asid: Asid) {
+        set_ttbr0(self, asid);
+    }
+
+    fn free_root(root: &'static mut Self, allocator: &impl PageAlloc) {
+        root.free_tables(pgt48::ROOT_LEVEL, allocator);
+        let _ = allocator.dealloc(virt_to_phys(root as *mut PageTable as usize), 1);
+    }
+}
@@ -1,13 +1,21 @@
 use hal_core::{
-    mm::{self, PageAlloc, PageMap},
+    mm::{self, Asid, MemoryType, PageAlloc, PageMap},
     AddressRange, Error,
 };
 
 use cortex_a::asm::barrier;
 use cortex_a::registers::*;
-use tock_registers::interfaces::{ReadWriteable, Writeable};
+use tock_registers::fields::FieldValue;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+mod address_space;
 mod pgt48;
+pub mod tlb;
+
+pub use address_space::AddressSpace;
 
 use pgt48::PageTable;
 
@@ -15,6 +23,128 @@ pub type EntryType = usize;
 
 pub const PAGE_SIZE: usize = PageTable::PAGE_SIZE;
 
+// The kernel lives in the upper half of the address space, translated through TTBR1_EL1, the lower
+// half is translated through TTBR0_EL1 and belongs to the address spaces of the processes:
+//   - 0xffff_0000_0000_0000..0xffff_0080_0000_0000: direct map of the first 512GiB of physical
+//     memory,
+//   - 0xffff_8000_0000_0000..0xffff_fff0_0000_0000: free for the kernel's own mappings,
+//   - 0xffff_fff0_0000_0000..: the kernel image, linked at 0xffff_fff0_4010_0000 and moved up by
+//     a multiple of 1GiB with KASLR.
+
+/// Virtual address of physical address 0 in the direct map.
+pub const PHYS_OFFSET: usize = 0xffff_0000_0000_0000;
+/// Amount of physical memory covered by the direct map.
+pub const DIRECT_MAP_SIZE: usize = 512 * 1024 * 1024 * 1024;
+/// Start of the part of the upper half left for the kernel's own mappings.
+pub const KERNEL_VIRT_START: usize = 0xffff_8000_0000_0000;
+/// End of the part of the upper half left for the kernel's own mappings.
+pub const KERNEL_VIRT_END: usize = 0xffff_fff0_0000_0000;
+
+/// Physical address the kernel image was loaded at, `_start` saves it before enabling the MMU.
+#[no_mangle]
+pub(crate) static mut KERNEL_PHYS_START: usize = 0;
+
+/// Returns the address of `pa` in the direct map.
+pub const fn phys_to_virt(pa: usize) -> usize {
+    pa + PHYS_OFFSET
+}
+
+/// Returns the physical address of `va`, which must be in the direct map or in the kernel image.
+pub fn virt_to_phys(va: usize) -> usize {
+    extern "C" {
+        static KERNEL_START: usize;
+    }
+    let kernel_start = unsafe { &KERNEL_START as *const usize as usize };
+
+    if va >= kernel_start {
+        va - kernel_start + unsafe { KERNEL_PHYS_START }
+    } else {
+        assert!(
+            (PHYS_OFFSET..PHYS_OFFSET + DIRECT_MAP_SIZE).contains(&va),
+            "{:#x} is neither in the direct map nor in the kernel image",
+            va
+        );
+        va - PHYS_OFFSET
+    }
+}
+
+/// Attributes programmed in MAIR_EL1, the position of a memory type is the AttrIndx of its
+/// mappings.
+const MAIR_ATTRIBUTES: [(MemoryType, u8); 4] = [
+    // Inner and outer write-back, read and write allocate.
+    (MemoryType::NormalCacheable, 0xff),
+    // Inner and outer non-cacheable.
+    (MemoryType::NormalNonCacheable, 0x44),
+    (MemoryType::DeviceNGnRE, 0x04),
+    (MemoryType::DeviceNGnRnE, 0x00),
+];
+
+/// Value of MAIR_EL1, `_start` programs the same one (0x0004_44ff) for its boot tables.
+const MAIR: u64 = {
+    let mut mair = 0;
+    let mut i = 0;
+    while i < MAIR_ATTRIBUTES.len() {
+        mair |= (MAIR_ATTRIBUTES[i].1 as u64) << (8 * i);
+        i += 1;
+    }
+    mair
+};
+
+/// Width of the virtual addresses of both halves, 52 bits when the cpu and the granule allow it.
+static VA_BITS: AtomicU8 = AtomicU8::new(48);
+/// Value of TCR_EL1.IPS, the physical address size of the cpu.
+static IPS: AtomicU8 = AtomicU8::new(0b101);
+
+pub(super) fn va_bits() -> usize {
+    VA_BITS.load(Ordering::Relaxed) as usize
+}
+
+/// Check that the cpu implements the granule the kernel was built for and pick the size of the
+/// virtual and physical addresses from what it supports, before the first table is built.
+fn probe_translation() {
+    let mmfr0 = ID_AA64MMFR0_EL1.get();
+    let field = |shift: u64| (mmfr0 >> shift) & 0xf;
+
+    let granule_supported = match PAGE_SIZE {
+        0x1000 => field(28) != 0xf,
+        0x4000 => field(20) != 0,
+        _ => field(24) != 0xf,
+    };
+    assert!(
+        granule_supported,
+        "the cpu doesn't implement the {}KiB translation granule",
+        PAGE_SIZE / 1024
+    );
+
+    // The descriptors only hold 48-bit output addresses, larger ones need FEAT_LPA.
+    IPS.store(field(0).min(0b101) as u8, Ordering::Relaxed);
+
+    // With the 64KiB granule FEAT_LVA only makes the root table bigger, the 4KiB and 16KiB
+    // granules would need the FEAT_LPA2 descriptors for 52-bit virtual addresses.
+    let mmfr2: u64;
+    unsafe { asm!("mrs {}, id_aa64mmfr2_el1", out(reg) mmfr2) };
+    if PAGE_SIZE == 0x10000 && (mmfr2 >> 16) & 0xf == 1 {
+        VA_BITS.store(52, Ordering::Relaxed);
+    }
+
+    log::info!(
+        "aarch64 translation: {}KiB granule, {}-bit virtual addresses",
+        PAGE_SIZE / 1024,
+        va_bits()
+    );
+}
+
+pub(super) fn mair_index(mem_type: MemoryType) -> usize {
+    MAIR_ATTRIBUTES
+        .iter()
+        .position(|&(attribute_type, _)| attribute_type == mem_type)
+        .unwrap()
+}
+
+pub(super) fn mair_memory_type(index: usize) -> MemoryType {
+    MAIR_ATTRIBUTES[index].0
+}
+
 use core::cell::OnceCell;
 
 static mut GPT: OnceCell<&'static mut PageTable> = OnceCell::new();
@@ -30,11 +160,14 @@ pub fn current() -> &'static mut PageTable {
 pub fn prefill_pagetable(
     r: impl Iterator<Item = AddressRange>,
     rw: impl Iterator<Item = AddressRange>,
-    rwx: impl Iterator<Item = AddressRange>,
-    pre_allocated: impl Iterator<Item = AddressRange>,
+    rx: impl Iterator<Item = AddressRange>,
+    device: impl Iterator<Item = AddressRange>,
     allocator: &impl PageAlloc,
 ) -> Result<(), Error> {
-    let pt = hal_core::mm::prefill_pagetable::<PageTable>(r, rw, rwx, pre_allocated, allocator)?;
+    probe_translation();
+
+    let pt =
+        hal_core::mm::prefill_pagetable::<PageTable>(r, rw, rx, device, virt_to_phys, allocator)?;
 
     // TODO: put into into the hal_core::Error
     unsafe {
@@ -52,35 +185,102 @@ pub fn enable_paging() {
     };
 }
 
+/// Switch back to the kernel's pagetable: nothing is left mapped in the lower half.
+pub fn activate_kernel_pagetable() {
+    TCR_EL1.modify(TCR_EL1::EPD0::DisableTTBR0Walks);
+    // No address space uses ASID 0, nothing cached for the lower half can match it.
+    TTBR0_EL1.write(TTBR0_EL1::ASID.val(0) + TTBR0_EL1::BADDR.val(0));
+
+    barrier::isb(barrier::SY);
+}
+
+/// Translate the lower half through `pt`.
+fn set_ttbr0(pt: &PageTable, asid: Asid) {
+    let pt_addr = virt_to_phys(pt as *const PageTable as usize) as u64;
+
+    // A single write, the walker mustn't see the new table with the old ASID.
+    TTBR0_EL1.write(TTBR0_EL1::ASID.val(asid as u64) + TTBR0_EL1::BADDR.val(pt_addr >> 1));
+    TCR_EL1.modify(TCR_EL1::EPD0::EnableTTBR0Walks);
+
+    barrier::isb(barrier::SY);
+}
+
+/// Install `pt` as the pagetable of the upper half. The MMU is already on, `_start` enabled it
+/// with its boot tables.
 unsafe fn load_pagetable(pt: &'static mut PageTable) {
-    MAIR_EL1.write(
-        // Attribute 0 - NonCacheable normal DRAM. FIXME: enable cache?
-        MAIR_EL1::Attr0_Normal_Outer::NonCacheable + MAIR_EL1::Attr0_Normal_Inner::NonCacheable,
-    );
-    TTBR0_EL1.set_baddr((pt as *const PageTable) as u64);
-    TCR_EL1.write(
-        TCR_EL1::TBI0::Used
-        + TCR_EL1::IPS::Bits_48
-        + TCR_EL1::TG0::KiB_4
-        // + TCR_EL1::SH0::Inner
-        + TCR_EL1::SH0::None
-        // + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::ORGN0::NonCacheable
-        // + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
-        + TCR_EL1::IRGN0::NonCacheable
-        + TCR_EL1::EPD0::EnableTTBR0Walks
+    MAIR_EL1.set(MAIR);
+    let pt_addr = virt_to_phys(pt as *const PageTable as usize) as u64;
+    let txsz = 64 - va_bits() as u64;
+
+    let tcr = TCR_EL1::TBI0::Used
+        + TCR_EL1::TBI1::Used
+        + TCR_EL1::IPS.val(IPS.load(Ordering::Relaxed) as u64)
+        + tg1()
+        // The tables live in DRAM, which is mapped cacheable, the walks have to agree.
+        + TCR_EL1::SH0::Inner
+        + TCR_EL1::SH1::Inner
+        + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::ORGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::IRGN1::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+        + TCR_EL1::EPD1::EnableTTBR1Walks
         + TCR_EL1::A1::TTBR0
-        + TCR_EL1::T0SZ.val(16)
-        + TCR_EL1::EPD1::DisableTTBR1Walks,
-    );
+        + TCR_EL1::T1SZ.val(txsz);
 
-    barrier::isb(barrier::SY);
+    // The granule and the size of the upper half may change under our feet, the switch runs from
+    // the identity map of the boot tables, which keep their 4KiB granule and 48-bit addresses.
+    let switch: unsafe extern "C" fn(u64, u64) =
+        core::mem::transmute(virt_to_phys(switch_ttbr1 as usize));
+    let boot_tcr =
+        tcr + TCR_EL1::TG0::KiB_4 + TCR_EL1::EPD0::EnableTTBR0Walks + TCR_EL1::T0SZ.val(16);
+    switch(pt_addr, boot_tcr.value);
 
-    SCTLR_EL1.modify(SCTLR_EL1::M::Enable);
+    // Until an address space is activated, the lower half isn't mapped at all.
+    TCR_EL1.write(tcr + tg0() + TCR_EL1::EPD0::DisableTTBR0Walks + TCR_EL1::T0SZ.val(txsz));
+
+    // Nothing cached from the boot tables may survive.
+    tlb::flush_all();
+
+    SCTLR_EL1.modify(SCTLR_EL1::M::Enable + SCTLR_EL1::C::Cacheable + SCTLR_EL1::I::Cacheable);
 
     barrier::isb(barrier::SY);
 }
 
+fn tg0() -> FieldValue<u64, TCR_EL1::Register> {
+    match PAGE_SIZE {
+        0x1000 => TCR_EL1::TG0::KiB_4,
+        0x4000 => TCR_EL1::TG0::KiB_16,
+        _ => TCR_EL1::TG0::KiB_64,
+    }
+}
+
+fn tg1() -> FieldValue<u64, TCR_EL1::Register> {
+    match PAGE_SIZE {
+        0x1000 => TCR_EL1::TG1::KiB_4,
+        0x4000 => TCR_EL1::TG1::KiB_16,
+        _ => TCR_EL1::TG1::KiB_64,
+    }
+}
+
+/// Point TTBR1_EL1 to `ttbr1` and write `tcr` in TCR_EL1, called at its physical address.
+/// The writes to the new tables are made visible to the walker first.
+#[naked]
+unsafe extern "C" fn switch_ttbr1(ttbr1: u64, tcr: u64) {
+    asm!(
+        "
+        dsb ishst
+        msr ttbr1_el1, x0
+        msr tcr_el1, x1
+        isb
+        tlbi vmalle1
+        dsb ish
+        isb
+        ret
+        ",
+        options(noreturn)
+    );
+}
+
 pub fn align_up(addr: usize) -> usize {
     mm::align_up(addr, PAGE_SIZE)
 }
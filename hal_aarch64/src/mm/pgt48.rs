@@ -1,28 +1,56 @@
 use hal_core::{
-    mm::{self, PageAlloc, PageEntry, PageMap, Permissions},
-    Error,
+    mm::{self, MemoryType, PageAlloc, PageEntry, PageMap, Permissions},
+    AddressRange, Error,
 };
 
+use super::{mair_index, mair_memory_type, phys_to_virt, tlb, va_bits, virt_to_phys};
+
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 use tock_registers::register_bitfields;
-use tock_registers::registers::{ReadOnly, ReadWrite};
+use tock_registers::registers::ReadWrite;
+
+#[cfg(all(feature = "granule_16k", feature = "granule_64k"))]
+compile_error!("only one of the granule_16k and granule_64k features can be enabled");
+
+// The translation granule is chosen at build time, 4KiB unless one of the `granule_16k` or
+// `granule_64k` features is enabled. The size of a table and of a page is the granule.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+const GRANULE_SHIFT: usize = 12;
+#[cfg(feature = "granule_16k")]
+const GRANULE_SHIFT: usize = 14;
+#[cfg(feature = "granule_64k")]
+const GRANULE_SHIFT: usize = 16;
+
+/// Each level resolves as many bits as there are 8 bytes descriptors in a table.
+const BITS_PER_LEVEL: usize = GRANULE_SHIFT - 3;
+const ENTRIES: usize = 1 << BITS_PER_LEVEL;
+
+/// The walk starts at the first level whose entries map less than the whole address space: with
+/// the 64KiB granule level 1 already covers 48 and 52-bit addresses.
+pub(super) const ROOT_LEVEL: u8 = if GRANULE_SHIFT == 16 { 1 } else { 0 };
+
+// Without FEAT_LPA2, only level 2 can hold blocks with the 16KiB and 64KiB granules.
+#[cfg(not(any(feature = "granule_16k", feature = "granule_64k")))]
+const GRANULE_BLOCK_SIZES: &[usize] = &[4096, 2 * 1024 * 1024, 1024 * 1024 * 1024];
+#[cfg(feature = "granule_16k")]
+const GRANULE_BLOCK_SIZES: &[usize] = &[16 * 1024, 32 * 1024 * 1024];
+#[cfg(feature = "granule_64k")]
+const GRANULE_BLOCK_SIZES: &[usize] = &[64 * 1024, 512 * 1024 * 1024];
+
+/// Position of the lowest bit of the virtual address indexing the tables of `level`.
+const fn level_shift(level: u8) -> usize {
+    GRANULE_SHIFT + BITS_PER_LEVEL * (3 - level as usize)
+}
 
 register_bitfields! [u64,
-    pub VAddrInner [
-        BLOCK_OFFSET OFFSET(0) NUMBITS(12) [],
-        LEVEL3_TABLE_IDX OFFSET(12) NUMBITS(9) [],
-        LEVEL2_TABLE_IDX OFFSET(21) NUMBITS(9) [],
-        LEVEL1_TABLE_IDX OFFSET(30) NUMBITS(9) [],
-        LEVEL0_TABLE_IDX OFFSET(39) NUMBITS(9) [],
-    ],
-
     pub TableEntryInner [
         TYPE OFFSET(0) NUMBITS(2) [
             TABLE_ENTRY = 0b11,
+            BLOCK_ENTRY = 0b01,
             INVALID_ENTRY = 0b00,
         ],
 
-        INDX OFFSET(2) NUMBITS(2) [],
+        INDX OFFSET(2) NUMBITS(3) [],
 
         AP OFFSET(6) NUMBITS(2) [
             U_NONE_K_RW = 0b00,
@@ -43,15 +71,21 @@ register_bitfields! [u64,
             TRUE = 0b1,
         ],
 
+        NG OFFSET(11) NUMBITS(1) [],
+
         DEST OFFSET(12) NUMBITS(36) [],
 
         PXN OFFSET(53) NUMBITS(1) [],
         UXN OFFSET(54) NUMBITS(1) [],
+
+        // Bits 55 to 58 are left to software, the MMU ignores them.
+        COW OFFSET(55) NUMBITS(1) [],
     ],
 
     pub TableDescriptorInner [
         TYPE OFFSET(0) NUMBITS(2) [
             TABLE_DESCRIPTOR = 0b11,
+            BLOCK_ENTRY = 0b01,
             INVALID_ENTRY = 0b00,
         ],
 
@@ -59,27 +93,32 @@ register_bitfields! [u64,
     ],
 ];
 
-struct VAddr(ReadOnly<u64, VAddrInner::Register>);
+struct VAddr(u64);
 
 impl VAddr {
     fn get_level_offset(&self, level: u8) -> usize {
-        let offset = match level {
-            0 => self.0.read(VAddrInner::LEVEL0_TABLE_IDX),
-            1 => self.0.read(VAddrInner::LEVEL1_TABLE_IDX),
-            2 => self.0.read(VAddrInner::LEVEL2_TABLE_IDX),
-            3 => self.0.read(VAddrInner::LEVEL3_TABLE_IDX),
-            _ => panic!("There are only 4 levels"),
+        assert!((ROOT_LEVEL..=3).contains(&level), "There are only 4 levels");
+
+        let shift = level_shift(level);
+        // The root table is indexed by whatever bits of the address are left.
+        let bits = if level == ROOT_LEVEL {
+            va_bits() - shift
+        } else {
+            BITS_PER_LEVEL
         };
 
-        offset as usize
+        ((self.0 >> shift) as usize) & ((1 << bits) - 1)
+    }
+
+    fn as_mm(&self) -> mm::VAddr {
+        mm::VAddr::new(self.0 as usize)
     }
 }
 
 impl From<mm::VAddr> for VAddr {
     fn from(paddr: mm::VAddr) -> Self {
         assert_eq!(usize::BITS, u64::BITS);
-        let val = paddr.val as u64;
-        Self(unsafe { core::mem::transmute::<u64, ReadOnly<u64, VAddrInner::Register>>(val) })
+        Self(paddr.val as u64)
     }
 }
 
@@ -102,15 +141,22 @@ struct TableDescriptor(ReadWrite<u64, TableDescriptorInner::Register>);
 
 impl TableDescriptor {
     fn get_next_level(&mut self) -> &mut PageTable {
-        let raw_pgt = (self.0.read(TableDescriptorInner::DEST) << 12) as *mut PageTable;
+        let raw_pgt = phys_to_virt(self.next_level_addr() as usize) as *mut PageTable;
 
         // Safety: there is no conceivable way for us to know if this pointer is valid.
         // If the pointer in our pagetable are invalid, then we're lost...
         unsafe { raw_pgt.as_mut().unwrap() }
     }
 
+    fn next_level(&self) -> &PageTable {
+        let raw_pgt = phys_to_virt(self.next_level_addr() as usize) as *const PageTable;
+
+        // Safety: same as get_next_level.
+        unsafe { raw_pgt.as_ref().unwrap() }
+    }
+
     fn set_next_level(&mut self, next_level: &mut PageTable) {
-        let next_level_addr = (next_level as *const PageTable) as u64;
+        let next_level_addr = virt_to_phys(next_level as *const PageTable as usize) as u64;
         self.0
             .modify(TableDescriptorInner::DEST.val(next_level_addr >> 12));
         self.0.modify(TableDescriptorInner::TYPE::TABLE_DESCRIPTOR);
@@ -120,6 +166,20 @@ impl TableDescriptor {
         self.0.read(TableDescriptorInner::TYPE) == TableDescriptorInner::TYPE::INVALID_ENTRY.into()
     }
 
+    /// Returns the descriptor itself if it points to a table of the next level.
+    fn as_table(&self) -> Option<&TableDescriptor> {
+        (!self.is_invalid() && !self.is_block()).then_some(self)
+    }
+
+    fn next_level_addr(&self) -> u64 {
+        self.0.read(TableDescriptorInner::DEST) << 12
+    }
+
+    /// Levels above 3 can hold a block entry mapping memory directly instead of a next level.
+    fn is_block(&self) -> bool {
+        self.0.read(TableDescriptorInner::TYPE) == TableDescriptorInner::TYPE::BLOCK_ENTRY.into()
+    }
+
     fn set_invalid(&mut self) {
         self.0.write(TableDescriptorInner::TYPE::INVALID_ENTRY);
     }
@@ -128,13 +188,41 @@ impl TableDescriptor {
 pub struct TableEntry(ReadWrite<u64, TableEntryInner::Register>);
 
 impl TableEntry {
+    fn is_valid(&self) -> bool {
+        self.0.read(TableEntryInner::TYPE) != TableEntryInner::TYPE::INVALID_ENTRY.into()
+    }
+
+    fn get_target(&self) -> u64 {
+        self.0.read(TableEntryInner::DEST) << 12
+    }
+
     fn set_target(&mut self, addr: u64) {
-        let field = TableEntryInner::DEST.val(addr >> 12);
-        self.0.modify(TableEntryInner::TYPE::TABLE_ENTRY);
-        self.0.modify(field);
+        self.0.modify(TableEntryInner::DEST.val(addr >> 12));
+    }
+
+    /// Must be done last, the walker may pick the entry up as soon as it is valid.
+    fn set_valid(&mut self, lvl: u8) {
+        if lvl == 3 {
+            self.0.modify(TableEntryInner::TYPE::TABLE_ENTRY);
+        } else {
+            self.0.modify(TableEntryInner::TYPE::BLOCK_ENTRY);
+        }
+    }
+
+    /// Copy everything but the type and the output address of `other`.
+    fn set_attributes_from(&mut self, other: &TableEntry) {
+        let type_mask = TableEntryInner::TYPE.mask << TableEntryInner::TYPE.shift;
+        let dest_mask = TableEntryInner::DEST.mask << TableEntryInner::DEST.shift;
+
+        self.0.set(other.0.get() & !(type_mask | dest_mask));
     }
 
     fn set_permissions(&mut self, perms: mm::Permissions) {
+        debug_assert!(!perms.contains(mm::Permissions::WRITE | mm::Permissions::COPY_ON_WRITE));
+        self.0.modify(
+            TableEntryInner::COW.val(perms.contains(mm::Permissions::COPY_ON_WRITE) as u64),
+        );
+
         // TODO: Can we improve this?
         if perms.contains(mm::Permissions::USER) {
             if perms.contains(mm::Permissions::WRITE) {
@@ -165,6 +253,37 @@ impl TableEntry {
         }
     }
 
+    fn get_permissions(&self) -> mm::Permissions {
+        let mut perms = mm::Permissions::READ;
+
+        let (user, write) = match self.0.read_as_enum(TableEntryInner::AP) {
+            Some(TableEntryInner::AP::Value::U_NONE_K_RW) => (false, true),
+            Some(TableEntryInner::AP::Value::U_RW_K_RW) => (true, true),
+            Some(TableEntryInner::AP::Value::U_NONE_K_R) => (false, false),
+            Some(TableEntryInner::AP::Value::U_R_K_R) => (true, false),
+            None => unreachable!("AP is 2 bits wide, all values are covered"),
+        };
+        let execute_never = if user {
+            self.0.is_set(TableEntryInner::UXN)
+        } else {
+            self.0.is_set(TableEntryInner::PXN)
+        };
+
+        perms.set(mm::Permissions::USER, user);
+        perms.set(mm::Permissions::WRITE, write);
+        perms.set(mm::Permissions::EXECUTE, !execute_never);
+        perms.set(
+            mm::Permissions::COPY_ON_WRITE,
+            self.0.is_set(TableEntryInner::COW),
+        );
+
+        perms
+    }
+
+    fn mem_type(&self) -> MemoryType {
+        mair_memory_type(self.0.read(TableEntryInner::INDX) as usize)
+    }
+
     fn set_mair_index(&mut self, index: usize) {
         // MAIR can store only 8 attributes
         assert!(index < 8);
@@ -179,6 +298,16 @@ impl TableEntry {
     fn set_access_flag(&mut self) {
         self.0.modify(TableEntryInner::AF::TRUE);
     }
+
+    /// Non-global translations are tagged with the ASID they were walked with, so that address
+    /// spaces can't see each other's entries.
+    fn set_not_global(&mut self) {
+        self.0.modify(TableEntryInner::NG.val(1));
+    }
+
+    fn is_global(&self) -> bool {
+        !self.0.is_set(TableEntryInner::NG)
+    }
 }
 
 impl PageEntry for TableEntry {
@@ -201,18 +330,23 @@ impl PageTableContent {
     }
 }
 
-#[repr(align(0x1000))]
+#[cfg_attr(
+    not(any(feature = "granule_16k", feature = "granule_64k")),
+    repr(align(0x1000))
+)]
+#[cfg_attr(feature = "granule_16k", repr(align(0x4000)))]
+#[cfg_attr(feature = "granule_64k", repr(align(0x10000)))]
 pub struct PageTable {
-    entries: [PageTableContent; 512],
+    entries: [PageTableContent; ENTRIES],
 }
 
 impl PageTable {
     pub const fn zeroed() -> Self {
         #[allow(clippy::uninit_assumed_init)]
-        let mut entries: [PageTableContent; 512] =
+        let mut entries: [PageTableContent; ENTRIES] =
             unsafe { core::mem::MaybeUninit::uninit().assume_init() };
         let mut i = 0;
-        while i < 512 {
+        while i < ENTRIES {
             entries[i] = PageTableContent::new_invalid();
             i += 1;
         }
@@ -220,14 +354,254 @@ impl PageTable {
     }
 }
 
+impl PageTable {
+    fn is_empty(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|content| unsafe { &content.descriptor }.is_invalid())
+    }
+
+    /// Size of the memory mapped by an entry at `lvl`.
+    fn level_size(lvl: u8) -> usize {
+        1 << level_shift(lvl)
+    }
+
+    /// Returns the valid page or block entry mapping `va` along with its level.
+    fn leaf(&self, va: &VAddr) -> Option<(&TableEntry, u8)> {
+        let mut pagetable = self;
+
+        for lvl in ROOT_LEVEL..3 {
+            let content = &pagetable.entries[va.get_level_offset(lvl)];
+            let descriptor = unsafe { &content.descriptor };
+
+            if descriptor.is_invalid() {
+                return None;
+            }
+
+            if descriptor.is_block() {
+                return Some((unsafe { &content.entry }, lvl));
+            }
+
+            pagetable = descriptor.next_level();
+        }
+
+        let entry = unsafe { &pagetable.entries[va.get_level_offset(3)].entry };
+
+        entry.is_valid().then_some((entry, 3))
+    }
+
+    /// Returns the entry of `va` at `lvl`, creating the missing tables on the way.
+    /// Blocks found on the way are split, a table found at `lvl` is freed along with its
+    /// sub-tables so that the entry can become a block.
+    fn entry_at_level(
+        &mut self,
+        va: &VAddr,
+        lvl: u8,
+        allocator: &impl PageAlloc,
+    ) -> Result<&mut TableEntry, Error> {
+        let mut pagetable = self;
+
+        for current_lvl in ROOT_LEVEL..=lvl {
+            let content = &mut pagetable.entries[va.get_level_offset(current_lvl)];
+
+            if current_lvl == lvl {
+                let descriptor = unsafe { &mut content.descriptor };
+
+                if lvl < 3 && !descriptor.is_invalid() && !descriptor.is_block() {
+                    let table = descriptor.get_next_level() as *mut PageTable;
+                    descriptor.set_invalid();
+                    // The TLB and the walk caches may hold entries of any page below the table,
+                    // they can't outlive it.
+                    tlb::flush_all();
+
+                    // Safety: the descriptor was pointing to this table, nothing else references
+                    // it.
+                    unsafe { (*table).free_tables(lvl + 1, allocator) };
+                    let _ = allocator.dealloc(virt_to_phys(table as usize), 1);
+                }
+
+                return Ok(unsafe { &mut content.entry });
+            }
+
+            if unsafe { &content.descriptor }.is_invalid() {
+                let new_page_table = PageTable::new(allocator)?;
+                unsafe { &mut content.descriptor }.set_next_level(new_page_table);
+            } else if unsafe { &content.descriptor }.is_block() {
+                Self::split(content, current_lvl, va, allocator)?;
+            }
+
+            pagetable = unsafe { &mut content.descriptor }.get_next_level();
+        }
+
+        unreachable!("We should have returned at lvl {} by now", lvl);
+    }
+
+    /// Replace the block in `content` at `lvl` by a table of smaller entries mapping the same
+    /// memory with the same attributes.
+    fn split(
+        content: &mut PageTableContent,
+        lvl: u8,
+        va: &VAddr,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let table = PageTable::new(allocator)?;
+        let block = unsafe { &content.entry };
+
+        for (i, sub_content) in table.entries.iter_mut().enumerate() {
+            let entry = unsafe { &mut sub_content.entry };
+
+            entry.set_attributes_from(block);
+            entry.set_target(block.get_target() + (i * Self::level_size(lvl + 1)) as u64);
+            entry.set_valid(lvl + 1);
+        }
+
+        // Break-before-make, the block has to be gone from the TLB before the table replaces it.
+        unsafe { &mut content.entry }.set_invalid();
+        tlb::flush_page(va.as_mm());
+
+        unsafe { &mut content.descriptor }.set_next_level(table);
+        tlb::sync();
+
+        Ok(())
+    }
+
+    /// Call `f` with the leaves below this table, which is at `lvl` and translates the addresses
+    /// starting at `base`.
+    fn walk_level(&self, base: u64, lvl: u8, f: &mut impl FnMut(mm::Mapping)) {
+        for (index, content) in self.entries.iter().enumerate() {
+            let descriptor = unsafe { &content.descriptor };
+            if descriptor.is_invalid() {
+                continue;
+            }
+
+            let va = base | ((index as u64) << level_shift(lvl));
+            if lvl < 3 && !descriptor.is_block() {
+                descriptor.next_level().walk_level(va, lvl + 1, f);
+                continue;
+            }
+
+            // The tables don't know which half they translate, but only the upper half, the
+            // kernel's, is mapped global.
+            let entry = unsafe { &content.entry };
+            let va = if entry.is_global() {
+                va | !((1 << va_bits()) - 1)
+            } else {
+                va
+            };
+
+            f(mm::Mapping {
+                va: mm::VAddr::new(va as usize),
+                pa: mm::PAddr::new(entry.get_target() as usize),
+                size: Self::level_size(lvl),
+                perms: entry.get_permissions(),
+                mem_type: entry.mem_type(),
+            });
+        }
+    }
+
+    /// Give back to `allocator` all the tables below this one, which is at `lvl`.
+    pub(super) fn free_tables(&mut self, lvl: u8, allocator: &impl PageAlloc) {
+        if lvl == 3 {
+            return;
+        }
+
+        for content in self.entries.iter_mut() {
+            let descriptor = unsafe { &mut content.descriptor };
+            if descriptor.as_table().is_none() {
+                continue;
+            }
+
+            let table = descriptor.get_next_level();
+            table.free_tables(lvl + 1, allocator);
+            let _ = allocator.dealloc(virt_to_phys(table as *mut PageTable as usize), 1);
+        }
+    }
+
+    fn map_at_level(
+        &mut self,
+        va: mm::VAddr,
+        pa: mm::PAddr,
+        perms: Permissions,
+        mem_type: MemoryType,
+        lvl: u8,
+        allocator: &impl PageAlloc,
+    ) -> Result<&mut TableEntry, Error> {
+        let vaddr = VAddr::from(va);
+        let pa = PAddr::from(pa);
+        let entry = self.entry_at_level(&vaddr, lvl, allocator)?;
+
+        // Break-before-make: a live entry has to be invalidated and flushed before it can point
+        // somewhere else.
+        let was_valid = entry.is_valid();
+        entry.set_invalid();
+        if was_valid {
+            tlb::flush_page(va);
+        }
+
+        entry.set_target(u64::from(&pa));
+        entry.set_permissions(perms);
+        entry.set_mair_index(mair_index(mem_type));
+        entry.set_shareable();
+        entry.set_access_flag();
+        // The upper half belongs to the kernel and is the same for everyone, the TLB entries of
+        // the lower half are tagged with the ASID of their address space.
+        if va.val >> 63 == 0 {
+            entry.set_not_global();
+        }
+        entry.set_valid(lvl);
+        tlb::sync();
+
+        Ok(entry)
+    }
+
+    /// Invalidate the entry of `va` in the sub-tree starting at `lvl` and free the tables that are
+    /// left without any valid entry.
+    fn unmap_level(&mut self, va: &VAddr, lvl: u8, allocator: &impl PageAlloc) -> Option<u64> {
+        let content = &mut self.entries[va.get_level_offset(lvl)];
+
+        if lvl == 3 {
+            let entry = unsafe { &mut content.entry };
+            if !entry.is_valid() {
+                return None;
+            }
+
+            let paddr = entry.get_target();
+            entry.set_invalid();
+            tlb::flush_page(va.as_mm());
+
+            return Some(paddr);
+        }
+
+        let descriptor = unsafe { &mut content.descriptor };
+        if descriptor.is_invalid() {
+            return None;
+        }
+
+        let next_level = descriptor.get_next_level();
+        let paddr = next_level.unmap_level(va, lvl + 1, allocator)?;
+
+        if next_level.is_empty() {
+            let next_level_addr = descriptor.next_level_addr() as usize;
+            descriptor.set_invalid();
+            // The walk caches may still point to the table.
+            tlb::flush_page(va.as_mm());
+            // Not being able to give back the page isn't worth failing the unmap.
+            let _ = allocator.dealloc(next_level_addr, 1);
+        }
+
+        Some(paddr)
+    }
+}
+
 impl PageMap for PageTable {
-    const PAGE_SIZE: usize = 4096;
+    const PAGE_SIZE: usize = 1 << GRANULE_SHIFT;
+    const BLOCK_SIZES: &'static [usize] = GRANULE_BLOCK_SIZES;
     type Entry = TableEntry;
 
     fn new(allocator: &impl PageAlloc) -> Result<&'static mut Self, Error> {
         let page = allocator.alloc(1)?;
-        let page_table = page as *mut PageTable;
-        // Safety: the PMM gave us the memory, it should be a valid pointer.
+        let page_table = phys_to_virt(page) as *mut PageTable;
+        // Safety: the PMM gave us the memory, it is reachable through the direct map.
         let page_table: &mut PageTable = unsafe { page_table.as_mut().unwrap() };
 
         page_table
@@ -243,55 +617,95 @@ impl PageMap for PageTable {
         va: mm::VAddr,
         pa: mm::PAddr,
         perms: Permissions,
+        mem_type: MemoryType,
         allocator: &impl PageAlloc,
     ) -> Result<&mut TableEntry, Error> {
-        let va = VAddr::from(va);
-        let pa = PAddr::from(pa);
-        let mut pagetable = self;
+        self.map_at_level(va, pa, perms, mem_type, 3, allocator)
+    }
 
-        for lvl in 0..=3 {
-            let offset = va.get_level_offset(lvl);
-            let content = &mut pagetable.entries[offset];
+    fn map_block(
+        &mut self,
+        va: mm::VAddr,
+        pa: mm::PAddr,
+        size: usize,
+        perms: Permissions,
+        mem_type: MemoryType,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let lvl = Self::BLOCK_SIZES
+            .iter()
+            .position(|&block_size| block_size == size)
+            .filter(|_| va.val % size == 0 && pa.val % size == 0)
+            .ok_or(Error::InvalidBlock(va.val))?;
+
+        self.map_at_level(va, pa, perms, mem_type, 3 - lvl as u8, allocator)
+            .map(|_| ())
+    }
 
-            if lvl == 3 {
-                let entry = unsafe { &mut content.entry };
-                entry.set_target(u64::from(&pa));
-                entry.set_permissions(perms);
-                entry.set_mair_index(0);
-                entry.set_shareable();
-                entry.set_access_flag();
+    fn unmap(&mut self, va: mm::VAddr, allocator: &impl PageAlloc) -> Option<mm::PAddr> {
+        let va = VAddr::from(va);
 
-                return Ok(entry);
-            }
+        let (_, lvl) = self.leaf(&va)?;
+        if lvl < 3 {
+            self.entry_at_level(&va, 3, allocator).ok()?;
+        }
 
-            let descriptor = unsafe { &mut content.descriptor };
-            if descriptor.is_invalid() {
-                let new_page_table = PageTable::new(allocator)?;
-                descriptor.set_next_level(new_page_table);
-            }
+        self.unmap_level(&va, ROOT_LEVEL, allocator)
+            .map(|paddr| mm::PAddr::new(paddr as usize))
+    }
 
-            pagetable = descriptor.get_next_level();
-        }
+    fn translate(&self, va: mm::VAddr) -> Option<(mm::PAddr, Permissions)> {
+        let (entry, lvl) = self.leaf(&VAddr::from(va))?;
+
+        let offset = va.val & (Self::level_size(lvl) - 1);
+        let paddr = mm::PAddr::new(entry.get_target() as usize + offset);
 
-        unreachable!("We should have reached lvl 3 and returned by now...");
+        Some((paddr, entry.get_permissions()))
     }
 
-    fn add_invalid_entry(
+    fn protect(
         &mut self,
-        va: mm::VAddr,
+        range: AddressRange,
+        perms: Permissions,
         allocator: &impl PageAlloc,
     ) -> Result<(), Error> {
-        let entry = self.map(
-            va,
-            mm::PAddr {
-                val: 0x0A0A_0A0A_0A0A_0A0A,
-            },
-            mm::Permissions::READ,
-            allocator,
-        )?;
+        let mut page = range.start;
 
-        entry.set_invalid();
+        while page < range.end {
+            let va = VAddr::from(mm::VAddr::new(page));
+            let (_, leaf_lvl) = self.leaf(&va).ok_or(Error::NotMapped(page))?;
+
+            // A block only partially covered by the range is split down to pages.
+            let size = Self::level_size(leaf_lvl);
+            let lvl = if page % size == 0 && page + size <= range.end {
+                leaf_lvl
+            } else {
+                3
+            };
+
+            self.entry_at_level(&va, lvl, allocator)?
+                .set_permissions(perms);
+            tlb::flush_page(mm::VAddr::new(page));
+
+            page += Self::level_size(lvl);
+        }
 
         Ok(())
     }
+
+    fn flush_tlb_page(&self, va: mm::VAddr) {
+        tlb::flush_page(va);
+    }
+
+    fn flush_tlb_asid(&self, asid: mm::Asid) {
+        tlb::flush_asid(asid);
+    }
+
+    fn flush_tlb_all(&self) {
+        tlb::flush_all();
+    }
+
+    fn walk(&self, f: &mut impl FnMut(mm::Mapping)) {
+        self.walk_level(0, ROOT_LEVEL, f);
+    }
 }
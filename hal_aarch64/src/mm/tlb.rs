@@ -0,0 +1,62 @@
+//! TLB maintenance, invalidations are broadcast to the inner shareable domain.
+
+use core::arch::asm;
+use hal_core::mm::{Asid, VAddr};
+
+/// The TLBI instructions take VA[55:12] in their 44 lowest bits and the ASID in the upper 16.
+fn tlbi_operand(va: VAddr, asid: Asid) -> usize {
+    ((asid as usize) << 48) | ((va.val >> 12) & ((1 << 44) - 1))
+}
+
+/// Make the pagetable writes done so far visible to the pagetable walker.
+pub fn sync() {
+    unsafe {
+        asm!("dsb ishst", "isb");
+    }
+}
+
+/// Invalidate the cached translations of `va` in all address spaces.
+pub fn flush_page(va: VAddr) {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vaae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) tlbi_operand(va, 0)
+        );
+    }
+}
+
+/// Invalidate the cached translation of `va` in the address space `asid`.
+pub fn flush_page_asid(va: VAddr, asid: Asid) {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vae1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) tlbi_operand(va, asid)
+        );
+    }
+}
+
+/// Invalidate all the cached translations of the address space `asid`, global mappings are kept.
+pub fn flush_asid(asid: Asid) {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi aside1is, {}",
+            "dsb ish",
+            "isb",
+            in(reg) (asid as usize) << 48
+        );
+    }
+}
+
+/// Invalidate all the cached translations.
+pub fn flush_all() {
+    unsafe {
+        asm!("dsb ishst", "tlbi vmalle1is", "dsb ish", "isb");
+    }
+}
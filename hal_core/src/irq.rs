@@ -0,0 +1,54 @@
+//! Interrupt controllers and the dispatch of their interrupts.
+
+use super::Error;
+
+/// How an interrupt line signals an interrupt.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Pending as long as the device holds the line.
+    Level,
+    /// Pending once per rising edge of the line.
+    Edge,
+}
+
+/// Priority given to the lines that don't ask for a specific one.
+pub const DEFAULT_PRIORITY: u8 = 0x80;
+
+/// Called by the hal with every line it claims from the interrupt controller, the line is
+/// completed once it returns.
+pub type IrqCallbackFn = fn(u32);
+
+/// An interrupt controller, lines are numbered the way the controller and the device tree do.
+///
+/// Every method takes `&self`: they are called from interrupt handlers as well as from regular
+/// code, the registers of the controller take care of the concurrent accesses.
+pub trait IrqChip {
+    /// Let `line` interrupt the cpu.
+    fn enable(&self, line: u32) -> Result<(), Error>;
+
+    /// Stop forwarding the interrupts of `line`, they stay pending in the controller.
+    fn disable(&self, line: u32) -> Result<(), Error>;
+
+    /// 0 is the lowest priority and never interrupts, higher values are more urgent. Controllers
+    /// with fewer levels keep the most significant bits.
+    fn set_priority(&self, line: u32, priority: u8) -> Result<(), Error>;
+
+    fn set_trigger(&self, line: u32, trigger: Trigger) -> Result<(), Error>;
+
+    /// Acknowledge the most urgent pending interrupt and return its line, `None` if nothing is
+    /// pending anymore.
+    fn claim(&self) -> Option<u32>;
+
+    /// Signal the end of the handling of a line returned by [`IrqChip::claim`].
+    fn complete(&self, line: u32);
+
+    /// Make `line` pending as if its device had raised it. Not every controller can, the PLIC
+    /// for one only learns about pending interrupts from the devices.
+    fn set_pending(&self, line: u32) -> Result<(), Error> {
+        Err(Error::InvalidIrqLine(line))
+    }
+
+    /// The line and trigger described by the `interrupts` cells of a device tree node wired to
+    /// this controller, there are `#interrupt-cells` of them.
+    fn decode_interrupt(&self, cells: &[u32]) -> Result<(u32, Trigger), Error>;
+}
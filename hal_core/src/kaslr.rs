@@ -0,0 +1,174 @@
+//! Kernel address space layout randomization.
+//!
+//! With the `kaslr` feature, the kernel image is linked as position independent and `_start` moves
+//! it up by a random number of gigabytes from its link address, the seed comes from the
+//! `/chosen/kaslr-seed` or `/chosen/rng-seed` property of the device tree.
+//!
+//! Everything here runs from `_start` with the MMU off, at the physical address of the kernel and
+//! before the relocations are applied: no absolute address may be used, which rules out jump tables
+//! and function pointers, and memory is only accessed with aligned loads and stores.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The image is slid by multiples of this, the boot pagetables map it with gigabyte pages.
+pub const SLIDE_ALIGN: usize = 1 << 30;
+
+static SLIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// How far above its link address the kernel image runs.
+pub fn slide() -> usize {
+    SLIDE.load(Ordering::Relaxed)
+}
+
+/// An entry of the `.rela.dyn` section.
+#[repr(C)]
+pub struct Rela {
+    pub offset: u64,
+    pub info: u64,
+    pub addend: u64,
+}
+
+/// Pick the slide of the kernel and apply its relocations, returns the slide.
+///
+/// `phys_start` and `link_start` are where the image starts in physical memory and where it was
+/// linked, `rela` is the physical address range of its relocations and `relative_type` the
+/// architecture's number for the relative ones, the only ones a position independent kernel has.
+/// The image must fit in the gigabyte it is linked in.
+///
+/// # Safety
+/// Must be called once, from `_start` with the MMU off, `device_tree` is the physical address of
+/// the device tree or 0.
+pub unsafe fn relocate(
+    device_tree: usize,
+    phys_start: usize,
+    link_start: usize,
+    rela: (usize, usize),
+    relative_type: u32,
+) -> usize {
+    let slide = if cfg!(feature = "kaslr") {
+        slide_for_seed(device_tree_seed(device_tree), link_start)
+    } else {
+        0
+    };
+
+    apply_relocations(phys_start, link_start, rela, relative_type, slide);
+    SLIDE.store(slide, Ordering::Relaxed);
+
+    slide
+}
+
+/// The slide of an image linked at `link_start` for `seed`, up to the last gigabyte of the address
+/// space.
+pub fn slide_for_seed(seed: u64, link_start: usize) -> usize {
+    let slots = (usize::MAX - link_start) / SLIDE_ALIGN + 1;
+    (seed % slots as u64) as usize * SLIDE_ALIGN
+}
+
+/// Write the relative relocations of `rela` to the image at `phys_start`, moved by `slide`.
+///
+/// # Safety
+/// `rela` must hold relocations of the image at `phys_start`, linked at `link_start`.
+pub unsafe fn apply_relocations(
+    phys_start: usize,
+    link_start: usize,
+    rela: (usize, usize),
+    relative_type: u32,
+    slide: usize,
+) {
+    let mut entry = rela.0;
+    while entry + core::mem::size_of::<Rela>() <= rela.1 {
+        let rela = &*(entry as *const Rela);
+        if rela.info as u32 == relative_type {
+            let target = phys_start + (rela.offset as usize - link_start);
+            (target as *mut u64).write_volatile(rela.addend.wrapping_add(slide as u64));
+        }
+
+        entry += core::mem::size_of::<Rela>();
+    }
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+/// Byte per byte, the device tree only guarantees 4 bytes alignment and with the MMU off
+/// unaligned accesses fault.
+unsafe fn byte(addr: usize) -> u8 {
+    (addr as *const u8).read_volatile()
+}
+
+unsafe fn be32(addr: usize) -> u32 {
+    (0..4).fold(0, |value, i| (value << 8) | byte(addr + i) as u32)
+}
+
+/// Whether the nul terminated string at `addr` is `s`, which includes the nul.
+unsafe fn str_eq(addr: usize, s: &[u8]) -> bool {
+    s.iter().enumerate().all(|(i, &c)| byte(addr + i) == c)
+}
+
+/// Returns the seed found in the `/chosen` node of the device tree at `fdt`, or 0. `kaslr-seed`
+/// is preferred over `rng-seed`, the property used is zeroed so that it can't be read back.
+///
+/// # Safety
+/// `fdt` must be 0 or the address of a device tree that can be written to.
+pub unsafe fn device_tree_seed(fdt: usize) -> u64 {
+    if fdt == 0 || be32(fdt) != FDT_MAGIC {
+        return 0;
+    }
+
+    let end = fdt + be32(fdt + 4) as usize;
+    let strings = fdt + be32(fdt + 12) as usize;
+    let mut token = fdt + be32(fdt + 8) as usize;
+
+    let mut depth = 0;
+    let mut in_chosen = false;
+    // (address, length) of the kaslr-seed and rng-seed values.
+    let mut kaslr_seed = None;
+    let mut rng_seed = None;
+
+    while token + 4 <= end {
+        let kind = be32(token);
+        token += 4;
+
+        if kind == FDT_BEGIN_NODE {
+            depth += 1;
+            in_chosen = depth == 2 && str_eq(token, b"chosen\0");
+            while byte(token) != 0 {
+                token += 1;
+            }
+            token = (token + 4) & !3;
+        } else if kind == FDT_END_NODE {
+            depth -= 1;
+            in_chosen = false;
+        } else if kind == FDT_PROP {
+            let len = be32(token) as usize;
+            let name = strings + be32(token + 4) as usize;
+            let value = token + 8;
+
+            if in_chosen && str_eq(name, b"kaslr-seed\0") {
+                kaslr_seed = Some((value, len));
+            } else if in_chosen && str_eq(name, b"rng-seed\0") {
+                rng_seed = Some((value, len));
+            }
+            token = (value + len + 3) & !3;
+        } else if kind == FDT_END {
+            break;
+        }
+    }
+
+    let (value, len) = match kaslr_seed.or(rng_seed) {
+        Some(seed) => seed,
+        None => return 0,
+    };
+
+    // FNV-1a, whatever the length of the seed all its bytes count.
+    let mut seed = 0xcbf2_9ce4_8422_2325u64;
+    for i in 0..len {
+        seed = (seed ^ byte(value + i) as u64).wrapping_mul(0x0100_0000_01b3);
+        (value as *mut u8).add(i).write_volatile(0);
+    }
+
+    seed
+}
@@ -4,11 +4,27 @@
 use core::convert::Into;
 use core::ops::Range;
 
+pub mod irq;
+pub mod kaslr;
 pub mod mm;
 
 #[derive(Debug)]
 pub enum Error {
     Alloc(mm::AllocatorError),
+    /// The virtual address doesn't have a valid mapping.
+    NotMapped(usize),
+    /// The block size isn't supported by the pagetable or the addresses aren't aligned on it.
+    InvalidBlock(usize),
+    /// All the address space identifiers are in use.
+    NoFreeAsid,
+    /// The interrupt controller doesn't have this line.
+    InvalidIrqLine(u32),
+    /// No interrupt controller was initialized.
+    NoIrqChip,
+    /// There is no driver for any of the compatible strings of the interrupt controller.
+    NoMatchingIrqChip,
+    /// A property of the device tree node is missing or has an unexpected value.
+    InvalidDeviceTreeNode(&'static str),
 }
 
 impl From<mm::AllocatorError> for Error {
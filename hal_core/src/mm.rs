@@ -2,6 +2,9 @@ use log::trace;
 
 use super::{AddressRange, Error};
 
+use core::fmt;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
 #[derive(Debug, Clone, Copy)]
 pub struct VAddr {
     pub val: usize,
@@ -40,21 +43,325 @@ impl PAddr {
 }
 
 bitflags::bitflags! {
-    #[derive(Clone, Copy)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct Permissions: u8 {
         const READ    = 0b00000001;
         const WRITE   = 0b00000010;
         const EXECUTE = 0b00000100;
         const USER    = 0b00001000;
+        /// Kept in the software bits of the entry, the page is shared and mapped without
+        /// [`Permissions::WRITE`] until the first write copies it.
+        const COPY_ON_WRITE = 0b00010000;
     }
 }
 
+/// How the memory behind a mapping is accessed by the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Regular memory, cached with write-back. What DRAM should be mapped as.
+    NormalCacheable,
+    /// Regular memory that isn't cached.
+    NormalNonCacheable,
+    /// Device registers, accesses aren't merged nor reordered but a write can be acknowledged
+    /// before it reaches the device. What MMIO should be mapped as.
+    DeviceNGnRE,
+    /// Device registers, a write is only acknowledged once the device got it.
+    DeviceNGnRnE,
+}
+
 pub type PageAllocFn = fn(usize) -> PAddr;
 
+/// A valid leaf entry of a pagetable, or a run of them when coalesced.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub va: VAddr,
+    pub pa: PAddr,
+    pub size: usize,
+    pub perms: Permissions,
+    pub mem_type: MemoryType,
+}
+
+impl Mapping {
+    /// Whether `next` picks up where this mapping ends, in both address spaces and with the same
+    /// attributes.
+    fn is_continued_by(&self, next: &Mapping) -> bool {
+        self.va.val.checked_add(self.size) == Some(next.va.val)
+            && self.pa.val + self.size == next.pa.val
+            && self.perms == next.perms
+            && self.mem_type == next.mem_type
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |perm, c| if self.perms.contains(perm) { c } else { '-' };
+
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {}{}{}{}{} {:?}",
+            self.va.val,
+            self.va.val.wrapping_add(self.size),
+            self.pa.val,
+            flag(Permissions::READ, 'r'),
+            flag(Permissions::WRITE, 'w'),
+            flag(Permissions::EXECUTE, 'x'),
+            flag(Permissions::USER, 'u'),
+            flag(Permissions::COPY_ON_WRITE, 'c'),
+            self.mem_type,
+        )
+    }
+}
+
+/// Prints the mappings of a pagetable, one run of contiguous leaves per line.
+pub struct Layout<'a, P: PageMap>(&'a P);
+
+impl<P: PageMap> fmt::Display for Layout<'_, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = Ok(());
+        self.0.walk_coalesced(&mut |mapping| {
+            if result.is_ok() {
+                result = writeln!(f, "{}", mapping);
+            }
+        });
+
+        result
+    }
+}
+
+/// The kind of access that faulted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAccess {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page fault as decoded by the HAL from the trap registers.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The address that was accessed, not aligned on a page.
+    pub addr: VAddr,
+    pub access: FaultAccess,
+    /// Whether the access came from user mode.
+    pub user: bool,
+}
+
+/// Called by the HAL on a page fault, returns whether the fault was resolved and the access can be
+/// retried.
+pub type PageFaultCallbackFn = fn(&PageFault) -> bool;
+
+/// Address space identifier tagging the TLB entries of an address space.
+pub type Asid = u16;
+
+/// Number of ASIDs an [`AsidAllocator`] manages at most.
+const MANAGED_ASIDS: usize = 256;
+
+/// Hands out the ASIDs of the address spaces, ASID 0 belongs to the kernel's pagetable.
+/// At most 256 of them are managed: aarch64 implementations have at least 8 bits of ASID, riscv
+/// ones may have none at all and are probed, see [`Self::set_asid_bits`].
+pub struct AsidAllocator {
+    used: [AtomicU64; MANAGED_ASIDS / 64],
+    /// Number of ASIDs the hardware implements, capped to the managed ones.
+    count: AtomicUsize,
+}
+
+impl AsidAllocator {
+    pub const fn new() -> Self {
+        Self {
+            used: [
+                AtomicU64::new(1),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            count: AtomicUsize::new(MANAGED_ASIDS),
+        }
+    }
+
+    /// Only hand out the ASIDs that fit in `bits`, to be called before the first allocation.
+    pub fn set_asid_bits(&self, bits: u32) {
+        let count = 1usize.checked_shl(bits).unwrap_or(usize::MAX);
+        self.count
+            .store(count.min(MANAGED_ASIDS), Ordering::Relaxed);
+    }
+
+    /// Whether there are ASIDs besides the kernel's. Without them, all the address spaces are
+    /// tagged with ASID 0 and the TLB has to be flushed whenever switching to one.
+    pub fn has_asids(&self) -> bool {
+        self.count.load(Ordering::Relaxed) > 1
+    }
+
+    pub fn alloc(&self) -> Result<Asid, Error> {
+        let count = self.count.load(Ordering::Relaxed);
+
+        for (i, word) in self.used.iter().enumerate().take((count + 63) / 64) {
+            let mut current = word.load(Ordering::Relaxed);
+
+            while current != u64::MAX {
+                let bit = current.trailing_ones();
+                if i * 64 + bit as usize >= count {
+                    break;
+                }
+
+                match word.compare_exchange_weak(
+                    current,
+                    current | (1 << bit),
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Ok((i * 64) as Asid + bit as Asid),
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+
+        Err(Error::NoFreeAsid)
+    }
+
+    /// The TLB entries tagged with `asid` must have been flushed before it is given back.
+    pub fn free(&self, asid: Asid) {
+        assert_ne!(asid, 0, "the kernel's ASID can't be freed");
+
+        let (word, bit) = (asid as usize / 64, asid as usize % 64);
+        self.used[word].fetch_and(!(1 << bit), Ordering::Release);
+    }
+}
+
+impl Default for AsidAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pagetables of the [`AddressSpace`]s, each architecture tells how they get the kernel
+/// mappings and how to switch to them.
+pub trait AddressSpaceTable: PageMap + 'static {
+    /// The ASIDs the address spaces are tagged with.
+    fn asids() -> &'static AsidAllocator;
+
+    /// Allocate the root table of a new address space.
+    fn new_root(allocator: &impl PageAlloc) -> Result<&'static mut Self, Error>;
+
+    /// Called before the mapping of `va` changes, to make sure that only this pagetable sees the
+    /// change. Nothing to do if it doesn't share any table with the kernel.
+    fn make_private(&mut self, _va: VAddr, _allocator: &impl PageAlloc) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Translate the addresses of the processes through this table, tagging the TLB entries with
+    /// `asid`.
+    fn activate(&self, asid: Asid);
+
+    /// Give back `root` and the tables below it that belong to the address space.
+    fn free_root(root: &'static mut Self, allocator: &impl PageAlloc);
+}
+
+/// A pagetable of its own tagged with an ASID, for the addresses of a process.
+///
+/// Without ASIDs, all the address spaces use the kernel's and the whole TLB is flushed when
+/// switching to one of them.
+pub struct AddressSpace<T: AddressSpaceTable> {
+    root: &'static mut T,
+    asid: Asid,
+}
+
+impl<T: AddressSpaceTable> AddressSpace<T> {
+    pub fn new(allocator: &impl PageAlloc) -> Result<Self, Error> {
+        let asids = T::asids();
+        let asid = if asids.has_asids() { asids.alloc()? } else { 0 };
+
+        let root = match T::new_root(allocator) {
+            Ok(root) => root,
+            Err(e) => {
+                if asid != 0 {
+                    asids.free(asid);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self { root, asid })
+    }
+
+    pub fn asid(&self) -> Asid {
+        self.asid
+    }
+
+    /// Map the page `va` to `pa` as normal, cacheable memory.
+    pub fn map(
+        &mut self,
+        va: VAddr,
+        pa: PAddr,
+        perms: Permissions,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        self.root.make_private(va, allocator)?;
+        self.root
+            .map(va, pa, perms, MemoryType::NormalCacheable, allocator)
+            .map(|_| ())
+    }
+
+    /// Returns where `va` was mapped to, an error means that it may still be mapped.
+    pub fn unmap(&mut self, va: VAddr, allocator: &impl PageAlloc) -> Result<Option<PAddr>, Error> {
+        self.root.make_private(va, allocator)?;
+        Ok(self.root.unmap(va, allocator))
+    }
+
+    pub fn translate(&self, va: VAddr) -> Option<(PAddr, Permissions)> {
+        self.root.translate(va)
+    }
+
+    pub fn protect(
+        &mut self,
+        range: AddressRange,
+        perms: Permissions,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        for page in range.iter_pages(T::PAGE_SIZE) {
+            self.root.make_private(VAddr::new(page), allocator)?;
+        }
+
+        self.root.protect(range, perms, allocator)
+    }
+
+    /// Switch to this address space, the translations cached for other ASIDs stay valid.
+    pub fn activate(&self) {
+        self.root.activate(self.asid);
+
+        if self.asid == 0 {
+            // The cached translations may come from any other address space.
+            self.root.flush_tlb_all();
+        }
+    }
+
+    /// Free the tables of this address space and give its ASID back, it mustn't be the active
+    /// one.
+    pub fn destroy(self, allocator: &impl PageAlloc) {
+        if self.asid == 0 {
+            self.root.flush_tlb_all();
+        } else {
+            self.root.flush_tlb_asid(self.asid);
+        }
+
+        T::free_root(self.root, allocator);
+        if self.asid != 0 {
+            T::asids().free(self.asid);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum AllocatorError {
     NotEnoughMemoryForMetadata,
     OutOfMemory,
+    /// The address isn't managed by the allocator or isn't the beginning of an allocation.
+    InvalidAddress(usize),
+    /// The pages were already free.
+    DoubleFree(usize),
+    /// The range doesn't match the size of the allocation it belongs to.
+    PartialFree(usize),
+    /// The allocator was given memory that doesn't start or end on a page boundary.
+    UnalignedRegion(AddressRange),
 }
 
 pub trait PageAlloc: Sync {
@@ -85,6 +392,8 @@ pub trait PageEntry {
 
 pub trait PageMap {
     const PAGE_SIZE: usize;
+    /// Sizes a single entry can map, in increasing order starting with [`Self::PAGE_SIZE`].
+    const BLOCK_SIZES: &'static [usize];
     type Entry: PageEntry;
 
     fn new(allocator: &impl PageAlloc) -> Result<&'static mut Self, Error>;
@@ -94,18 +403,102 @@ pub trait PageMap {
         va: VAddr,
         pa: PAddr,
         perms: Permissions,
+        mem_type: MemoryType,
         allocator: &impl PageAlloc,
     ) -> Result<&mut Self::Entry, Error>;
 
+    /// Map `size` bytes at `va` to `pa` with a single entry, `size` must be one of
+    /// [`Self::BLOCK_SIZES`] and both addresses must be aligned on it.
+    /// A bigger block containing `va` is split, smaller mappings in the way are replaced.
+    fn map_block(
+        &mut self,
+        va: VAddr,
+        pa: PAddr,
+        size: usize,
+        perms: Permissions,
+        mem_type: MemoryType,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error>;
+
+    /// Remove the mapping of the page `va` and return the physical address it was mapped to.
+    /// A block containing `va` is split first, nothing is unmapped if that fails.
+    /// Intermediate tables left empty are given back to `allocator`, use [`Self::add_invalid_entry`]
+    /// instead to keep them around.
+    fn unmap(&mut self, va: VAddr, allocator: &impl PageAlloc) -> Option<PAddr>;
+
+    /// Returns the physical address `va` is mapped to, along with the permissions of the mapping.
+    fn translate(&self, va: VAddr) -> Option<(PAddr, Permissions)>;
+
+    /// Change the permissions of all the pages in `range`, they must already be mapped.
+    /// Blocks only partially covered by `range` are split, the memory type doesn't change.
+    fn protect(
+        &mut self,
+        range: AddressRange,
+        perms: Permissions,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error>;
+
+    /// Invalidate the cached translations of `va` in all address spaces.
+    /// [`Self::map`], [`Self::unmap`] and [`Self::protect`] already do it when they change a valid
+    /// entry.
+    fn flush_tlb_page(&self, va: VAddr);
+
+    /// Invalidate all the cached translations tagged with `asid`.
+    fn flush_tlb_asid(&self, asid: Asid);
+
+    /// Invalidate all the cached translations.
+    fn flush_tlb_all(&self);
+
+    /// Call `f` with every valid leaf entry, by increasing virtual address.
+    fn walk(&self, f: &mut impl FnMut(Mapping));
+
+    /// Same as [`Self::walk`] but the leaves that are contiguous in both address spaces and have
+    /// the same attributes are merged into a single mapping.
+    fn walk_coalesced(&self, f: &mut impl FnMut(Mapping)) {
+        let mut run: Option<Mapping> = None;
+
+        self.walk(&mut |mapping| match run.as_mut() {
+            Some(current) if current.is_continued_by(&mapping) => current.size += mapping.size,
+            _ => {
+                if let Some(done) = run.replace(mapping) {
+                    f(done);
+                }
+            }
+        });
+
+        if let Some(done) = run {
+            f(done);
+        }
+    }
+
+    /// Whether every page of `range` is mapped with exactly `perms`.
+    fn is_mapped(&self, range: AddressRange, perms: Permissions) -> bool {
+        range
+            .iter_pages(Self::PAGE_SIZE)
+            .all(|page| matches!(self.translate(VAddr::new(page)), Some((_, p)) if p == perms))
+    }
+
+    /// Something to print the mappings with, see [`Layout`].
+    fn layout(&self) -> Layout<'_, Self>
+    where
+        Self: Sized,
+    {
+        Layout(self)
+    }
+
     fn add_invalid_entry(&mut self, va: VAddr, allocator: &impl PageAlloc) -> Result<(), Error> {
         self.map(
             va,
             PAddr::new(0x0A0A_0A0A_0A0A_0A0A),
             Permissions::READ,
+            MemoryType::NormalCacheable,
             allocator,
         )?
         .set_invalid();
 
+        // The placeholder mapping was live until set_invalid, it may have been cached.
+        self.flush_tlb_page(va);
+
         Ok(())
     }
 
@@ -113,9 +506,10 @@ pub trait PageMap {
         &mut self,
         addr: VAddr,
         perms: Permissions,
+        mem_type: MemoryType,
         allocator: &impl PageAlloc,
     ) -> Result<(), Error> {
-        self.map(addr, PAddr::new(addr.val), perms, allocator)
+        self.map(addr, PAddr::new(addr.val), perms, mem_type, allocator)
             .map(|_| ())
     }
 
@@ -124,11 +518,17 @@ pub trait PageMap {
         addr: VAddr,
         page_count: usize,
         perms: Permissions,
+        mem_type: MemoryType,
         allocator: &impl PageAlloc,
     ) -> Result<(), Error> {
         let start = addr.val;
         for i in 0..page_count {
-            self.identity_map(VAddr::new(start + i * Self::PAGE_SIZE), perms, allocator)?;
+            self.identity_map(
+                VAddr::new(start + i * Self::PAGE_SIZE),
+                perms,
+                mem_type,
+                allocator,
+            )?;
         }
 
         Ok(())
@@ -146,18 +546,52 @@ pub trait PageMap {
         Ok(())
     }
 
-    fn identity_map_addressrange(
+    /// Map `range` to the physical memory starting at `pa` using the biggest blocks the alignment
+    /// of both allows.
+    fn map_addressrange(
         &mut self,
         range: AddressRange,
+        pa: PAddr,
         perms: Permissions,
+        mem_type: MemoryType,
         allocator: &impl PageAlloc,
     ) -> Result<(), Error> {
-        for page in range.iter_pages(Self::PAGE_SIZE) {
-            self.identity_map(VAddr::new(page), perms, allocator)?;
+        let mut addr = range.start & !(Self::PAGE_SIZE - 1);
+        let mut paddr = pa.val & !(Self::PAGE_SIZE - 1);
+
+        while addr < range.end {
+            let size = Self::BLOCK_SIZES
+                .iter()
+                .rev()
+                .copied()
+                .find(|&size| addr % size == 0 && paddr % size == 0 && addr + size <= range.end)
+                .unwrap_or(Self::PAGE_SIZE);
+
+            self.map_block(
+                VAddr::new(addr),
+                PAddr::new(paddr),
+                size,
+                perms,
+                mem_type,
+                allocator,
+            )?;
+            addr += size;
+            paddr += size;
         }
 
         Ok(())
     }
+
+    /// Identity map `range` using the biggest blocks its alignment allows.
+    fn identity_map_addressrange(
+        &mut self,
+        range: AddressRange,
+        perms: Permissions,
+        mem_type: MemoryType,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        self.map_addressrange(range, PAddr::new(range.start), perms, mem_type, allocator)
+    }
 }
 
 pub fn align_up(val: usize, page_sz: usize) -> usize {
@@ -165,42 +599,50 @@ pub fn align_up(val: usize, page_sz: usize) -> usize {
 }
 
 pub fn align_down(addr: usize, page_sz: usize) -> usize {
-    // TODO: can this be more optimized ?
-    // XXX: uh isn't this math wrong ?
-    align_up(addr, page_sz) + page_sz
+    (addr / page_sz) * page_sz
 }
 
+/// Build a pagetable mapping the virtual ranges given as read-only, read-write and
+/// read-execute normal memory, and the device registers as read-write device memory.
+/// `virt_to_phys` tells where each range lives in physical memory.
+/// Nothing is ever mapped both writable and executable.
 pub fn prefill_pagetable<P: PageMap + 'static>(
     r: impl Iterator<Item = AddressRange>,
     rw: impl Iterator<Item = AddressRange>,
-    rwx: impl Iterator<Item = AddressRange>,
-    pre_allocated: impl Iterator<Item = AddressRange>,
+    rx: impl Iterator<Item = AddressRange>,
+    device: impl Iterator<Item = AddressRange>,
+    virt_to_phys: impl Fn(usize) -> usize,
     allocator: &impl PageAlloc,
 ) -> Result<&'static mut P, Error> {
     trace!("hal_core::mm::prefill_pagetable");
     let pt: &'static mut P = P::new(allocator)?;
-
-    for range in pre_allocated {
-        pt.add_invalid_entries(range, allocator)?;
-    }
+    let normal = MemoryType::NormalCacheable;
 
     for range in r {
         trace!("mapping as RO: {:X?}", range);
-        pt.identity_map_addressrange(range, Permissions::READ, allocator)?;
+        let pa = PAddr::new(virt_to_phys(range.start));
+        pt.map_addressrange(range, pa, Permissions::READ, normal, allocator)?;
     }
 
     for range in rw {
         trace!("mapping as RW: {:X?}", range);
-        pt.identity_map_addressrange(range, Permissions::READ | Permissions::WRITE, allocator)?;
+        let pa = PAddr::new(virt_to_phys(range.start));
+        let perms = Permissions::READ | Permissions::WRITE;
+        pt.map_addressrange(range, pa, perms, normal, allocator)?;
     }
 
-    for range in rwx {
-        trace!("mapping as RWX: {:X?}", range);
-        pt.identity_map_addressrange(
-            range,
-            Permissions::READ | Permissions::WRITE | Permissions::EXECUTE,
-            allocator,
-        )?
+    for range in rx {
+        trace!("mapping as RX: {:X?}", range);
+        let pa = PAddr::new(virt_to_phys(range.start));
+        let perms = Permissions::READ | Permissions::EXECUTE;
+        pt.map_addressrange(range, pa, perms, normal, allocator)?;
+    }
+
+    for range in device {
+        trace!("mapping as device: {:X?}", range);
+        let pa = PAddr::new(virt_to_phys(range.start));
+        let perms = Permissions::READ | Permissions::WRITE;
+        pt.map_addressrange(range, pa, perms, MemoryType::DeviceNGnRE, allocator)?;
     }
 
     Ok(pt)
@@ -1,5 +1,20 @@
 use super::registers;
 
+use core::arch::asm;
+
+/// How many harts the kernel supports, their ids go from 0 to `MAX_HARTS - 1`.
+pub const MAX_HARTS: usize = 64;
+
+/// The id of the calling hart, `_start` keeps it in `tp` for the whole life of the kernel.
+pub fn hart_id() -> usize {
+    let hart: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) hart, options(nomem, nostack, preserves_flags));
+    }
+
+    hart
+}
+
 pub fn unmask_interrupts() {
     registers::set_sstatus_sie();
     registers::set_sie_ssie();
@@ -7,6 +22,33 @@ pub fn unmask_interrupts() {
     registers::set_sie_stie();
 }
 
+pub fn mask_interrupts() {
+    registers::clear_sstatus_sie();
+}
+
+pub fn interrupts_enabled() -> bool {
+    registers::read_sstatus_sie()
+}
+
+/// Whether sstatus.SIE was set when [`save_and_mask_interrupts`] cleared it.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptState(bool);
+
+/// Mask the interrupts of the hart, returning how they were for [`restore_interrupts`].
+pub fn save_and_mask_interrupts() -> InterruptState {
+    let sstatus: usize;
+    unsafe { asm!("csrrci {}, sstatus, 0b10", out(reg) sstatus) };
+
+    InterruptState(sstatus & 0b10 != 0)
+}
+
+/// Put sstatus.SIE back as it was, the sources enabled in sie are left alone.
+pub fn restore_interrupts(state: InterruptState) {
+    if state.0 {
+        registers::set_sstatus_sie();
+    }
+}
+
 pub fn clear_physical_timer() {
     sbi::timer::set_timer(u64::MAX).unwrap();
 }
@@ -1,49 +1,213 @@
 use hal_core::{
-    mm::{PageAlloc, PageMap, Permissions, VAddr},
-    Error, TimerCallbackFn,
+    irq::{IrqCallbackFn, IrqChip},
+    mm::{
+        FaultAccess, MemoryType, PAddr, PageAlloc, PageFault, PageFaultCallbackFn, PageMap,
+        Permissions, VAddr,
+    },
+    AddressRange, Error, TimerCallbackFn,
 };
 
+use super::cpu;
 use super::mm;
-use super::plic::Plic;
+use super::plic::{Plic, PLIC_MAX_HARTS};
 use super::registers;
 
+use fdt::{node::FdtNode, standard_nodes::MemoryRegion, Fdt};
+
 use core::arch::asm;
 use core::ptr;
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
 
 use riscv;
 use sbi;
 
+/// The stacks the traps from user mode are handled on, one per hart.
+#[repr(C, align(16))]
+struct TrapStacks([[u8; TRAP_STACK_SIZE]; cpu::MAX_HARTS]);
+
+const TRAP_STACK_SIZE: usize = 0x4000;
+
+static mut TRAP_STACKS: TrapStacks = TrapStacks([[0; TRAP_STACK_SIZE]; cpu::MAX_HARTS]);
+
+/// Where each hart pushes the frames of the traps from user mode, right below its id kept at the
+/// top of its trap stack. [`trap_handler`] puts it in sscratch when it returns to user mode.
+#[no_mangle]
+static mut TRAP_STACK_POINTERS: [usize; cpu::MAX_HARTS] = [0; cpu::MAX_HARTS];
+
+/// Set up the traps of the calling hart, each of them has to before it takes one.
 pub fn init_exception_handlers() {
+    let hart = cpu::hart_id();
+    assert!(hart < cpu::MAX_HARTS, "no trap stack for hart {}", hart);
+
+    unsafe {
+        let top = ptr::addr_of_mut!(TRAP_STACKS.0[hart]) as usize + TRAP_STACK_SIZE;
+        // Keeps the stack pointer 16-byte aligned.
+        let stack_pointer = top - 16;
+        (stack_pointer as *mut usize).write(hart);
+        TRAP_STACK_POINTERS[hart] = stack_pointer;
+
+        // Running in supervisor mode.
+        asm!("csrw sscratch, zero");
+    }
     registers::set_stvec(trap_handler as usize);
 }
 
 static mut IRQ_CHIP: Option<Plic> = None;
 
-pub fn init_irq_chip(_dt_node: (), allocator: &impl PageAlloc) -> Result<(), Error> {
-    // TODO map the dt_node
-    let base = 0xc000000;
-    let max_offset = 0x3FFFFFC;
+/// The compatible strings of the interrupt controllers [`Plic`] drives.
+const PLIC_COMPATIBLES: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
 
-    mm::current().identity_map_range(
-        VAddr::new(base),
-        max_offset / mm::PAGE_SIZE + 1,
+fn is_compatible(dt_node: &FdtNode, compatibles: &[&str]) -> bool {
+    dt_node
+        .property("compatible")
+        .and_then(|prop| prop.as_str())
+        .unwrap_or("")
+        .split('\0')
+        .any(|compatible| compatibles.contains(&compatible))
+}
+
+fn property_usize(dt_node: &FdtNode, name: &'static str) -> Result<usize, Error> {
+    dt_node
+        .property(name)
+        .and_then(|prop| prop.as_usize())
+        .ok_or(Error::InvalidDeviceTreeNode(name))
+}
+
+/// Map the registers of a device in the direct map, returns their virtual address.
+fn map_device_region(
+    region: Option<MemoryRegion>,
+    allocator: &impl PageAlloc,
+) -> Result<usize, Error> {
+    let region = region.ok_or(Error::InvalidDeviceTreeNode("reg"))?;
+    let base = region.starting_address as usize;
+    let size = region.size.ok_or(Error::InvalidDeviceTreeNode("reg"))?;
+
+    mm::current().map_addressrange(
+        AddressRange::with_size(mm::phys_to_virt(base), size).round_up_to_page(mm::PAGE_SIZE),
+        PAddr::new(base),
         Permissions::READ | Permissions::WRITE,
+        MemoryType::DeviceNGnRE,
         allocator,
     )?;
+
+    Ok(mm::phys_to_virt(base))
+}
+
+/// The supervisor context of each hart on the PLIC at `dt_node`, indexed by hart id.
+///
+/// The `interrupts-extended` property lists the contexts in order, each as the phandle of the
+/// local interrupt controller of a hart and the cause it raises there: the supervisor external
+/// interrupt for the supervisor contexts.
+fn plic_contexts(
+    device_tree: &Fdt,
+    dt_node: &FdtNode,
+) -> Result<[Option<usize>; PLIC_MAX_HARTS], Error> {
+    const SUPERVISOR_EXTERNAL_INTERRUPT: u32 = 9;
+
+    let contexts = dt_node
+        .property("interrupts-extended")
+        .ok_or(Error::InvalidDeviceTreeNode("interrupts-extended"))?
+        .value;
+    let cell = |cells: &[u8]| u32::from_be_bytes(cells.try_into().unwrap());
+
+    let mut supervisor_contexts = [None; PLIC_MAX_HARTS];
+    let cpus = device_tree
+        .find_node("/cpus")
+        .ok_or(Error::InvalidDeviceTreeNode("cpus"))?;
+    for cpu in cpus.children() {
+        let hart = match cpu.reg().and_then(|mut reg| reg.next()) {
+            Some(reg) => reg.starting_address as usize,
+            None => continue,
+        };
+        let intc = match cpu
+            .children()
+            .find(|child| child.property("interrupt-controller").is_some())
+        {
+            Some(intc) => intc,
+            None => continue,
+        };
+        // Nothing can be routed to an interrupt controller without a phandle.
+        let phandle = match intc.property("phandle").and_then(|prop| prop.as_usize()) {
+            Some(phandle) => phandle as u32,
+            None => continue,
+        };
+        if property_usize(&intc, "#interrupt-cells")? != 1 {
+            return Err(Error::InvalidDeviceTreeNode("#interrupt-cells"));
+        }
+
+        if let Some(context) = supervisor_contexts.get_mut(hart) {
+            *context = contexts.chunks_exact(8).position(|pair| {
+                cell(&pair[..4]) == phandle && cell(&pair[4..]) == SUPERVISOR_EXTERNAL_INTERRUPT
+            });
+        }
+    }
+
+    Ok(supervisor_contexts)
+}
+
+/// Set up the interrupt controller described by `dt_node`, the driver is picked from its
+/// compatible strings. `device_tree` holds the nodes `dt_node` refers to.
+pub fn init_irq_chip(
+    dt_node: &FdtNode,
+    device_tree: &Fdt,
+    allocator: &impl PageAlloc,
+) -> Result<(), Error> {
+    if !is_compatible(dt_node, PLIC_COMPATIBLES) {
+        return Err(Error::NoMatchingIrqChip);
+    }
+
+    let ndev = property_usize(dt_node, "riscv,ndev")?;
+    if property_usize(dt_node, "#interrupt-cells")? != 1 {
+        return Err(Error::InvalidDeviceTreeNode("#interrupt-cells"));
+    }
+    let contexts = plic_contexts(device_tree, dt_node)?;
+
+    let base = map_device_region(dt_node.reg().and_then(|mut reg| reg.next()), allocator)?;
     unsafe {
-        IRQ_CHIP = Some(Plic::new(base));
+        IRQ_CHIP = Some(Plic::new(base, ndev as u32, contexts)?);
     }
 
     Ok(())
 }
 
+/// The interrupt controller set up by [`init_irq_chip`].
+pub fn irq_chip() -> Result<&'static dyn IrqChip, Error> {
+    match unsafe { IRQ_CHIP.as_ref() } {
+        Some(chip) => Ok(chip),
+        None => Err(Error::NoIrqChip),
+    }
+}
+
+static IRQ_CALLBACK: AtomicPtr<IrqCallbackFn> = AtomicPtr::new(ptr::null_mut());
+
+/// `h` handles the interrupts of every line of the interrupt controller.
+pub fn set_irq_handler(h: IrqCallbackFn) {
+    IRQ_CALLBACK.store(h as *mut _, Ordering::Relaxed);
+}
+
 static TIMER_CALLBACK: AtomicPtr<TimerCallbackFn> = AtomicPtr::new(ptr::null_mut());
 
 pub fn set_timer_handler(h: TimerCallbackFn) {
     TIMER_CALLBACK.store(h as *mut _, Ordering::Relaxed);
 }
 
+static PAGE_FAULT_CALLBACK: AtomicPtr<PageFaultCallbackFn> = AtomicPtr::new(ptr::null_mut());
+
+/// Handles a breakpoint, it has to move `frame.pc` past the `ebreak`.
+pub type BreakpointCallbackFn = fn(&mut TrapFrame);
+
+static BREAKPOINT_CALLBACK: AtomicPtr<BreakpointCallbackFn> = AtomicPtr::new(ptr::null_mut());
+
+/// `h` is called for the breakpoints, they are fatal without a handler.
+pub fn set_breakpoint_handler(h: BreakpointCallbackFn) {
+    BREAKPOINT_CALLBACK.store(h as *mut _, Ordering::Relaxed);
+}
+
+/// `h` gets a chance to resolve the page faults before they are considered fatal.
+pub fn set_page_fault_handler(h: PageFaultCallbackFn) {
+    PAGE_FAULT_CALLBACK.store(h as *mut _, Ordering::Relaxed);
+}
+
 pub fn set_timer(ticks: usize) -> Result<(), Error> {
     let target_time = riscv::register::time::read() + ticks;
     sbi::timer::set_timer(target_time as u64).unwrap();
@@ -51,6 +215,33 @@ pub fn set_timer(ticks: usize) -> Result<(), Error> {
     Ok(())
 }
 
+/// Set while [`write_faults`] waits for its store to fault.
+static PROBING_WRITE: AtomicBool = AtomicBool::new(false);
+static PROBED_WRITE_FAULTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether writing to `addr` faults. The value already at `addr` is written back, nothing
+/// changes if the write goes through.
+pub fn write_faults(addr: usize) -> bool {
+    PROBED_WRITE_FAULTED.store(false, Ordering::Relaxed);
+    PROBING_WRITE.store(true, Ordering::SeqCst);
+
+    unsafe {
+        asm!(
+            // The trap handler skips the faulting instruction assuming it is 4 bytes long.
+            ".option push",
+            ".option norvc",
+            "ld {tmp}, 0({addr})",
+            "sd {tmp}, 0({addr})",
+            ".option pop",
+            addr = in(reg) addr,
+            tmp = out(reg) _,
+        );
+    }
+
+    PROBING_WRITE.store(false, Ordering::SeqCst);
+    PROBED_WRITE_FAULTED.load(Ordering::Relaxed)
+}
+
 #[derive(Debug, Copy, Clone)]
 enum InterruptType {
     Reserved,
@@ -60,12 +251,6 @@ enum InterruptType {
     Platform(u64),
 }
 
-impl InterruptType {
-    fn is_asynchronous(&self) -> bool {
-        matches!(self, Self::SupervisorTimer)
-    }
-}
-
 impl From<u64> for InterruptType {
     fn from(code: u64) -> Self {
         match code {
@@ -172,29 +357,106 @@ static mut INTERRUPT_VECTOR: &[extern "C" fn()] = &[
     supervisor_external_interrupt_handler,
 ];
 
-/// Dispatch interrupts and exceptions
-/// Returns 0 if it was synchronous, 1 otherwise
+/// The state of the trapped context, saved by [`trap_handler`] on the kernel stack and restored
+/// from there when the trap returns, changes made by the handlers take effect then.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct TrapFrame {
+    /// x0 to x31, x0 is always 0 and x2 is the stack pointer the trap happened with, it is
+    /// restored as well.
+    pub regs: [u64; 32],
+    /// sepc, where execution resumes.
+    pub pc: u64,
+    /// sstatus
+    pub status: u64,
+    /// scause
+    pub cause: u64,
+    /// stval
+    pub fault_address: u64,
+}
+
+// The offsets used by trap_handler.
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == 0x120);
+
+impl TrapFrame {
+    /// Whether the trap was taken from user mode, looking at sstatus.SPP.
+    pub fn from_user(&self) -> bool {
+        self.status & (1 << 8) == 0
+    }
+}
+
+/// Dispatch interrupts and exceptions, execution resumes at `frame.pc`.
 #[no_mangle]
-extern "C" fn trap_dispatch(cause: u64) -> u64 {
-    match TrapType::from(cause) {
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    match TrapType::from(frame.cause) {
         TrapType::Interrupt(itype) => {
             let exception_code: u64 = itype.into();
             unsafe { INTERRUPT_VECTOR[exception_code as usize]() };
-
-            if itype.is_asynchronous() {
-                1
-            } else {
-                0
-            }
+        }
+        TrapType::Exception(ExceptionType::StoreAMOPageFault)
+            if PROBING_WRITE.swap(false, Ordering::SeqCst) =>
+        {
+            PROBED_WRITE_FAULTED.store(true, Ordering::Relaxed);
+            // Skip the faulting store.
+            frame.pc += 4;
+        }
+        TrapType::Exception(
+            etype @ (ExceptionType::InstructionPageFault
+            | ExceptionType::LoadPageFault
+            | ExceptionType::StoreAMOPageFault),
+        ) => {
+            // Once the mapping is there, the faulting instruction runs again.
+            page_fault(etype, frame);
+        }
+        TrapType::Exception(ExceptionType::Breakpoint)
+            if !BREAKPOINT_CALLBACK.load(Ordering::Relaxed).is_null() =>
+        {
+            let breakpoint_cb = BREAKPOINT_CALLBACK.load(Ordering::Relaxed);
+            unsafe { core::mem::transmute::<_, BreakpointCallbackFn>(breakpoint_cb)(frame) };
         }
         TrapType::Exception(etype) => {
-            panic!("Exception '{:?}' not implemented yet", etype)
+            panic!("Exception '{:?}' not implemented yet: {:X?}", etype, frame)
         }
     }
 }
 
+/// Hand the fault over to the page fault handler, there is nothing else to fall back on if it
+/// can't resolve it.
+fn page_fault(etype: ExceptionType, frame: &TrapFrame) {
+    let access = match etype {
+        ExceptionType::InstructionPageFault => FaultAccess::Execute,
+        ExceptionType::LoadPageFault => FaultAccess::Read,
+        ExceptionType::StoreAMOPageFault => FaultAccess::Write,
+        _ => unreachable!("{:?} isn't a page fault", etype),
+    };
+    let fault = PageFault {
+        addr: VAddr::new(frame.fault_address as usize),
+        access,
+        user: frame.from_user(),
+    };
+
+    let page_fault_cb = PAGE_FAULT_CALLBACK.load(Ordering::Relaxed);
+    let resolved = !page_fault_cb.is_null()
+        && unsafe { core::mem::transmute::<_, PageFaultCallbackFn>(page_fault_cb)(&fault) };
+
+    if !resolved {
+        // There is no user context to kill yet, everything runs on behalf of the kernel.
+        panic!("unhandled page fault: {:X?}", fault);
+    }
+}
+
 extern "C" fn supervisor_external_interrupt_handler() {
-    todo!("fwd the external int to the irq_chip or smthing...");
+    let chip = irq_chip().expect("got an external interrupt without an interrupt controller");
+
+    while let Some(source) = chip.claim() {
+        let irq_cb = IRQ_CALLBACK.load(Ordering::Relaxed);
+        if irq_cb.is_null() {
+            panic!("got an irq on line {} but nobody handles it", source);
+        }
+        unsafe { core::mem::transmute::<_, IrqCallbackFn>(irq_cb)(source) };
+
+        chip.complete(source);
+    }
 }
 
 extern "C" fn undefined_handler() {
@@ -210,101 +472,135 @@ extern "C" fn timer_handler() {
     }
 }
 
+/// Saves a [`TrapFrame`] on the kernel stack and hands it to [`trap_dispatch`], the trapped context
+/// is restored from it afterwards.
+///
+/// sscratch holds the trap stack of the hart while it runs in user mode and 0 in supervisor mode:
+/// the traps from user mode switch to that stack, the others stay on the one in use. The hart id
+/// the kernel keeps in tp is taken back from the top of the trap stack.
 #[naked]
 #[no_mangle]
 #[repr(align(4))]
 unsafe extern "C" fn trap_handler() {
     asm!(
         "
-        addi sp, sp, -0x100
-
-        sd x31, 0x100(sp)
-        sd x30, 0xf8(sp)
-        sd x29, 0xf0(sp)
-        sd x28, 0xd8(sp)
-        sd x27, 0xd0(sp)
-        sd x26, 0xc8(sp)
-        sd x25, 0xc0(sp)
-        sd x24, 0xb8(sp)
-        sd x23, 0xb0(sp)
-        sd x22, 0xa8(sp)
-        sd x21, 0xa0(sp)
-        sd x20, 0x98(sp)
-        sd x19, 0x90(sp)
-        sd x18, 0x88(sp)
-        sd x17, 0x80(sp)
-        sd x16, 0x78(sp)
-        sd x15, 0x70(sp)
-        sd x14, 0x68(sp)
-        sd x13, 0x60(sp)
-        sd x12, 0x58(sp)
-        sd x11, 0x50(sp)
-        sd x10, 0x48(sp)
-        sd x9, 0x40(sp)
-        sd x8, 0x38(sp)
-        sd x7, 0x30(sp)
-        sd x6, 0x28(sp)
-        sd x5, 0x20(sp)
-        sd x4, 0x18(sp)
-        sd x3, 0x10(sp)
-        sd x2, 0x8(sp)
-        sd x1, 0x0(sp)
-
-        // mv a0, sp // Pointer on stack for the register struct
-        // csrr a1, sepc
-        // csrr a2, stval
-        // csrr a3, scause
-        // csrr a5, sstatus
-
-        csrr a0, scause
-        jal trap_dispatch
-
-        bne a0, x0, 1f
+        csrrw sp, sscratch, sp
+        bnez sp, 1f
+        // From supervisor mode, back to the stack it was using.
+        csrrw sp, sscratch, sp
+    1:
+        addi sp, sp, -0x120
+
+        sd x1, 0x8(sp)
+        sd x3, 0x18(sp)
+        sd x4, 0x20(sp)
+        sd x5, 0x28(sp)
+        sd x6, 0x30(sp)
+        sd x7, 0x38(sp)
+        sd x8, 0x40(sp)
+        sd x9, 0x48(sp)
+        sd x10, 0x50(sp)
+        sd x11, 0x58(sp)
+        sd x12, 0x60(sp)
+        sd x13, 0x68(sp)
+        sd x14, 0x70(sp)
+        sd x15, 0x78(sp)
+        sd x16, 0x80(sp)
+        sd x17, 0x88(sp)
+        sd x18, 0x90(sp)
+        sd x19, 0x98(sp)
+        sd x20, 0xa0(sp)
+        sd x21, 0xa8(sp)
+        sd x22, 0xb0(sp)
+        sd x23, 0xb8(sp)
+        sd x24, 0xc0(sp)
+        sd x25, 0xc8(sp)
+        sd x26, 0xd0(sp)
+        sd x27, 0xd8(sp)
+        sd x28, 0xe0(sp)
+        sd x29, 0xe8(sp)
+        sd x30, 0xf0(sp)
+        sd x31, 0xf8(sp)
+
+        // x2, the stack pointer the trap happened with: the one of user mode is in sscratch, the
+        // frame is right below the one of supervisor mode. sscratch is 0 while the trap is
+        // handled, the traps taken by the handlers stay on this stack.
+        csrrw t0, sscratch, zero
+        beqz t0, 2f
+        sd t0, 0x10(sp)
+        // tp was saved with the rest, the hart id is right above the frame.
+        ld tp, 0x120(sp)
+        j 3f
+    2:
+        addi t0, sp, 0x120
+        sd t0, 0x10(sp)
+    3:
+        sd x0, 0x0(sp)
 
         csrr t0, sepc
-        addi t0, t0, 4
+        sd t0, 0x100(sp)
+        csrr t0, sstatus
+        sd t0, 0x108(sp)
+        csrr t0, scause
+        sd t0, 0x110(sp)
+        csrr t0, stval
+        sd t0, 0x118(sp)
+
+        mv a0, sp
+        call trap_dispatch
+
+        ld t0, 0x100(sp)
         csrw sepc, t0
-
-1:
-        ld x1, 0x0(sp)
-        ld x2, 0x8(sp)
-        ld x3, 0x10(sp)
-        ld x4, 0x18(sp)
-        ld x5, 0x20(sp)
-        ld x6, 0x28(sp)
-        ld x7, 0x30(sp)
-        ld x8, 0x38(sp)
-        ld x9, 0x40(sp)
-        ld x10, 0x48(sp)
-        ld x11, 0x50(sp)
-        ld x12, 0x58(sp)
-        ld x13, 0x60(sp)
-        ld x14, 0x68(sp)
-        ld x15, 0x70(sp)
-        ld x16, 0x78(sp)
-        ld x17, 0x80(sp)
-        ld x18, 0x88(sp)
-        ld x19, 0x90(sp)
-        ld x20, 0x98(sp)
-        ld x21, 0xa0(sp)
-        ld x22, 0xa8(sp)
-        ld x23, 0xb0(sp)
-        ld x24, 0xb8(sp)
-        ld x25, 0xc0(sp)
-        ld x26, 0xc8(sp)
-        ld x27, 0xd0(sp)
-        ld x28, 0xd8(sp)
-        ld x29, 0xf0(sp)
-        ld x30, 0xf8(sp)
-        ld x31, 0x100(sp)
-
-        addi sp, sp, 0x100
+        ld t0, 0x108(sp)
+        csrw sstatus, t0
+
+        // Back to user mode, its next trap starts over on the trap stack of this hart, tp isn't
+        // restored yet. SPP is bit 8.
+        andi t0, t0, 0x100
+        bnez t0, 4f
+        la t0, TRAP_STACK_POINTERS
+        slli t1, tp, 3
+        add t0, t0, t1
+        ld t0, 0(t0)
+        csrw sscratch, t0
+    4:
+
+        ld x1, 0x8(sp)
+        ld x3, 0x18(sp)
+        ld x4, 0x20(sp)
+        ld x5, 0x28(sp)
+        ld x6, 0x30(sp)
+        ld x7, 0x38(sp)
+        ld x8, 0x40(sp)
+        ld x9, 0x48(sp)
+        ld x10, 0x50(sp)
+        ld x11, 0x58(sp)
+        ld x12, 0x60(sp)
+        ld x13, 0x68(sp)
+        ld x14, 0x70(sp)
+        ld x15, 0x78(sp)
+        ld x16, 0x80(sp)
+        ld x17, 0x88(sp)
+        ld x18, 0x90(sp)
+        ld x19, 0x98(sp)
+        ld x20, 0xa0(sp)
+        ld x21, 0xa8(sp)
+        ld x22, 0xb0(sp)
+        ld x23, 0xb8(sp)
+        ld x24, 0xc0(sp)
+        ld x25, 0xc8(sp)
+        ld x26, 0xd0(sp)
+        ld x27, 0xd8(sp)
+        ld x28, 0xe0(sp)
+        ld x29, 0xe8(sp)
+        ld x30, 0xf0(sp)
+        ld x31, 0xf8(sp)
+        // Last, the frame is on the stack it points to.
+        ld x2, 0x10(sp)
 
         sret",
         options(noreturn)
     );
-    // Obviously this isn't done, we need to jump back to the previous context before the
-    // interrupt using mpp/spp and mepc/sepc.
 }
 
 #[cfg(test)]
@@ -12,8 +12,118 @@ use core::arch::asm;
 
 pub fn panic_info() {}
 
+/// Root table used from `_start` until the kernel builds its own pagetable.
+#[repr(C, align(4096))]
+struct BootPageTable([u64; 512]);
+
+static mut BOOT_PAGETABLE: BootPageTable = BootPageTable([0; 512]);
+
+/// Called from `_start` with paging off, see [`hal_core::kaslr::relocate`].
+unsafe extern "C" fn relocate_kernel(
+    device_tree: usize,
+    phys_start: usize,
+    link_start: usize,
+    rela_start: usize,
+    rela_end: usize,
+) -> usize {
+    const R_RISCV_RELATIVE: u32 = 3;
+
+    hal_core::kaslr::relocate(
+        device_tree,
+        phys_start,
+        link_start,
+        (rela_start, rela_end),
+        R_RISCV_RELATIVE,
+    )
+}
+
+/// Entered with paging off at the load address of the kernel. The kernel is relocated, then it
+/// and the direct map are mapped with 1GiB pages before jumping to the upper half, the kernel
+/// mustn't cross a gigabyte boundary and its link and load addresses must have the same offset in
+/// their gigabyte.
 #[naked]
 #[no_mangle]
 unsafe extern "C" fn _start() -> ! {
-    asm!("la sp, STACK_START", "call k_main", options(noreturn));
+    asm!(
+        "
+        // a0 and a1 (hart id and device tree) are left for k_main.
+        mv s0, a0
+        mv s1, a1
+        // The hart id stays in tp, see cpu::hart_id.
+        mv tp, a0
+
+        // Until the jump to the upper half, the boot stack is used at its physical address.
+        lla sp, STACK_START
+        mv a0, a1
+        lla a1, KERNEL_START
+        lla a2, 3f
+        ld a2, 0(a2)
+        lla a3, RELA_START
+        lla a4, RELA_END
+        call {relocate_kernel}
+        mv a0, s0
+        mv a1, s1
+
+        // From now on the addresses stored in the image, 3f included, are the slid ones.
+        lla t0, {boot_pagetable}
+
+        // Direct map: entries 256 to 383 map the first 128GiB, RW, accessed and dirty.
+        li t1, 0xc7
+        li t2, 256
+        li t3, 384
+        li t4, 1 << 28
+    1:
+        slli t5, t2, 3
+        add t5, t5, t0
+        sd t1, 0(t5)
+        add t1, t1, t4
+        addi t2, t2, 1
+        blt t2, t3, 1b
+
+        lla t1, KERNEL_START
+        lla t2, {kernel_phys_start}
+        sd t1, 0(t2)
+
+        // The gigabyte holding the kernel, RWX, where it runs now and at its link address plus the
+        // slide.
+        srli t1, t1, 30
+        slli t2, t1, 28
+        ori t2, t2, 0xcf
+        slli t3, t1, 3
+        add t3, t3, t0
+        sd t2, 0(t3)
+
+        lla t3, 3f
+        ld t3, 0(t3)
+        srli t3, t3, 30
+        andi t3, t3, 511
+        slli t3, t3, 3
+        add t3, t3, t0
+        sd t2, 0(t3)
+
+        // Sv39, ASID 0.
+        srli t0, t0, 12
+        li t1, 8 << 60
+        or t0, t0, t1
+        csrw satp, t0
+        sfence.vma
+
+        lla t0, 3f
+        ld t0, 8(t0)
+        jr t0
+
+        .balign 8
+    3:
+        .dword KERNEL_START
+        .dword 2f
+
+    2:
+        la sp, STACK_START
+        call k_main
+        ",
+        relocate_kernel = sym relocate_kernel,
+        boot_pagetable = sym BOOT_PAGETABLE,
+        kernel_phys_start = sym mm::KERNEL_PHYS_START,
+        options(noreturn)
+    );
 }
@@ -0,0 +1,40 @@
+use hal_core::{
+    mm::{self, AddressSpaceTable, Asid, AsidAllocator, PageAlloc},
+    Error,
+};
+
+use super::pagetable::PageTable;
+use super::{current, set_satp, virt_to_phys};
+
+pub(super) static ASIDS: AsidAllocator = AsidAllocator::new();
+
+/// The root table of an address space starts as a copy of the kernel's.
+///
+/// The root entries of the kernel's part of the upper half are set once and for all at boot, the
+/// tables below them are shared and the kernel mappings added later are seen by all the address
+/// spaces. The other tables of the kernel are shared until a mapping below them changes, the
+/// address space then gets a private copy of them.
+pub type AddressSpace = mm::AddressSpace<PageTable>;
+
+impl AddressSpaceTable for PageTable {
+    fn asids() -> &'static AsidAllocator {
+        &ASIDS
+    }
+
+    fn new_root(allocator: &impl PageAlloc) -> Result<&'static mut Self, Error> {
+        PageTable::copy_of(current(), allocator)
+    }
+
+    fn make_private(&mut self, va: mm::VAddr, allocator: &impl PageAlloc) -> Result<(), Error> {
+        self.unshare(current(), va, allocator)
+    }
+
+    fn activate(&self, asid: Asid) {
+        set_satp(self, asid);
+    }
+
+    fn free_root(root: &'static mut Self, allocator: &impl PageAlloc) {
+        root.free_tables(Some(current()), PageTable::root_level(), allocator);
+        let _ = allocator.dealloc(virt_to_phys(root as *mut PageTable as usize), 1);
+    }
+}
@@ -1,17 +1,160 @@
 use core::arch::asm;
 use core::cell::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use hal_core::{
-    mm::{self, PageAlloc, PageMap},
+    mm::{self, Asid, PageAlloc, PageMap},
     AddressRange, Error,
 };
 
-mod sv39;
-use sv39::{PageTable, Satp, SatpMode};
+mod address_space;
+mod pagetable;
+pub mod tlb;
+pub use address_space::AddressSpace;
+pub use pagetable::PagingMode;
+use pagetable::{PageTable, Satp};
 
 pub const PAGE_SIZE: usize = PageTable::PAGE_SIZE;
 
+// The kernel lives in the top 256GiB of the address space, the upper half of Sv39 and the end of
+// the upper half of Sv48 and Sv57, the lower half belongs to the address spaces of the processes:
+//   - 0xffff_ffc0_0000_0000..0xffff_ffe0_0000_0000: direct map of the first 128GiB of physical
+//     memory,
+//   - 0xffff_ffe0_0000_0000..0xffff_fff0_0000_0000: free for the kernel's own mappings,
+//   - 0xffff_fff0_0000_0000..: the kernel image, linked at 0xffff_fff0_8020_0000 and moved up by
+//     a multiple of 1GiB with KASLR.
+
+/// Virtual address of physical address 0 in the direct map.
+pub const PHYS_OFFSET: usize = 0xffff_ffc0_0000_0000;
+/// Amount of physical memory covered by the direct map.
+pub const DIRECT_MAP_SIZE: usize = 128 * 1024 * 1024 * 1024;
+/// Start of the part of the upper half left for the kernel's own mappings.
+pub const KERNEL_VIRT_START: usize = 0xffff_ffe0_0000_0000;
+/// End of the part of the upper half left for the kernel's own mappings.
+pub const KERNEL_VIRT_END: usize = 0xffff_fff0_0000_0000;
+
+/// Physical address the kernel image was loaded at, `_start` saves it before enabling paging.
+#[no_mangle]
+pub(crate) static mut KERNEL_PHYS_START: usize = 0;
+
+/// Returns the address of `pa` in the direct map.
+pub const fn phys_to_virt(pa: usize) -> usize {
+    pa + PHYS_OFFSET
+}
+
+/// Returns the physical address of `va`, which must be in the direct map or in the kernel image.
+pub fn virt_to_phys(va: usize) -> usize {
+    extern "C" {
+        static KERNEL_START: usize;
+    }
+    let kernel_start = unsafe { &KERNEL_START as *const usize as usize };
+
+    if va >= kernel_start {
+        va - kernel_start + unsafe { KERNEL_PHYS_START }
+    } else {
+        assert!(
+            (PHYS_OFFSET..PHYS_OFFSET + DIRECT_MAP_SIZE).contains(&va),
+            "{:#x} is neither in the direct map nor in the kernel image",
+            va
+        );
+        va - PHYS_OFFSET
+    }
+}
+
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+
+/// Let the pagetables pick the memory type of their mappings, to be called before anything gets
+/// mapped and only if the harts implement the Svpbmt extension.
+/// Without it, the memory type comes from the physical memory attributes of the platform.
+pub fn enable_svpbmt() {
+    SVPBMT.store(true, Ordering::Relaxed);
+}
+
+fn svpbmt_enabled() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
+}
+
+static PAGING_MODE: AtomicU8 = AtomicU8::new(PagingMode::Sv39 as u8);
+
+/// Use `mode` for all the pagetables, to be called before anything gets mapped.
+/// The boot pagetable is Sv39 whatever the mode.
+pub fn set_paging_mode(mode: PagingMode) {
+    PAGING_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn paging_mode() -> PagingMode {
+    match PAGING_MODE.load(Ordering::Relaxed) {
+        0 => PagingMode::Sv39,
+        1 => PagingMode::Sv48,
+        _ => PagingMode::Sv57,
+    }
+}
+
+fn read_satp() -> u64 {
+    let satp: u64;
+    unsafe {
+        asm!("csrr {}, satp", out(reg) satp);
+    }
+
+    satp
+}
+
+fn write_satp(satp: u64) {
+    unsafe {
+        asm!("sfence.vma", "csrw satp, {}", "sfence.vma", in(reg) satp);
+    }
+}
+
+#[repr(align(0x1000))]
+struct ProbeTable([u64; 512]);
+
+/// Tables put on top of the boot pagetable to try the Sv48 and Sv57 modes.
+static mut PROBE_TABLES: [ProbeTable; 2] = [ProbeTable([0; 512]), ProbeTable([0; 512])];
+
+/// Returns the biggest paging mode the hart implements, while running on the boot pagetable.
+///
+/// satp ignores a write with a mode it doesn't implement, so each mode is tried for real: the first
+/// and last entries of each table added on top point to the level below, which keeps the lowest
+/// and highest 256GiB translated as they are now.
+pub fn probe_paging_mode() -> PagingMode {
+    const SATP_PPN_MASK: u64 = (1 << 44) - 1;
+
+    let boot_satp = read_satp();
+    let mut root_ppn = boot_satp & SATP_PPN_MASK;
+    let mut supported = PagingMode::Sv39;
+
+    // Safety: only used here, with interrupts off during the boot.
+    let tables = unsafe { &mut PROBE_TABLES };
+    for (table, mode) in tables.iter_mut().zip([PagingMode::Sv48, PagingMode::Sv57]) {
+        // Valid, not a leaf.
+        let pte = (root_ppn << 10) | 1;
+        table.0[0] = pte;
+        table.0[511] = pte;
+
+        root_ppn = (virt_to_phys(table as *const ProbeTable as usize) >> 12) as u64;
+        let satp = u64::from(Satp::with_values(root_ppn, 0, mode.satp_mode()));
+        write_satp(satp);
+
+        if read_satp() != satp {
+            break;
+        }
+        supported = mode;
+    }
+
+    write_satp(boot_satp);
+
+    supported
+}
+
 static mut GPT: OnceCell<&'static mut PageTable> = OnceCell::new();
 
+/// Set once the root entries of the kernel's pagetable covering the kernel's part of the upper
+/// half are final, the address spaces copy them when they are created.
+static KERNEL_ROOT_PINNED: AtomicBool = AtomicBool::new(false);
+
+fn kernel_root_pinned() -> bool {
+    KERNEL_ROOT_PINNED.load(Ordering::Relaxed)
+}
+
 pub fn current() -> &'static mut PageTable {
     unsafe { GPT.get_mut().unwrap() }
 }
@@ -19,11 +162,16 @@ pub fn current() -> &'static mut PageTable {
 pub fn prefill_pagetable(
     r: impl Iterator<Item = AddressRange>,
     rw: impl Iterator<Item = AddressRange>,
-    rwx: impl Iterator<Item = AddressRange>,
-    pre_allocated: impl Iterator<Item = AddressRange>,
+    rx: impl Iterator<Item = AddressRange>,
+    device: impl Iterator<Item = AddressRange>,
     allocator: &impl PageAlloc,
 ) -> Result<(), Error> {
-    let pt = hal_core::mm::prefill_pagetable::<PageTable>(r, rw, rwx, pre_allocated, allocator)?;
+    let pt =
+        hal_core::mm::prefill_pagetable::<PageTable>(r, rw, rx, device, virt_to_phys, allocator)?;
+
+    // Everything the kernel maps, now or later, is above the direct map.
+    pt.pin_root_entries(AddressRange::new(PHYS_OFFSET..usize::MAX), allocator)?;
+    KERNEL_ROOT_PINNED.store(true, Ordering::Relaxed);
 
     // TODO: put into into the hal_core::Error
     unsafe {
@@ -39,20 +187,49 @@ pub fn enable_paging() {
     unsafe {
         load_pagetable(current());
     }
+
+    address_space::ASIDS.set_asid_bits(probe_asid_bits());
 }
 
-unsafe fn load_pagetable(pt: &'static mut PageTable) {
-    let pt_addr = pt as *mut PageTable as usize;
+/// Returns the number of ASID bits the hart implements, satp ignores the writes to the others.
+fn probe_asid_bits() -> u32 {
+    const SATP_ASID_SHIFT: u64 = 44;
+    const SATP_ASID_MASK: u64 = 0xffff << SATP_ASID_SHIFT;
+
+    let satp = read_satp();
+    write_satp(satp | SATP_ASID_MASK);
+    let asid_bits = ((read_satp() & SATP_ASID_MASK) >> SATP_ASID_SHIFT).count_ones();
+    write_satp(satp);
+
+    asid_bits
+}
+
+/// Switch back to the kernel's pagetable, which uses ASID 0.
+pub fn activate_kernel_pagetable() {
+    set_satp(current(), 0);
+
+    if !address_space::ASIDS.has_asids() {
+        // The address space we leave used ASID 0 too.
+        tlb::flush_all();
+    }
+}
+
+fn set_satp(pt: &PageTable, asid: Asid) {
+    let pt_addr = virt_to_phys(pt as *const PageTable as usize);
     let ppn = pt_addr >> 12;
 
-    let satp = Satp::with_values(ppn as u64, 0, SatpMode::Sv39);
+    let satp = Satp::with_values(ppn as u64, asid, paging_mode().satp_mode());
 
     unsafe {
         asm!("csrw satp, {}", in(reg)u64::from(satp));
-        asm!("sfence.vma");
     }
 }
 
+unsafe fn load_pagetable(pt: &'static mut PageTable) {
+    set_satp(pt, 0);
+    tlb::flush_all();
+}
+
 pub fn align_down(addr: usize) -> usize {
     mm::align_down(addr, PageTable::PAGE_SIZE)
 }
@@ -0,0 +1,731 @@
+use modular_bitfield::{bitfield, prelude::*};
+
+use hal_core::mm::{self, MemoryType, PageAlloc, PageEntry, PageMap};
+use hal_core::{AddressRange, Error};
+
+use super::{kernel_root_pinned, paging_mode, phys_to_virt, svpbmt_enabled, tlb, virt_to_phys};
+
+use core::ptr;
+
+#[repr(C)]
+pub struct VAddr {
+    addr: u64,
+}
+
+impl From<mm::VAddr> for VAddr {
+    fn from(vaddr: mm::VAddr) -> Self {
+        assert_eq!(usize::BITS, u64::BITS);
+        Self::from_u64(vaddr.val as u64)
+    }
+}
+
+impl VAddr {
+    pub fn from_u64(addr: u64) -> VAddr {
+        // The bits above the highest one of the paging mode are copies of it.
+        let unused_bits = 64 - paging_mode().va_bits();
+
+        Self {
+            addr: ((addr << unused_bits) as i64 >> unused_bits) as u64,
+        }
+    }
+
+    pub fn vpn(&self, nb: usize) -> u16 {
+        let vpn = self.addr >> 12;
+
+        ((vpn >> (nb * 9)) & 0x1ff) as u16
+    }
+}
+
+#[repr(C)]
+pub struct PAddr {
+    addr: u64,
+}
+
+impl From<mm::PAddr> for PAddr {
+    fn from(paddr: mm::PAddr) -> Self {
+        assert_eq!(usize::BITS, u64::BITS);
+        Self::from_u64(paddr.val as u64)
+    }
+}
+
+impl PAddr {
+    pub fn from_u64(addr: u64) -> Self {
+        let mut paddr = Self { addr };
+
+        let bit55 = (paddr.ppn(2) >> 8) != 0;
+
+        paddr.extend_55th_bit(bit55);
+
+        paddr
+    }
+
+    fn ppn(&self, nb: usize) -> u64 {
+        let ppn = self.addr >> 12;
+
+        if nb == 2 {
+            (ppn >> (nb * 9)) & 0x3fffff
+        } else {
+            (ppn >> (nb * 9)) & 0x1ff
+        }
+    }
+
+    fn extend_55th_bit(&mut self, bit: bool) {
+        let mask = ((!0u64) >> 55) << 55;
+
+        if bit {
+            self.addr |= mask; // set all the bits above the 55th
+        } else {
+            self.addr &= !mask; // clear all the bits above the 55th
+        }
+    }
+}
+
+#[repr(u64)]
+#[bitfield]
+pub struct PageTableEntry {
+    v: B1,
+    r: B1,
+    w: B1,
+    x: B1,
+    u: B1,
+    #[skip]
+    g: B1,
+    #[skip]
+    a: B1,
+    #[skip]
+    d: B1,
+    /// Left to software, bit 0 marks copy-on-write pages.
+    rsw: B2,
+    ppn0: B9,
+    ppn1: B9,
+    ppn2: B26,
+    #[skip]
+    reserved: B7,
+    pbmt: B2,
+    #[skip]
+    n: B1,
+}
+
+impl PageTableEntry {
+    fn is_valid(&self) -> bool {
+        self.v() == 1
+    }
+
+    fn set_valid(&mut self) {
+        self.set_v(1)
+    }
+
+    fn set_paddr(&mut self, paddr: &PAddr) {
+        self.set_ppn2(paddr.ppn(2) as u32);
+        self.set_ppn1(paddr.ppn(1) as u16);
+        self.set_ppn0(paddr.ppn(0) as u16);
+    }
+
+    fn set_target(&mut self, pt: *mut PageTable) {
+        let addr = virt_to_phys(pt as usize) as u64;
+        self.set_paddr(&PAddr::from_u64(addr))
+    }
+
+    /// A valid entry with any of the R, W or X bits set is a leaf, otherwise it points to the
+    /// next level of the pagetable.
+    fn is_leaf(&self) -> bool {
+        self.r() == 1 || self.w() == 1 || self.x() == 1
+    }
+
+    fn get_paddr(&self) -> u64 {
+        ((self.ppn2() as u64) << 18 | (self.ppn1() as u64) << 9 | self.ppn0() as u64) * 4096u64
+    }
+
+    fn get_target(&mut self) -> &mut PageTable {
+        let table = phys_to_virt(self.get_paddr() as usize) as *mut PageTable;
+        unsafe { table.as_mut().unwrap() }
+    }
+
+    fn target(&self) -> &PageTable {
+        let table = phys_to_virt(self.get_paddr() as usize) as *const PageTable;
+        unsafe { table.as_ref().unwrap() }
+    }
+
+    /// Returns the entry itself if it points to a table of the next level.
+    fn next_level(&self) -> Option<&PageTableEntry> {
+        (self.is_valid() && !self.is_leaf()).then_some(self)
+    }
+
+    fn set_perms(&mut self, perms: mm::Permissions) {
+        debug_assert!(!perms.contains(mm::Permissions::WRITE | mm::Permissions::COPY_ON_WRITE));
+
+        self.set_r(perms.contains(mm::Permissions::READ) as u8);
+        self.set_w(perms.contains(mm::Permissions::WRITE) as u8);
+        self.set_x(perms.contains(mm::Permissions::EXECUTE) as u8);
+        self.set_u(perms.contains(mm::Permissions::USER) as u8);
+        self.set_rsw(perms.contains(mm::Permissions::COPY_ON_WRITE) as u8);
+    }
+
+    /// Only has an effect when the Svpbmt extension is there, the PBMT bits are reserved
+    /// otherwise and the attributes of the memory come from the platform.
+    fn set_mem_type(&mut self, mem_type: MemoryType) {
+        if !svpbmt_enabled() {
+            return;
+        }
+
+        // PMA, NC or IO.
+        let pbmt = match mem_type {
+            MemoryType::NormalCacheable => 0,
+            MemoryType::NormalNonCacheable => 1,
+            MemoryType::DeviceNGnRE | MemoryType::DeviceNGnRnE => 2,
+        };
+        self.set_pbmt(pbmt);
+    }
+
+    /// Without Svpbmt the PBMT bits stay clear, everything reads as normal cacheable memory.
+    fn get_mem_type(&self) -> MemoryType {
+        match self.pbmt() {
+            1 => MemoryType::NormalNonCacheable,
+            2 => MemoryType::DeviceNGnRE,
+            _ => MemoryType::NormalCacheable,
+        }
+    }
+
+    fn get_perms(&self) -> mm::Permissions {
+        let mut perms = mm::Permissions::empty();
+        perms.set(mm::Permissions::READ, self.r() == 1);
+        perms.set(mm::Permissions::WRITE, self.w() == 1);
+        perms.set(mm::Permissions::EXECUTE, self.x() == 1);
+        perms.set(mm::Permissions::USER, self.u() == 1);
+        perms.set(mm::Permissions::COPY_ON_WRITE, self.rsw() & 1 == 1);
+
+        perms
+    }
+}
+
+impl PageEntry for PageTableEntry {
+    fn set_invalid(&mut self) {
+        self.set_v(0);
+    }
+}
+
+impl PageTableEntry {
+    /// Overwrite the whole entry with a single store, so that the walker never sees it half
+    /// updated.
+    fn replace(&mut self, new: PageTableEntry) {
+        let raw = u64::from(new);
+        unsafe { (self as *mut PageTableEntry as *mut u64).write_volatile(raw) };
+    }
+}
+
+#[repr(align(0x1000))]
+pub struct PageTable {
+    entries: [PageTableEntry; 512],
+}
+
+impl PageTable {
+    /// Level of the root tables, the leaves of level 0 map pages.
+    pub(super) fn root_level() -> usize {
+        paging_mode().levels() - 1
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.iter().all(|pte| !pte.is_valid())
+    }
+
+    /// Size of the memory mapped by a leaf entry at `level`.
+    fn level_size(level: usize) -> usize {
+        Self::PAGE_SIZE << (9 * level)
+    }
+
+    /// Returns the leaf entry mapping `vaddr` along with its level.
+    fn leaf(&self, vaddr: &VAddr) -> Option<(&PageTableEntry, usize)> {
+        let mut pagetable = self;
+
+        for level in (0..=Self::root_level()).rev() {
+            let pte = &pagetable.entries[vaddr.vpn(level) as usize];
+
+            if !pte.is_valid() {
+                return None;
+            }
+
+            if level == 0 || pte.is_leaf() {
+                return Some((pte, level));
+            }
+
+            pagetable = pte.target();
+        }
+
+        unreachable!("We should have returned by now");
+    }
+
+    /// Returns the entry of `vaddr` at `level`, creating the missing tables on the way.
+    /// Leaves of a bigger size found on the way are split, a table found at `level` is freed along
+    /// with its sub-tables so that the entry can become a leaf.
+    fn entry_at_level(
+        &mut self,
+        vaddr: &VAddr,
+        level: usize,
+        allocator: &impl PageAlloc,
+    ) -> Result<&mut PageTableEntry, Error> {
+        let mut pagetable = self;
+
+        for current_level in (level..=Self::root_level()).rev() {
+            let pte = &mut pagetable.entries[vaddr.vpn(current_level) as usize];
+
+            if current_level == level {
+                if Self::is_pinned_root_entry(vaddr, level) {
+                    return Err(Error::InvalidBlock(vaddr.addr as usize));
+                }
+
+                if level > 0 && pte.is_valid() && !pte.is_leaf() {
+                    let table = pte.get_target() as *mut PageTable;
+                    pte.set_invalid();
+                    tlb::flush_all();
+
+                    // Safety: the entry was pointing to this table, nothing else references it.
+                    unsafe { (*table).free_tables(None, level - 1, allocator) };
+                    let _ = allocator.dealloc(virt_to_phys(table as usize), 1);
+                }
+
+                return Ok(pte);
+            }
+
+            if !pte.is_valid() {
+                let new_page_table = PageTable::new(allocator)?;
+
+                let mut table_pte = PageTableEntry::new();
+                table_pte.set_target(new_page_table as *mut PageTable);
+                table_pte.set_valid();
+                pte.replace(table_pte);
+            } else if pte.is_leaf() {
+                Self::split(pte, current_level, allocator)?;
+            }
+
+            pagetable = pte.get_target();
+        }
+
+        unreachable!("We should have returned by now");
+    }
+
+    /// Replace the leaf `pte` of `level` by a table of smaller leaves mapping the same memory with
+    /// the same permissions and memory type.
+    fn split(
+        pte: &mut PageTableEntry,
+        level: usize,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let table = PageTable::new(allocator)?;
+        let (base, perms, pbmt) = (pte.get_paddr(), pte.get_perms(), pte.pbmt());
+
+        for (i, entry) in table.entries.iter_mut().enumerate() {
+            let paddr = base + (i * Self::level_size(level - 1)) as u64;
+
+            entry.set_paddr(&PAddr::from_u64(paddr));
+            entry.set_perms(perms);
+            entry.set_pbmt(pbmt);
+            entry.set_valid();
+        }
+
+        // The privileged spec doesn't require break-before-make, the old leaf only has to be flushed once the
+        // table is in place.
+        let mut table_pte = PageTableEntry::new();
+        table_pte.set_target(table as *mut PageTable);
+        table_pte.set_valid();
+        pte.replace(table_pte);
+        tlb::flush_all();
+
+        Ok(())
+    }
+
+    /// Call `f` with the leaves below this table, which is at `level` and translates the addresses
+    /// starting at `base`.
+    fn walk_level(&self, base: u64, level: usize, f: &mut impl FnMut(mm::Mapping)) {
+        for (index, pte) in self.entries.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+
+            let va = VAddr::from_u64(base + ((index as u64) << (12 + 9 * level))).addr;
+            if level == 0 || pte.is_leaf() {
+                f(mm::Mapping {
+                    va: mm::VAddr::new(va as usize),
+                    pa: mm::PAddr::new(pte.get_paddr() as usize),
+                    size: Self::level_size(level),
+                    perms: pte.get_perms(),
+                    mem_type: pte.get_mem_type(),
+                });
+            } else {
+                pte.target().walk_level(va, level - 1, f);
+            }
+        }
+    }
+
+    /// Give back to `allocator` the tables below this one, which is at `level`, except the ones
+    /// also found at the same place in `shared_with`.
+    pub(super) fn free_tables(
+        &mut self,
+        shared_with: Option<&PageTable>,
+        level: usize,
+        allocator: &impl PageAlloc,
+    ) {
+        if level == 0 {
+            return;
+        }
+
+        for (index, pte) in self.entries.iter_mut().enumerate() {
+            if !pte.is_valid() || pte.is_leaf() {
+                continue;
+            }
+
+            let shared_table = shared_with.and_then(|shared| shared.entries[index].next_level());
+            if shared_table.map(|table| table.get_paddr()) == Some(pte.get_paddr()) {
+                continue;
+            }
+
+            let table = pte.get_target();
+            table.free_tables(shared_table.map(|pte| pte.target()), level - 1, allocator);
+            let _ = allocator.dealloc(virt_to_phys(table as *mut PageTable as usize), 1);
+        }
+    }
+
+    /// Give a table of its own to every root entry covering `kernel`, splitting the leaves, so
+    /// that these root entries never change afterwards: tables below them can't be freed and
+    /// blocks can't replace them. The root tables copied from this one see all the changes made
+    /// to the mappings of `kernel`.
+    pub(super) fn pin_root_entries(
+        &mut self,
+        kernel: AddressRange,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let level = Self::root_level();
+        let first = VAddr::from_u64(kernel.start as u64).vpn(level) as usize;
+        let last = VAddr::from_u64((kernel.end - 1) as u64).vpn(level) as usize;
+
+        for pte in self.entries[first..=last].iter_mut() {
+            if !pte.is_valid() {
+                let table = PageTable::new(allocator)?;
+
+                let mut table_pte = PageTableEntry::new();
+                table_pte.set_target(table as *mut PageTable);
+                table_pte.set_valid();
+                pte.replace(table_pte);
+            } else if pte.is_leaf() {
+                Self::split(pte, level, allocator)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the entry of `vaddr` at `level` is one of the root entries of the kernel that
+    /// mustn't change anymore, see [`Self::pin_root_entries`].
+    fn is_pinned_root_entry(vaddr: &VAddr, level: usize) -> bool {
+        // Only the upper half, the kernel's, is ever pinned.
+        level == Self::root_level() && (vaddr.addr as i64) < 0 && kernel_root_pinned()
+    }
+
+    /// Allocate a root table with the same entries as `other`, all the tables below are shared.
+    pub(super) fn copy_of(
+        other: &PageTable,
+        allocator: &impl PageAlloc,
+    ) -> Result<&'static mut PageTable, Error> {
+        let table = PageTable::new(allocator)?;
+        unsafe { ptr::copy_nonoverlapping(other as *const PageTable, table as *mut PageTable, 1) };
+
+        Ok(table)
+    }
+
+    /// Replace the tables on the way to `va` that are shared with `kernel` by private copies, so
+    /// that changing the mapping of `va` leaves `kernel` untouched.
+    pub(super) fn unshare(
+        &mut self,
+        kernel: &PageTable,
+        va: mm::VAddr,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let vaddr: VAddr = va.into();
+        let mut pagetable = self;
+        let mut kernel_table = kernel;
+
+        for level in (1..=Self::root_level()).rev() {
+            let index = vaddr.vpn(level) as usize;
+            let kernel_pte = match kernel_table.entries[index].next_level() {
+                Some(kernel_pte) => kernel_pte,
+                None => return Ok(()),
+            };
+
+            let pte = &mut pagetable.entries[index];
+            if !pte.is_valid() || pte.is_leaf() {
+                return Ok(());
+            }
+
+            if pte.get_paddr() == kernel_pte.get_paddr() {
+                let copy = Self::copy_of(kernel_pte.target(), allocator)?;
+
+                let mut table_pte = PageTableEntry::new();
+                table_pte.set_target(copy as *mut PageTable);
+                table_pte.set_valid();
+                pte.replace(table_pte);
+                // The walk caches may still point to the shared table.
+                tlb::flush_all();
+            }
+
+            pagetable = pte.get_target();
+            kernel_table = kernel_pte.target();
+        }
+
+        Ok(())
+    }
+
+    fn map_at_level(
+        &mut self,
+        va: mm::VAddr,
+        pa: mm::PAddr,
+        perms: mm::Permissions,
+        mem_type: MemoryType,
+        level: usize,
+        allocator: &impl PageAlloc,
+    ) -> Result<&mut PageTableEntry, Error> {
+        let vaddr: VAddr = va.into();
+        let pte = self.entry_at_level(&vaddr, level, allocator)?;
+        let was_valid = pte.is_valid();
+
+        let mut leaf = PageTableEntry::new();
+        leaf.set_paddr(&pa.into());
+        leaf.set_perms(perms);
+        leaf.set_mem_type(mem_type);
+        leaf.set_valid();
+        pte.replace(leaf);
+
+        if was_valid {
+            tlb::flush_page(va);
+        }
+
+        Ok(pte)
+    }
+
+    /// Invalidate the leaf entry of `vaddr` in the sub-tree starting at `level` and free the
+    /// tables that are left without any valid entry.
+    fn unmap_level(
+        &mut self,
+        vaddr: &VAddr,
+        level: usize,
+        allocator: &impl PageAlloc,
+    ) -> Option<u64> {
+        let pte = &mut self.entries[vaddr.vpn(level) as usize];
+
+        if !pte.is_valid() {
+            return None;
+        }
+
+        if level == 0 || pte.is_leaf() {
+            let paddr = pte.get_paddr();
+            pte.set_invalid();
+
+            return Some(paddr);
+        }
+
+        let next_level = pte.get_target();
+        let paddr = next_level.unmap_level(vaddr, level - 1, allocator)?;
+
+        if next_level.is_empty() && !Self::is_pinned_root_entry(vaddr, level) {
+            let next_level_addr = pte.get_paddr() as usize;
+            pte.set_invalid();
+            // `sfence.vma va` only guarantees the leaf is evicted, the walk caches may still hold
+            // the table we are about to free.
+            tlb::flush_all();
+            // Not being able to give back the page isn't worth failing the unmap.
+            let _ = allocator.dealloc(next_level_addr, 1);
+        }
+
+        Some(paddr)
+    }
+}
+
+impl PageMap for PageTable {
+    const PAGE_SIZE: usize = 4096;
+    const BLOCK_SIZES: &'static [usize] = &[4096, 2 * 1024 * 1024, 1024 * 1024 * 1024];
+    type Entry = PageTableEntry;
+
+    fn new(allocator: &impl PageAlloc) -> Result<&'static mut Self, Error> {
+        let page = allocator.alloc(1)?;
+        let page_table = phys_to_virt(page) as *mut PageTable;
+        // Safety: the PMM gave us the memory, it is reachable through the direct map.
+        let page_table: &mut PageTable = unsafe { page_table.as_mut().unwrap() };
+
+        page_table
+            .entries
+            .iter_mut()
+            .for_each(|pte| *pte = PageTableEntry::new());
+
+        Ok(page_table)
+    }
+
+    fn map(
+        &mut self,
+        va: mm::VAddr,
+        pa: mm::PAddr,
+        perms: mm::Permissions,
+        mem_type: MemoryType,
+        allocator: &impl PageAlloc,
+    ) -> Result<&mut Self::Entry, Error> {
+        self.map_at_level(va, pa, perms, mem_type, 0, allocator)
+    }
+
+    fn map_block(
+        &mut self,
+        va: mm::VAddr,
+        pa: mm::PAddr,
+        size: usize,
+        perms: mm::Permissions,
+        mem_type: MemoryType,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let level = Self::BLOCK_SIZES
+            .iter()
+            .position(|&block_size| block_size == size)
+            .filter(|_| va.val % size == 0 && pa.val % size == 0)
+            .ok_or(Error::InvalidBlock(va.val))?;
+
+        self.map_at_level(va, pa, perms, mem_type, level, allocator)
+            .map(|_| ())
+    }
+
+    fn unmap(&mut self, va: mm::VAddr, allocator: &impl PageAlloc) -> Option<mm::PAddr> {
+        let vaddr: VAddr = va.into();
+
+        let (_, level) = self.leaf(&vaddr)?;
+        if level > 0 {
+            self.entry_at_level(&vaddr, 0, allocator).ok()?;
+        }
+
+        let paddr = self.unmap_level(&vaddr, Self::root_level(), allocator)?;
+        tlb::flush_page(va);
+
+        Some(mm::PAddr::new(paddr as usize))
+    }
+
+    fn translate(&self, va: mm::VAddr) -> Option<(mm::PAddr, mm::Permissions)> {
+        let vaddr: VAddr = va.into();
+        let (pte, level) = self.leaf(&vaddr)?;
+
+        let offset = va.val & (Self::level_size(level) - 1);
+        let paddr = mm::PAddr::new(pte.get_paddr() as usize + offset);
+
+        Some((paddr, pte.get_perms()))
+    }
+
+    fn protect(
+        &mut self,
+        range: AddressRange,
+        perms: mm::Permissions,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), Error> {
+        let mut page = range.start;
+
+        while page < range.end {
+            let vaddr: VAddr = mm::VAddr::new(page).into();
+            let (_, leaf_level) = self.leaf(&vaddr).ok_or(Error::NotMapped(page))?;
+
+            // A leaf only partially covered by the range is split down to pages.
+            let size = Self::level_size(leaf_level);
+            let level = if page % size == 0 && page + size <= range.end {
+                leaf_level
+            } else {
+                0
+            };
+
+            self.entry_at_level(&vaddr, level, allocator)?
+                .set_perms(perms);
+            tlb::flush_page(mm::VAddr::new(page));
+
+            page += Self::level_size(level);
+        }
+
+        Ok(())
+    }
+
+    fn flush_tlb_page(&self, va: mm::VAddr) {
+        tlb::flush_page(va);
+    }
+
+    fn flush_tlb_asid(&self, asid: mm::Asid) {
+        tlb::flush_asid(asid);
+    }
+
+    fn flush_tlb_all(&self) {
+        tlb::flush_all();
+    }
+
+    fn walk(&self, f: &mut impl FnMut(mm::Mapping)) {
+        self.walk_level(0, Self::root_level(), f);
+    }
+}
+
+#[repr(u8)]
+pub(crate) enum SatpMode {
+    _Bare = 0,
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+    _Sv64 = 11,
+}
+
+/// The translation scheme, all the pagetables have the same number of levels.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PagingMode {
+    Sv39,
+    Sv48,
+    Sv57,
+}
+
+impl PagingMode {
+    /// From the `mmu-type` property of a cpu node of the device tree.
+    pub fn from_mmu_type(mmu_type: &str) -> Option<Self> {
+        match mmu_type {
+            "riscv,sv39" => Some(Self::Sv39),
+            "riscv,sv48" => Some(Self::Sv48),
+            "riscv,sv57" => Some(Self::Sv57),
+            _ => None,
+        }
+    }
+
+    pub fn levels(self) -> usize {
+        match self {
+            Self::Sv39 => 3,
+            Self::Sv48 => 4,
+            Self::Sv57 => 5,
+        }
+    }
+
+    /// Number of meaningful bits of the virtual addresses, half of the address space is below
+    /// `1 << (va_bits - 1)` and belongs to the processes.
+    pub fn va_bits(self) -> u32 {
+        12 + 9 * self.levels() as u32
+    }
+
+    pub(crate) fn satp_mode(self) -> SatpMode {
+        match self {
+            Self::Sv39 => SatpMode::Sv39,
+            Self::Sv48 => SatpMode::Sv48,
+            Self::Sv57 => SatpMode::Sv57,
+        }
+    }
+}
+
+#[repr(u64)]
+#[bitfield]
+pub(crate) struct Satp {
+    #[skip(getters)]
+    ppn: B44,
+    #[skip(getters)]
+    asid: B16,
+    #[skip(getters)]
+    mode: B4,
+}
+
+impl Satp {
+    pub fn with_values(ppn: u64, asid: u16, mode: SatpMode) -> Self {
+        Satp::new()
+            .with_ppn(ppn)
+            .with_asid(asid)
+            .with_mode(mode as u8)
+    }
+}
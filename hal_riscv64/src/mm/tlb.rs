@@ -0,0 +1,32 @@
+//! TLB maintenance, these only act on the current hart.
+
+use core::arch::asm;
+use hal_core::mm::{Asid, VAddr};
+
+/// Invalidate the cached translations of `va` in all address spaces.
+pub fn flush_page(va: VAddr) {
+    unsafe {
+        asm!("sfence.vma {}, zero", in(reg) va.val);
+    }
+}
+
+/// Invalidate the cached translation of `va` in the address space `asid`.
+pub fn flush_page_asid(va: VAddr, asid: Asid) {
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) va.val, in(reg) asid as usize);
+    }
+}
+
+/// Invalidate all the cached translations of the address space `asid`, global mappings are kept.
+pub fn flush_asid(asid: Asid) {
+    unsafe {
+        asm!("sfence.vma zero, {}", in(reg) asid as usize);
+    }
+}
+
+/// Invalidate all the cached translations, including the intermediate levels of the pagetables.
+pub fn flush_all() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
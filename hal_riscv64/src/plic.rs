@@ -1,90 +1,215 @@
+use hal_core::irq::{IrqChip, Trigger};
 use hal_core::Error;
 
-const PLIC_ENABLE_OFFSET: usize = 0x002080;
-const PLIC_THRESHOLD_OFFSET: usize = 0x201000;
-const PLIC_NUMBER_SOURCES: u16 = 1024;
-const PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER: u8 = 32;
-const PLIC_NUMBER_SOURCE_REGISTER: u16 =
-    PLIC_NUMBER_SOURCES / PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER as u16;
-const PLIC_MAX_CONTEXT: u16 = 0x3e00;
-const PLIC_CLAIM_OFFSET: usize = 0x201004;
-
+use super::cpu;
+
+const PLIC_ENABLE_OFFSET: usize = 0x002000;
+const PLIC_ENABLE_CONTEXT_STRIDE: usize = 0x80;
+const PLIC_THRESHOLD_OFFSET: usize = 0x200000;
+const PLIC_CLAIM_OFFSET: usize = 0x200004;
+const PLIC_CONTEXT_STRIDE: usize = 0x1000;
+const PLIC_NUMBER_SOURCES: u32 = 1024;
+const PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER: u32 = 32;
+const PLIC_MAX_CONTEXT: usize = 15872;
+/// Harts with an id past this don't get interrupts from the PLIC.
+pub const PLIC_MAX_HARTS: usize = cpu::MAX_HARTS;
+
+/// The enable bits, threshold and claim register of a hart are those of its supervisor context,
+/// the one of the hart calling the methods. Sources are routed to the harts that enabled them.
 pub struct Plic {
     base_register_address: usize,
+    /// The sources are numbered from 1 to `ndev`.
+    ndev: u32,
+    /// The supervisor context of each hart, indexed by hart id.
+    contexts: [Option<usize>; PLIC_MAX_HARTS],
+    /// Number of bits of the priorities, the PLIC ignores the others.
+    priority_bits: u32,
 }
 
 impl Plic {
-    pub fn new(base_register_address: usize) -> Plic {
-        Self {
+    /// The calling hart is ready to take interrupts once this returns, see [`Plic::init_hart`].
+    /// `contexts` are the supervisor contexts of the harts, from the device tree.
+    pub fn new(
+        base_register_address: usize,
+        ndev: u32,
+        contexts: [Option<usize>; PLIC_MAX_HARTS],
+    ) -> Result<Plic, Error> {
+        if contexts
+            .iter()
+            .flatten()
+            .any(|&context| context >= PLIC_MAX_CONTEXT)
+        {
+            return Err(Error::InvalidDeviceTreeNode("interrupts-extended"));
+        }
+
+        let mut plic = Self {
             base_register_address,
+            ndev: ndev.min(PLIC_NUMBER_SOURCES - 1),
+            contexts,
+            priority_bits: 0,
+        };
+        plic.priority_bits = plic.probe_priority_bits();
+
+        if plic
+            .contexts
+            .get(cpu::hart_id())
+            .copied()
+            .flatten()
+            .is_none()
+        {
+            return Err(Error::InvalidDeviceTreeNode("interrupts-extended"));
+        }
+        plic.init_hart();
+
+        Ok(plic)
+    }
+
+    /// The priority registers only keep the bits that are implemented, the others read as 0.
+    fn probe_priority_bits(&self) -> u32 {
+        if self.ndev == 0 {
+            return 0;
+        }
+
+        let addr = self.priority_register(1);
+        unsafe {
+            let priority = addr.read_volatile();
+            addr.write_volatile(u32::MAX);
+            let bits = addr.read_volatile().count_ones();
+            addr.write_volatile(priority);
+
+            bits
         }
     }
 
+    /// Set up the context of the calling hart.
+    pub fn init_hart(&self) {
+        // Let every interrupt with a non zero priority through.
+        self.set_threshold(0);
+    }
+
+    /// Interrupts with a priority lower or equal to `threshold` don't reach the calling hart.
     pub fn set_threshold(&self, threshold: u8) {
         unsafe {
-            let addr = (self.base_register_address + PLIC_THRESHOLD_OFFSET) as *mut u32;
+            let addr = (self.context_registers() + PLIC_THRESHOLD_OFFSET) as *mut u32;
             addr.write_volatile(threshold as u32);
         }
     }
 
-    pub fn enable_interrupt(&self, id: u16, hart: u16) -> Result<(), &'static str> {
-        if id >= PLIC_NUMBER_SOURCES {
-            return Err("enable_interrupt: Id is higher than PLIC_MAX_INTERRUPT_SOURCE");
-        }
+    fn context(&self) -> usize {
+        self.contexts
+            .get(cpu::hart_id())
+            .copied()
+            .flatten()
+            .expect("hart has no plic context")
+    }
+
+    /// The registers of the calling hart's context, the threshold and claim offsets are relative
+    /// to this.
+    fn context_registers(&self) -> usize {
+        self.base_register_address + self.context() * PLIC_CONTEXT_STRIDE
+    }
+
+    fn priority_register(&self, id: u32) -> *mut u32 {
+        (self.base_register_address + id as usize * 4) as *mut u32
+    }
 
-        if hart >= PLIC_MAX_CONTEXT {
-            return Err("enable_interrupt: hart is higher than PLIC_MAX_CONTEXT");
+    fn check_source(&self, id: u32) -> Result<(), Error> {
+        // Source 0 doesn't exist, claiming it means there is nothing pending.
+        if id == 0 || id > self.ndev {
+            Err(Error::InvalidIrqLine(id))
+        } else {
+            Ok(())
         }
+    }
 
-        let source_offset = (id / PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER as u16
-            + hart * PLIC_NUMBER_SOURCE_REGISTER) as usize;
-        let id_shift = 1 << (id % PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER as u16);
+    /// The enable register of `id` in the calling hart's context and its bit in there.
+    fn enable_register(&self, id: u32) -> (*mut u32, u32) {
+        let register = (id / PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER) as usize;
+        let addr = self.base_register_address
+            + PLIC_ENABLE_OFFSET
+            + self.context() * PLIC_ENABLE_CONTEXT_STRIDE
+            + register * 4;
+
+        (
+            addr as *mut u32,
+            1 << (id % PLIC_NUMBER_INTERRUPT_SOURCE_BY_REGISTER),
+        )
+    }
+}
 
+impl IrqChip for Plic {
+    fn enable(&self, id: u32) -> Result<(), Error> {
+        self.check_source(id)?;
+
+        let (addr, bit) = self.enable_register(id);
         unsafe {
-            let addr =
-                (self.base_register_address + PLIC_ENABLE_OFFSET + source_offset) as *mut u32;
-            let current_interrupt = core::ptr::read_volatile(addr);
-            addr.write_volatile(current_interrupt | id_shift);
+            addr.write_volatile(addr.read_volatile() | bit);
         }
 
         Ok(())
     }
 
-    pub fn set_priority(&self, id: u16, priority: u32) -> Result<(), &'static str> {
-        if id >= PLIC_NUMBER_SOURCES {
-            return Err("set_priority: Id is higher than PLIC_MAX_INTERRUPT_SOURCE");
-        }
+    fn disable(&self, id: u32) -> Result<(), Error> {
+        self.check_source(id)?;
 
+        let (addr, bit) = self.enable_register(id);
         unsafe {
-            let addr = (self.base_register_address + (id * 4) as usize) as *mut u32;
-            addr.write_volatile(priority);
+            addr.write_volatile(addr.read_volatile() & !bit);
         }
 
         Ok(())
     }
 
-    pub fn claim(&self) -> u32 {
+    fn set_priority(&self, id: u32, priority: u8) -> Result<(), Error> {
+        self.check_source(id)?;
+
+        // Keep the most significant bits, without masking the lowest non zero priorities.
+        let priority = match priority {
+            0 => 0,
+            priority => (priority as u32 >> (8 - self.priority_bits.min(8))).max(1),
+        };
+
         unsafe {
-            let addr = (self.base_register_address + PLIC_CLAIM_OFFSET) as *mut u32;
+            self.priority_register(id).write_volatile(priority);
+        }
+
+        Ok(())
+    }
+
+    fn set_trigger(&self, id: u32, _trigger: Trigger) -> Result<(), Error> {
+        // The gateways of the sources are wired for their device, edges or levels they all reach
+        // the core the same way.
+        self.check_source(id)
+    }
+
+    fn claim(&self) -> Option<u32> {
+        let source = unsafe {
+            let addr = (self.context_registers() + PLIC_CLAIM_OFFSET) as *mut u32;
             addr.read_volatile()
+        };
+
+        if source == 0 {
+            None
+        } else {
+            Some(source)
         }
     }
 
-    pub fn complete(&self, source: u32) {
+    fn complete(&self, source: u32) {
         unsafe {
-            // XXX: this isn't hart specific ?
-            let addr = (self.base_register_address + PLIC_CLAIM_OFFSET) as *mut u32;
+            let addr = (self.context_registers() + PLIC_CLAIM_OFFSET) as *mut u32;
             addr.write_volatile(source);
         }
     }
 
-    fn get_int(&self) -> Result<u32, Error> {
-        let source = self.claim();
-
-        Ok(source)
-    }
-
-    fn clear_int(&self, int: u32) {
-        self.complete(int);
+    fn decode_interrupt(&self, cells: &[u32]) -> Result<(u32, Trigger), Error> {
+        match *cells {
+            [source] => {
+                self.check_source(source)?;
+                // The trigger is the gateway's business, see set_trigger.
+                Ok((source, Trigger::Level))
+            }
+            _ => Err(Error::InvalidDeviceTreeNode("interrupts")),
+        }
     }
 }
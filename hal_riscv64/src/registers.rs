@@ -6,6 +6,21 @@ pub fn set_sstatus_sie() {
     }
 }
 
+pub fn clear_sstatus_sie() {
+    unsafe {
+        asm!("csrrc zero, sstatus, {}", in(reg)1 << 1);
+    }
+}
+
+pub fn read_sstatus_sie() -> bool {
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus);
+    }
+
+    sstatus & (1 << 1) != 0
+}
+
 pub fn set_sie_ssie() {
     unsafe {
         asm!("csrrs zero, sie, {}", in(reg)1 << 1);
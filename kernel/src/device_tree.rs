@@ -1,9 +1,60 @@
 use super::Error;
+use crate::hal;
 
 use hal_core::AddressRange;
 
+use fdt::node::CellSizes;
 use fdt::node::FdtNode;
 
+/// A range of memory the device tree keeps away from the kernel, either from the memory
+/// reservation block of the header or from a child of `/reserved-memory` with a `reg`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReservedMemory {
+    pub range: AddressRange,
+    /// Not even mapped, the CPU mustn't be able to access it speculatively.
+    pub no_map: bool,
+    /// The kernel may use it as long as the device owning it doesn't, there is no way to take
+    /// pages back from their users yet so it is reserved all the same.
+    pub reusable: bool,
+}
+
+/// A child of `/reserved-memory` with a `size` but no `reg`, the kernel decides where it goes.
+#[derive(Debug, Clone, Copy)]
+pub struct DynamicReservation<'a> {
+    pub name: &'a str,
+    pub size: usize,
+    pub alignment: usize,
+    pub no_map: bool,
+    pub reusable: bool,
+    alloc_ranges: Option<&'a [u8]>,
+    cell_sizes: CellSizes,
+}
+
+impl DynamicReservation<'_> {
+    /// The ranges the memory has to be taken from, anywhere in memory if `None`.
+    pub fn alloc_ranges(&self) -> Option<impl Iterator<Item = AddressRange> + '_> {
+        let pair_size = (self.cell_sizes.address_cells + self.cell_sizes.size_cells) * 4;
+
+        self.alloc_ranges.map(move |ranges| {
+            ranges.chunks_exact(pair_size).filter_map(move |pair| {
+                let (address, size) = pair.split_at(self.cell_sizes.address_cells * 4);
+                let (address, size) = (read_cells(address)?, read_cells(size)?);
+
+                (size > 0).then(|| AddressRange::with_size(address, size))
+            })
+        })
+    }
+}
+
+/// Big-endian value of one or two cells.
+fn read_cells(cells: &[u8]) -> Option<usize> {
+    match cells.len() {
+        4 => Some(u32::from_be_bytes(cells.try_into().ok()?) as usize),
+        8 => Some(u64::from_be_bytes(cells.try_into().ok()?) as usize),
+        _ => None,
+    }
+}
+
 pub struct DeviceTree {
     addr: usize,
     dtb: fdt::Fdt<'static>,
@@ -11,6 +62,7 @@ pub struct DeviceTree {
 }
 
 impl DeviceTree {
+    /// `device_tree_ptr` is the virtual address of the blob, in the direct map.
     pub fn new(device_tree_ptr: usize) -> Result<Self, Error> {
         let dtb = unsafe { fdt::Fdt::from_ptr(device_tree_ptr as *const u8)? };
 
@@ -21,8 +73,15 @@ impl DeviceTree {
         })
     }
 
+    /// The parsed blob, for the code that follows the references between nodes.
+    pub fn fdt(&self) -> &fdt::Fdt<'static> {
+        &self.dtb
+    }
+
+    /// Physical memory holding the blob.
     pub fn memory_region(&self) -> AddressRange {
-        AddressRange::new(self.addr..self.addr + self.total_size)
+        let start = hal::mm::virt_to_phys(self.addr);
+        AddressRange::new(start..start + self.total_size)
     }
 
     pub fn for_all_memory_regions<F: FnMut(&mut dyn Iterator<Item = (usize, usize)>)>(
@@ -37,22 +96,82 @@ impl DeviceTree {
         f(&mut regions);
     }
 
-    pub fn for_all_reserved_memory_regions<F: FnMut(&mut dyn Iterator<Item = (usize, usize)>)>(
+    /// The entries of the memory reservation block of the header, followed by the children of
+    /// `/reserved-memory` placed with a `reg` property.
+    pub fn for_all_reserved_memory_regions<F: FnMut(&mut dyn Iterator<Item = ReservedMemory>)>(
         &self,
         mut f: F,
     ) {
-        match self.dtb.find_node("/reserved-memory") {
-            None => (),
-            Some(reserved_memory) => {
-                let mut regions = reserved_memory
-                    .children()
-                    .flat_map(|child| child.reg())
-                    .flatten()
-                    .map(|region| (region.starting_address as usize, region.size.unwrap_or(0)));
-
-                f(&mut regions);
-            }
-        }
+        let header = self
+            .dtb
+            .memory_reservations()
+            .filter(|reservation| reservation.size() > 0)
+            .map(|reservation| ReservedMemory {
+                range: AddressRange::with_size(reservation.address() as usize, reservation.size()),
+                no_map: false,
+                reusable: false,
+            });
+
+        let nodes = self
+            .reserved_memory_children()
+            .filter_map(|child| Some((child, child.reg()?)))
+            .flat_map(|(child, regions)| {
+                let no_map = child.property("no-map").is_some();
+                let reusable = child.property("reusable").is_some();
+
+                regions
+                    .filter_map(|region| Some((region.starting_address as usize, region.size?)))
+                    .filter(|&(_, size)| size > 0)
+                    .map(move |(base, size)| ReservedMemory {
+                        range: AddressRange::with_size(base, size),
+                        no_map,
+                        reusable,
+                    })
+            });
+
+        f(&mut header.chain(nodes));
+    }
+
+    /// The children of `/reserved-memory` that only give a `size`, they have to be allocated.
+    pub fn for_all_dynamic_reservations<
+        F: FnMut(&mut dyn Iterator<Item = DynamicReservation<'static>>),
+    >(
+        &self,
+        mut f: F,
+    ) {
+        let cell_sizes = match self.dtb.find_node("/reserved-memory") {
+            Some(reserved_memory) => reserved_memory.cell_sizes(),
+            None => return,
+        };
+        let property =
+            |node: &FdtNode, name| node.property(name).and_then(|prop| read_cells(prop.value));
+
+        let mut reservations = self
+            .reserved_memory_children()
+            .filter(|child| child.reg().is_none())
+            .filter_map(|child| {
+                Some(DynamicReservation {
+                    name: child.name,
+                    size: property(&child, "size")?,
+                    alignment: property(&child, "alignment").unwrap_or(1),
+                    no_map: child.property("no-map").is_some(),
+                    reusable: child.property("reusable").is_some(),
+                    alloc_ranges: child.property("alloc-ranges").map(|prop| prop.value),
+                    cell_sizes,
+                })
+            });
+
+        f(&mut reservations);
+    }
+
+    fn reserved_memory_children(&self) -> impl Iterator<Item = FdtNode<'_, 'static>> {
+        self.dtb
+            .find_node("/reserved-memory")
+            .into_iter()
+            .flat_map(|reserved_memory| reserved_memory.children())
+            .filter(|child| {
+                child.property("status").and_then(|prop| prop.as_str()) != Some("disabled")
+            })
     }
 
     pub fn console_node(&self) -> Option<FdtNode> {
@@ -60,6 +179,42 @@ impl DeviceTree {
         chosen.stdout()
     }
 
+    /// Whether all the cpus implement the multi-letter riscv ISA `extension`, looking at their
+    /// "riscv,isa-extensions" property or at their "riscv,isa" string for older device trees.
+    pub fn cpus_have_isa_extension(&self, extension: &str) -> bool {
+        let mut cpus = self.cpus().peekable();
+
+        cpus.peek().is_some()
+            && cpus.all(|cpu| {
+                if let Some(extensions) = cpu.property("riscv,isa-extensions") {
+                    extensions
+                        .value
+                        .split(|&byte| byte == 0)
+                        .any(|ext| ext == extension.as_bytes())
+                } else if let Some(isa) = cpu.property("riscv,isa").and_then(|prop| prop.as_str()) {
+                    // The single-letter extensions come first, along with the base ISA.
+                    isa.split('_').skip(1).any(|ext| ext == extension)
+                } else {
+                    false
+                }
+            })
+    }
+
+    /// The "mmu-type" property of each cpu, "riscv,sv48" for example.
+    pub fn cpus_mmu_types(&self) -> impl Iterator<Item = &str> {
+        self.cpus().map(|cpu| {
+            cpu.property("mmu-type")
+                .and_then(|prop| prop.as_str())
+                .unwrap_or("")
+        })
+    }
+
+    fn cpus(&self) -> impl Iterator<Item = FdtNode<'_, 'static>> {
+        self.dtb.all_nodes().filter(|node| {
+            node.property("device_type").and_then(|prop| prop.as_str()) == Some("cpu")
+        })
+    }
+
     pub fn interrupt_controller(&self) -> Option<FdtNode> {
         // This is a funny one.
         // There can be multiple interrupt controllers:
@@ -7,9 +7,12 @@ use super::kernel_console;
 use drivers::{Console, Driver};
 use fdt::node::FdtNode;
 
+use crate::globals;
 use crate::hal;
-use crate::mm::alloc_pages_for_hal;
-use hal_core::mm::{PageMap, Permissions};
+use hal_core::{
+    mm::{MemoryType, PAddr, PageMap, Permissions},
+    AddressRange,
+};
 
 pub struct DriverManager {
     drivers: LinkedList<Arc<dyn Driver>>,
@@ -97,11 +100,12 @@ fn map_dt_regions(node: &FdtNode) -> Result<(), Error> {
             let size = memory_region.size.ok_or(Error::InvalidFdtNode)?;
 
             assert!(size % hal::mm::PAGE_SIZE == 0);
-            hal::mm::current().identity_map_range(
-                start.into(),
-                size / hal::mm::PAGE_SIZE,
+            hal::mm::current().map_addressrange(
+                AddressRange::with_size(hal::mm::phys_to_virt(start), size),
+                PAddr::new(start),
                 Permissions::READ | Permissions::WRITE,
-                alloc_pages_for_hal,
+                MemoryType::DeviceNGnRE,
+                &globals::PHYSICAL_MEMORY_MANAGER,
             )?;
         }
     }
@@ -119,10 +123,9 @@ fn unmap_dt_regions(node: &FdtNode) -> Result<(), Error> {
             assert!(size % hal::mm::PAGE_SIZE == 0);
 
             let kernel_pt = hal::mm::current();
+            let start = hal::mm::phys_to_virt(start);
             for page in (start..start + size).step_by(pagesize) {
-                kernel_pt
-                    .add_invalid_entry(page.into(), |_| unreachable!())
-                    .unwrap();
+                kernel_pt.unmap(page.into(), &globals::PHYSICAL_MEMORY_MANAGER);
             }
         }
     }
@@ -13,6 +13,11 @@ use fdt::standard_nodes::MemoryRegion;
 
 pub trait Driver {
     fn get_address_range(&self) -> Option<(usize, usize)>;
+
+    /// Register the interrupt handlers of the device, called once the interrupt controller is up.
+    fn init_irq(&'static self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 pub trait Console: Driver {
@@ -5,16 +5,70 @@ use super::Console;
 use super::ConsoleMatcher;
 use super::Driver;
 
-use crate::utils::lock::Lock;
+use crate::hal;
+use crate::irq;
+use crate::utils::lock::IrqLock;
+use crate::Error;
+use hal_core::irq::Trigger;
 
 pub extern crate alloc;
 use alloc::boxed::Box;
 
 const TRANSMITTER_HOLDING_REGISTER: usize = 0;
-const _INTERRUPT_ENABLE_REGISTER: usize = 1;
+const RECEIVER_BUFFER_REGISTER: usize = 0;
+const INTERRUPT_ENABLE_REGISTER: usize = 1;
+const MODEM_CONTROL_REGISTER: usize = 4;
+const MODEM_CONTROL_OUT2: u8 = 1 << 3;
+const MODEM_CONTROL_LOOPBACK: u8 = 1 << 4;
+const LINE_STATUS_REGISTER: usize = 5;
+
+/// How many received bytes are kept until they are read.
+const RX_BUFFER_SIZE: usize = 64;
 
 pub struct Ns16550 {
-    inner: Lock<Ns16550Inner>,
+    /// Also taken by the interrupt handler, it can't interrupt a write to the registers.
+    inner: IrqLock<Ns16550Inner>,
+    irq_line: Option<u32>,
+    rx: IrqLock<RxBuffer>,
+}
+
+/// The bytes received by the interrupt handler, the oldest ones are dropped when it is full.
+struct RxBuffer {
+    bytes: [u8; RX_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RxBuffer {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; RX_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len == RX_BUFFER_SIZE {
+            self.start = (self.start + 1) % RX_BUFFER_SIZE;
+            self.len -= 1;
+        }
+
+        self.bytes[(self.start + self.len) % RX_BUFFER_SIZE] = byte;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let byte = self.bytes[self.start];
+        self.start = (self.start + 1) % RX_BUFFER_SIZE;
+        self.len -= 1;
+
+        Some(byte)
+    }
 }
 
 struct Ns16550Inner {
@@ -28,29 +82,45 @@ impl Ns16550Inner {
         }
     }
 
-    pub fn _read(&self) -> u8 {
-        self._read_transmitter_holding_reg()
+    fn register(&self, offset: usize) -> *mut u8 {
+        (self.base_register_address as *mut u8).wrapping_add(offset)
     }
 
-    pub fn _enable_data_ready_interrupt(&self) {
-        // Data ready is the first bit of the Interrupt Enable Register
+    fn enable_data_ready_interrupt(&self) {
         unsafe {
-            let addr = (self.base_register_address as *mut u8).add(_INTERRUPT_ENABLE_REGISTER);
-            addr.write_volatile(1 << 0)
+            // Data ready is the first bit of the Interrupt Enable Register.
+            self.register(INTERRUPT_ENABLE_REGISTER)
+                .write_volatile(1 << 0);
+            // OUT2 connects the interrupt output of the chip on PCs, qemu doesn't care.
+            let mcr = self.register(MODEM_CONTROL_REGISTER);
+            mcr.write_volatile(mcr.read_volatile() | MODEM_CONTROL_OUT2);
         }
     }
 
-    fn write_transmitter_holding_reg(&self, byte: u8) {
+    fn set_loopback(&self, enabled: bool) {
         unsafe {
-            let addr = (self.base_register_address as *mut u8).add(TRANSMITTER_HOLDING_REGISTER);
-            addr.write_volatile(byte);
+            let mcr = self.register(MODEM_CONTROL_REGISTER);
+            let value = mcr.read_volatile() & !MODEM_CONTROL_LOOPBACK;
+            mcr.write_volatile(value | if enabled { MODEM_CONTROL_LOOPBACK } else { 0 });
+        }
+    }
+
+    /// Returns the next received byte, if any.
+    fn read(&self) -> Option<u8> {
+        unsafe {
+            // Data ready is the first bit of the Line Status Register.
+            if self.register(LINE_STATUS_REGISTER).read_volatile() & (1 << 0) != 0 {
+                Some(self.register(RECEIVER_BUFFER_REGISTER).read_volatile())
+            } else {
+                None
+            }
         }
     }
 
-    fn _read_transmitter_holding_reg(&self) -> u8 {
+    fn write_transmitter_holding_reg(&self, byte: u8) {
         unsafe {
             let addr = (self.base_register_address as *mut u8).add(TRANSMITTER_HOLDING_REGISTER);
-            addr.read_volatile()
+            addr.write_volatile(byte);
         }
     }
 }
@@ -58,9 +128,40 @@ impl Ns16550Inner {
 impl Ns16550 {
     pub const fn new(base: usize) -> Self {
         Self {
-            inner: Lock::new(Ns16550Inner::new(base)),
+            inner: IrqLock::new(Ns16550Inner::new(base)),
+            irq_line: None,
+            rx: IrqLock::new(RxBuffer::new()),
         }
     }
+
+    /// The received bytes raise interrupts on `irq_line` once the interrupt controller is up,
+    /// they are buffered until [`Ns16550::read`] picks them up.
+    pub const fn with_rx_interrupt(base: usize, irq_line: u32) -> Self {
+        Self {
+            irq_line: Some(irq_line),
+            ..Self::new(base)
+        }
+    }
+
+    /// Returns the oldest received byte that wasn't read yet.
+    pub fn read(&self) -> Option<u8> {
+        self.rx.lock(|rx| rx.pop())
+    }
+
+    /// The bytes written are received back instead of being sent on the line, to test the
+    /// receiving side.
+    pub fn set_loopback(&self, enabled: bool) {
+        self.inner.lock(|ns16550| ns16550.set_loopback(enabled))
+    }
+
+    fn handle_rx_interrupt(&self) {
+        self.inner.lock(|ns16550| {
+            // Reading them all clears the interrupt.
+            while let Some(byte) = ns16550.read() {
+                self.rx.lock(|rx| rx.push(byte));
+            }
+        })
+    }
 }
 
 impl Driver for Ns16550 {
@@ -69,6 +170,16 @@ impl Driver for Ns16550 {
         self.inner
             .lock(|ns16550| Some((ns16550.base_register_address, 0b111)))
     }
+
+    fn init_irq(&'static self) -> Result<(), Error> {
+        if let Some(line) = self.irq_line {
+            irq::register(line, Trigger::Level, move |_| self.handle_rx_interrupt())?;
+            self.inner
+                .lock(|ns16550| ns16550.enable_data_ready_interrupt());
+        }
+
+        Ok(())
+    }
 }
 
 impl Console for Ns16550 {
@@ -84,8 +195,8 @@ impl Console for Ns16550 {
 pub(super) const MATCHER: ConsoleMatcher = ConsoleMatcher {
     compatibles: &["ns16550a"],
     constructor: |reg| {
-        Ok(Box::new(Ns16550::new(
+        Ok(Box::new(Ns16550::new(hal::mm::phys_to_virt(
             reg.next().unwrap().starting_address as usize,
-        )))
+        ))))
     },
 };
@@ -2,6 +2,7 @@ use super::Console;
 use super::ConsoleMatcher;
 use super::Driver;
 
+use crate::hal;
 use crate::utils::lock::Lock;
 
 pub extern crate alloc;
@@ -65,8 +66,8 @@ impl Pl011 {
 pub(super) const MATCHER: ConsoleMatcher = ConsoleMatcher {
     compatibles: &["arm,pl011"],
     constructor: |reg| {
-        Ok(Box::new(Pl011::new(
+        Ok(Box::new(Pl011::new(hal::mm::phys_to_virt(
             reg.next().unwrap().starting_address as usize,
-        )))
+        ))))
     },
 };
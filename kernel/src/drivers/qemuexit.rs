@@ -3,6 +3,9 @@ use qemu_exit::QEMUExit;
 
 use super::Driver;
 
+#[cfg(target_arch = "riscv64")]
+use crate::hal;
+
 #[cfg(target_arch = "riscv64")]
 const RISCV64_BASE_ADDRESS: usize = 0x100000;
 
@@ -18,9 +21,13 @@ pub struct QemuExit {
 impl QemuExit {
     pub fn new() -> Self {
         #[cfg(target_arch = "riscv64")]
-        return Self {
-            address: RISCV64_BASE_ADDRESS,
-            inner: qemu_exit::RISCV64::new(RISCV64_BASE_ADDRESS as u64),
+        return {
+            let address = hal::mm::phys_to_virt(RISCV64_BASE_ADDRESS);
+
+            Self {
+                address,
+                inner: qemu_exit::RISCV64::new(address as u64),
+            }
         };
 
         #[cfg(target_arch = "aarch64")]
@@ -7,6 +7,12 @@ pub enum Error {
     Allocator(hal_core::mm::AllocatorError),
     Hal(hal_core::Error),
     SetLoggerError(log::SetLoggerError),
+    /// The region overlaps with one already in the address space.
+    OverlappingRegion(hal_core::AddressRange),
+    /// A handler is already registered for this interrupt line.
+    IrqLineInUse(u32),
+    /// No handler is registered for this interrupt line.
+    NoIrqHandler(u32),
 }
 
 impl From<fdt::FdtError> for Error {
@@ -1,6 +1,7 @@
 use core::iter::Iterator;
 
 use crate::globals;
+use crate::mm::PageOwner;
 use crate::Error;
 
 use goblin;
@@ -9,7 +10,7 @@ use goblin::elf::program_header::program_header64::ProgramHeader;
 use goblin::elf::program_header::*;
 
 use crate::hal;
-use hal_core::mm::{PAddr, PageAlloc, PageMap, Permissions, VAddr};
+use hal_core::mm::{PAddr, Permissions, VAddr};
 
 fn align_down(addr: usize, page_size: usize) -> usize {
     let page_mask = !(page_size - 1);
@@ -64,7 +65,8 @@ impl<'a> Elf<'a> {
         }
     }
 
-    pub fn load(&self) -> Result<(), Error> {
+    /// Load the segments of the ELF in `address_space`.
+    pub fn load(&self, address_space: &mut hal::mm::AddressSpace) -> Result<(), Error> {
         let page_size = hal::mm::PAGE_SIZE;
 
         for segment in self.segments() {
@@ -78,14 +80,15 @@ impl<'a> Elf<'a> {
 
             let pages_needed = Self::pages_needed(segment, page_size);
             let physical_pages = globals::PHYSICAL_MEMORY_MANAGER
-                .alloc(pages_needed)
+                .alloc_pages(pages_needed, PageOwner::Executable)
                 .unwrap();
             let virtual_pages = segment.p_paddr as *mut u8;
             let offset_in_page =
                 (virtual_pages as usize) - align_down(virtual_pages as usize, page_size);
 
             let segment_data_src_addr = ((self.data.as_ptr() as usize) + p_offset) as *const u8;
-            let segment_data_dst_addr = (physical_pages + offset_in_page) as *mut u8;
+            let segment_data_dst_addr =
+                hal::mm::phys_to_virt(physical_pages + offset_in_page) as *mut u8;
 
             let segment_data_src: &[u8] =
                 unsafe { core::slice::from_raw_parts(segment_data_src_addr, p_filesz) };
@@ -106,7 +109,7 @@ impl<'a> Elf<'a> {
             for i in 0..pages_needed {
                 let page_offset = i * page_size;
                 // FIXME: No unwrap
-                hal::mm::current()
+                address_space
                     .map(
                         VAddr::new(align_down(virtual_pages as usize, page_size) + page_offset),
                         PAddr::new(physical_pages + page_offset),
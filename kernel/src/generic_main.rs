@@ -4,35 +4,59 @@ use super::drivers::Driver;
 use super::globals;
 
 use crate::hal;
+use crate::irq;
 use crate::mm;
 
 use crate::tests::{self, TestResult};
 
-use log::info;
+use log::{error, info};
 
-pub fn generic_main<const LAUNCH_TESTS: bool>(dt: DeviceTree, hacky_devices: &[&dyn Driver]) -> ! {
+pub fn generic_main<const LAUNCH_TESTS: bool>(
+    dt: DeviceTree,
+    hacky_devices: &[&'static dyn Driver],
+) -> ! {
     info!("Entered generic_main");
+    if cfg!(feature = "kaslr") {
+        info!("kernel image slid by {:#x}", hal_core::kaslr::slide());
+    }
     let qemu_exit = QemuExit::new();
     let qemu_exit_slice = [&qemu_exit as &dyn Driver];
 
     let devices = hacky_devices.iter().chain(&qemu_exit_slice);
 
     // Memory init
-    globals::PHYSICAL_MEMORY_MANAGER
-        .init_from_device_tree(&dt)
-        .unwrap();
+    if let Err(e) = globals::PHYSICAL_MEMORY_MANAGER.init_from_device_tree(&dt) {
+        error!("failed to initialize the physical memory manager: {:?}", e);
+        qemu_exit.exit_failure();
+    }
     mm::map_address_space(&dt, devices).expect("failed to map the addres space");
+    hal::irq::set_page_fault_handler(mm::handle_page_fault);
+
+    info!("{:?}", globals::PHYSICAL_MEMORY_MANAGER.stats());
+    info!(
+        "physical memory map:\n{}",
+        globals::PHYSICAL_MEMORY_MANAGER.memory_map(&dt)
+    );
 
     // Driver stuff
     // let _drvmgr = DriverManager::with_devices(&dt).unwrap();
 
-    hal::irq::init_irq_chip((), &globals::PHYSICAL_MEMORY_MANAGER)
+    let irq_chip_node = dt
+        .interrupt_controller()
+        .expect("the device tree has no interrupt controller");
+    hal::irq::init_irq_chip(&irq_chip_node, dt.fdt(), &globals::PHYSICAL_MEMORY_MANAGER)
         .expect("initialization of irq chip failed");
+    hal::irq::set_irq_handler(irq::dispatch);
+    for device in hacky_devices {
+        device
+            .init_irq()
+            .expect("failed to set up the interrupts of a device");
+    }
 
     hal::cpu::unmask_interrupts();
 
     if LAUNCH_TESTS {
-        match tests::launch() {
+        match tests::launch(dt) {
             TestResult::Success => qemu_exit.exit_success(),
             TestResult::Failure => qemu_exit.exit_failure(),
         }
@@ -1,6 +1,9 @@
+use crate::hal;
 use crate::mm;
 
 pub static PHYSICAL_MEMORY_MANAGER: mm::PhysicalMemoryManager = mm::PhysicalMemoryManager::new();
+pub static VIRTUAL_MEMORY_MANAGER: mm::VirtualMemoryManager =
+    mm::VirtualMemoryManager::new(hal::mm::KERNEL_VIRT_START, hal::mm::KERNEL_VIRT_END);
 
 pub enum KernelState {
     EarlyInit,
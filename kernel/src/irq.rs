@@ -0,0 +1,70 @@
+//! Interrupt handlers of the drivers, one per line of the interrupt controller. The hal calls
+//! [`dispatch`] with every interrupt it claims, the line is completed once its handler returns.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use crate::hal;
+use crate::utils::lock::IrqLock;
+use crate::Error;
+use hal_core::irq::{Trigger, DEFAULT_PRIORITY};
+
+use log::warn;
+
+type Handler = Arc<dyn Fn(u32) + Send + Sync>;
+
+static HANDLERS: IrqLock<BTreeMap<u32, Handler>> = IrqLock::new(BTreeMap::new());
+
+/// Route the interrupts of `line` to `handler` and enable the line, a line has a single handler.
+pub fn register(
+    line: u32,
+    trigger: Trigger,
+    handler: impl Fn(u32) + Send + Sync + 'static,
+) -> Result<(), Error> {
+    let handler: Handler = Arc::new(handler);
+    let chip = hal::irq::irq_chip()?;
+
+    HANDLERS.lock(|handlers| {
+        if handlers.contains_key(&line) {
+            return Err(Error::IrqLineInUse(line));
+        }
+
+        chip.set_trigger(line, trigger)?;
+        chip.set_priority(line, DEFAULT_PRIORITY)?;
+        // Interrupts are masked while the lock is held, the line can't fire before its handler
+        // is in the map. A line that couldn't be enabled doesn't keep a handler.
+        chip.enable(line)?;
+        handlers.insert(line, handler);
+
+        Ok(())
+    })
+}
+
+/// Disable `line` and forget its handler.
+pub fn unregister(line: u32) -> Result<(), Error> {
+    let chip = hal::irq::irq_chip()?;
+
+    HANDLERS.lock(|handlers| {
+        if !handlers.contains_key(&line) {
+            return Err(Error::NoIrqHandler(line));
+        }
+
+        chip.disable(line)?;
+        handlers.remove(&line);
+
+        Ok(())
+    })
+}
+
+/// Run the handler of `line`. Lines without one are disabled so that they don't fire again.
+pub fn dispatch(line: u32) {
+    // The handler may (un)register lines, it runs without the lock.
+    match HANDLERS.lock(|handlers| handlers.get(&line).cloned()) {
+        Some(handler) => handler(line),
+        None => {
+            warn!("no handler for irq line {}, disabling it", line);
+            if let Err(e) = hal::irq::irq_chip().and_then(|chip| chip.disable(line)) {
+                warn!("failed to disable irq line {}: {:?}", line, e);
+            }
+        }
+    }
+}
@@ -23,6 +23,7 @@ pub mod device_tree;
 pub mod executable;
 pub mod generic_main;
 pub mod globals;
+pub mod irq;
 pub mod kernel_console;
 pub mod mm;
 mod panic;
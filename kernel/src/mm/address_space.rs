@@ -0,0 +1,285 @@
+use crate::globals;
+use crate::hal;
+use crate::Error;
+
+use super::PageOwner;
+
+use hal_core::mm::{FaultAccess, PAddr, PageAlloc, PageFault, Permissions, VAddr};
+use hal_core::AddressRange;
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use log::warn;
+
+/// What backs the pages of a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Zeroed pages allocated on the first access, for stacks and heaps.
+    Anonymous,
+}
+
+/// A range of the address space the faults are resolved in.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub range: AddressRange,
+    pub perms: Permissions,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    /// Whether `fault` is an access the region allows.
+    fn allows(&self, fault: &PageFault) -> bool {
+        let needed = match fault.access {
+            FaultAccess::Read => Permissions::READ,
+            FaultAccess::Write => Permissions::WRITE,
+            FaultAccess::Execute => Permissions::EXECUTE,
+        };
+
+        self.perms.contains(needed) && (!fault.user || self.perms.contains(Permissions::USER))
+    }
+}
+
+/// The address space being translated, the page faults are resolved with its regions.
+static CURRENT: AtomicPtr<AddressSpace> = AtomicPtr::new(ptr::null_mut());
+
+/// A pagetable along with the list of the regions whose pages are mapped on demand.
+/// The pages of the regions may be shared with other address spaces, they are reference counted
+/// by the physical memory manager.
+pub struct AddressSpace {
+    pagetable: hal::mm::AddressSpace,
+    /// Sorted and non-overlapping.
+    regions: Vec<Region>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, Error> {
+        Ok(Self {
+            pagetable: hal::mm::AddressSpace::new(&globals::PHYSICAL_MEMORY_MANAGER)?,
+            regions: Vec::new(),
+        })
+    }
+
+    pub fn pagetable(&mut self) -> &mut hal::mm::AddressSpace {
+        &mut self.pagetable
+    }
+
+    /// Nothing is mapped until the pages of `region` are accessed.
+    pub fn add_region(&mut self, region: Region) -> Result<(), Error> {
+        assert_eq!(region.range.start % hal::mm::PAGE_SIZE, 0);
+        assert_eq!(region.range.end % hal::mm::PAGE_SIZE, 0);
+
+        let index = self
+            .regions
+            .partition_point(|other| other.range.end <= region.range.start);
+        if let Some(next) = self.regions.get(index) {
+            if next.range.start < region.range.end {
+                return Err(Error::OverlappingRegion(region.range));
+            }
+        }
+
+        self.regions.insert(index, region);
+
+        Ok(())
+    }
+
+    pub fn region(&self, va: VAddr) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|region| region.range.contains(va.val))
+    }
+
+    /// Switch to this address space, it mustn't move until another one or the kernel's pagetable
+    /// is activated.
+    pub fn activate(&mut self) {
+        self.pagetable.activate();
+        CURRENT.store(self as *mut AddressSpace, Ordering::Release);
+    }
+
+    /// Returns a copy of this address space sharing all the pages faulted in so far, the writable
+    /// ones become copy-on-write in both.
+    pub fn duplicate(&mut self) -> Result<AddressSpace, Error> {
+        let mut copy = AddressSpace::new()?;
+        copy.regions = self.regions.clone();
+
+        if let Err(e) = self.share_pages_with(&mut copy) {
+            copy.destroy();
+            return Err(e);
+        }
+
+        Ok(copy)
+    }
+
+    fn share_pages_with(&mut self, copy: &mut AddressSpace) -> Result<(), Error> {
+        let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+        for region in self.regions.iter() {
+            for page in region.range.iter_pages(hal::mm::PAGE_SIZE) {
+                let va = VAddr::new(page);
+                let (paddr, perms) = match self.pagetable.translate(va) {
+                    Some(mapping) => mapping,
+                    None => continue,
+                };
+
+                let shared_perms = if perms.contains(Permissions::WRITE) {
+                    (perms - Permissions::WRITE) | Permissions::COPY_ON_WRITE
+                } else {
+                    perms
+                };
+
+                pmm.share_page(paddr.val)?;
+                if let Err(e) = copy.pagetable.map(va, paddr, shared_perms, pmm) {
+                    let _ = pmm.release_page(paddr.val);
+                    return Err(e.into());
+                }
+
+                if shared_perms != perms {
+                    self.pagetable.protect(
+                        AddressRange::with_size(page, hal::mm::PAGE_SIZE),
+                        shared_perms,
+                        pmm,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Resolve `fault` if it is in a region that allows the access: a missing page of an anonymous
+    /// region is mapped zeroed, a write to a copy-on-write page gets its own copy.
+    /// Returns false if the access is illegal.
+    fn resolve_fault(&mut self, fault: &PageFault) -> bool {
+        let page = VAddr::new(fault.addr.val & !(hal::mm::PAGE_SIZE - 1));
+        let region = match self.region(page) {
+            Some(region) if region.allows(fault) => *region,
+            _ => return false,
+        };
+
+        match self.pagetable.translate(page) {
+            None => match region.kind {
+                RegionKind::Anonymous => self.map_zeroed(page, region.perms),
+            },
+            Some((shared, perms))
+                if fault.access == FaultAccess::Write
+                    && perms.contains(Permissions::COPY_ON_WRITE) =>
+            {
+                self.copy_on_write(page, shared, region.perms)
+            }
+            // The page is already there but doesn't allow the access.
+            Some(_) => false,
+        }
+    }
+
+    fn map_zeroed(&mut self, page: VAddr, perms: Permissions) -> bool {
+        let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+        let paddr = match pmm.alloc_pages(1, PageOwner::AddressSpace) {
+            Ok(paddr) => paddr,
+            Err(_) => return false,
+        };
+        unsafe {
+            ptr::write_bytes(
+                hal::mm::phys_to_virt(paddr) as *mut u8,
+                0,
+                hal::mm::PAGE_SIZE,
+            )
+        };
+
+        if self
+            .pagetable
+            .map(page, PAddr::new(paddr), perms, pmm)
+            .is_err()
+        {
+            let _ = pmm.dealloc(paddr, 1);
+            return false;
+        }
+
+        true
+    }
+
+    /// Give `page` a private copy of `shared`, unless nobody else references it anymore in which
+    /// case it is just made writable again.
+    fn copy_on_write(&mut self, page: VAddr, shared: PAddr, perms: Permissions) -> bool {
+        let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+        let paddr = if pmm.page_refcount(shared.val).ok() == Some(1) {
+            shared.val
+        } else {
+            let copy = match pmm.alloc_pages(1, PageOwner::AddressSpace) {
+                Ok(copy) => copy,
+                Err(_) => return false,
+            };
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    hal::mm::phys_to_virt(shared.val) as *const u8,
+                    hal::mm::phys_to_virt(copy) as *mut u8,
+                    hal::mm::PAGE_SIZE,
+                )
+            };
+
+            copy
+        };
+
+        if self
+            .pagetable
+            .map(page, PAddr::new(paddr), perms, pmm)
+            .is_err()
+        {
+            if paddr != shared.val {
+                let _ = pmm.dealloc(paddr, 1);
+            }
+            return false;
+        }
+
+        if paddr != shared.val {
+            let _ = pmm.release_page(shared.val);
+        }
+
+        true
+    }
+
+    /// Drop the references on the pages faulted in and give back the pagetable, it mustn't be the
+    /// active address space.
+    pub fn destroy(self) {
+        let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+        let AddressSpace {
+            mut pagetable,
+            regions,
+        } = self;
+        for region in regions {
+            for page in region.range.iter_pages(hal::mm::PAGE_SIZE) {
+                match pagetable.unmap(VAddr::new(page), pmm) {
+                    Ok(Some(paddr)) => {
+                        let _ = pmm.release_page(paddr.val);
+                    }
+                    Ok(None) => {}
+                    // Still mapped in a table shared with the kernel, the page has to stay.
+                    Err(e) => warn!("leaking the page mapped at {:#x}: {:?}", page, e),
+                }
+            }
+        }
+
+        pagetable.destroy(pmm);
+    }
+}
+
+/// Switch back to the kernel's pagetable, the faults are no longer resolved with the regions of
+/// the address space that was active.
+pub fn activate_kernel_pagetable() {
+    CURRENT.store(ptr::null_mut(), Ordering::Release);
+    hal::mm::activate_kernel_pagetable();
+}
+
+/// Resolve `fault` with the regions of the current address space, registered as the page fault
+/// handler of the HAL.
+pub fn handle_page_fault(fault: &PageFault) -> bool {
+    let current = CURRENT.load(Ordering::Acquire);
+
+    // Safety: the active address space stays where it is until it is switched away from.
+    match unsafe { current.as_mut() } {
+        Some(address_space) => address_space.resolve_fault(fault),
+        None => false,
+    }
+}
@@ -1,48 +1,227 @@
+//! Binary buddy allocator handing out naturally aligned blocks of `1 << order` pages.
+//!
+//! Memory is taken from the [`PhysicalMemoryManager`](super::PhysicalMemoryManager) in arenas of
+//! `1 << MAX_ORDER` pages, aligned on their size so that the buddy of a block can be computed by
+//! flipping a single bit of its address. The first page of each arena holds a bitmap telling
+//! which blocks are free, it is never handed out.
+
+use super::physical_memory_manager::PageOwner;
 use crate::globals;
-use crate::hal::mm::PAGE_SIZE;
-use hal_core::mm::PageAlloc;
+use crate::hal::{self, mm::PAGE_SIZE};
+use hal_core::mm::AllocatorError;
+
+use core::mem;
+use core::ptr;
 
-use core::alloc::{GlobalAlloc, Layout};
+/// Blocks are at most of order `MAX_ORDER - 1`, arenas are of order `MAX_ORDER`.
+pub const MAX_ORDER: usize = 9;
 
-use log::warn;
+const ARENA_PAGES: usize = 1 << MAX_ORDER;
+const ARENA_SIZE: usize = ARENA_PAGES * PAGE_SIZE;
 
-pub struct BinaryBuddyAllocator;
+/// One bit per block of each order, there are `ARENA_PAGES >> order` blocks of a given order.
+const FREE_BITMAP_WORDS: usize = 2 * ARENA_PAGES / u64::BITS as usize;
 
-unsafe impl Sync for BinaryBuddyAllocator {}
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct ArenaHeader {
+    free: [u64; FREE_BITMAP_WORDS],
+}
 
-unsafe impl GlobalAlloc for BinaryBuddyAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        if unsafe { globals::STATE.is_earlyinit() } {
-            panic!("Something tried to allocate before earlyinit is over o_O");
+const _: () = assert!(mem::size_of::<ArenaHeader>() <= PAGE_SIZE);
+
+pub struct BinaryBuddyAllocator {
+    free_lists: [*mut FreeBlock; MAX_ORDER],
+    arena_count: usize,
+    free_pages: usize,
+}
+
+impl BinaryBuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [ptr::null_mut(); MAX_ORDER],
+            arena_count: 0,
+            free_pages: 0,
         }
+    }
 
-        assert!(layout.size() > 0);
+    pub fn arena_count(&self) -> usize {
+        self.arena_count
+    }
 
-        // The keen eye might notice this is just giving away pages ^^
-        // TODO: some much to be done
-        //   - actually implement a buddy allocator ^^
-        //   - be thread-safe
-        //   - disable interrupts when entering, then re-enable
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
 
-        let page_count = if layout.size() <= PAGE_SIZE {
-            1
-        } else {
-            layout.size() / PAGE_SIZE + 1
+    /// Allocate a block of `1 << order` pages, aligned on its size.
+    pub fn alloc(&mut self, order: usize) -> Result<usize, AllocatorError> {
+        if order >= MAX_ORDER {
+            return Err(AllocatorError::OutOfMemory);
+        }
+
+        let block_order = match (order..MAX_ORDER).find(|&o| !self.free_lists[o].is_null()) {
+            Some(block_order) => block_order,
+            None => {
+                self.grow()?;
+                MAX_ORDER - 1
+            }
         };
-        globals::PHYSICAL_MEMORY_MANAGER
-            .alloc(page_count)
-            .unwrap_or(0usize) as *mut u8
+
+        let block = self.pop(block_order);
+
+        // Split the block until it has the requested size, putting the upper halves back in the
+        // free lists.
+        let mut current_order = block_order;
+        while current_order > order {
+            current_order -= 1;
+            self.push(block + (PAGE_SIZE << current_order), current_order);
+        }
+
+        self.free_pages -= 1 << order;
+
+        Ok(block)
     }
 
-    unsafe fn dealloc(&self, _: *mut u8, _: Layout) {
-        warn!("[WARNING] dealloc is not implemented yet, freeing memory isn't supported by the allocator");
+    /// Give back a block previously returned by [`Self::alloc`] with the same `order`.
+    pub fn dealloc(&mut self, block: usize, order: usize) -> Result<(), AllocatorError> {
+        assert!(order < MAX_ORDER);
+
+        if self.is_inside_free_block(block, order) {
+            return Err(AllocatorError::DoubleFree(block));
+        }
+
+        self.free_pages += 1 << order;
+
+        // Merge with our buddy as long as it is free, the first page of the arena is never free so
+        // this stops at most at order MAX_ORDER - 1.
+        let arena = Self::arena_of(block);
+        let (mut block, mut order) = (block, order);
+        while order < MAX_ORDER - 1 {
+            let buddy = arena + ((block - arena) ^ (PAGE_SIZE << order));
+            if !self.is_free(buddy, order) {
+                break;
+            }
+
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+
+        self.push(block, order);
+
+        Ok(())
     }
-}
 
-#[global_allocator]
-static HEAP: BinaryBuddyAllocator = BinaryBuddyAllocator;
+    fn grow(&mut self) -> Result<(), AllocatorError> {
+        let arena = hal::mm::phys_to_virt(globals::PHYSICAL_MEMORY_MANAGER.alloc_pages_aligned(
+            ARENA_PAGES,
+            ARENA_SIZE,
+            PageOwner::KernelHeap,
+        )?);
+
+        unsafe {
+            (arena as *mut ArenaHeader).write(ArenaHeader {
+                free: [0; FREE_BITMAP_WORDS],
+            })
+        };
+
+        // Everything but the header page is free: that is one block of each order, each starting
+        // right after the previous one.
+        for order in 0..MAX_ORDER {
+            self.push(arena + (PAGE_SIZE << order), order);
+        }
+
+        self.arena_count += 1;
+        self.free_pages += ARENA_PAGES - 1;
 
-#[alloc_error_handler]
-fn binary_buddy_oom(_layout: Layout) -> ! {
-    panic!("The binary buddy allocator has oomed, this system has gobbled up all the RAM\nWe are dOOMed !!!");
+        Ok(())
+    }
+
+    fn arena_of(block: usize) -> usize {
+        block & !(ARENA_SIZE - 1)
+    }
+
+    /// Returns the header of the arena containing `block` and the index of the bit tracking it.
+    fn bit_of(block: usize, order: usize) -> (&'static mut ArenaHeader, usize) {
+        let arena = Self::arena_of(block);
+        let header = unsafe { (arena as *mut ArenaHeader).as_mut().unwrap() };
+
+        let bits_of_lower_orders = 2 * ARENA_PAGES - ((2 * ARENA_PAGES) >> order);
+        let index_in_order = (block - arena) / (PAGE_SIZE << order);
+
+        (header, bits_of_lower_orders + index_in_order)
+    }
+
+    /// Whether `block` or one of the blocks containing it is free, a freed block may have been
+    /// merged with its buddies since.
+    fn is_inside_free_block(&self, block: usize, order: usize) -> bool {
+        let arena = Self::arena_of(block);
+
+        (order..MAX_ORDER).any(|order| {
+            let parent = arena + ((block - arena) & !((PAGE_SIZE << order) - 1));
+            self.is_free(parent, order)
+        })
+    }
+
+    fn is_free(&self, block: usize, order: usize) -> bool {
+        let (header, bit) = Self::bit_of(block, order);
+
+        header.free[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn set_free(&mut self, block: usize, order: usize, free: bool) {
+        let (header, bit) = Self::bit_of(block, order);
+
+        if free {
+            header.free[bit / 64] |= 1 << (bit % 64);
+        } else {
+            header.free[bit / 64] &= !(1 << (bit % 64));
+        }
+    }
+
+    fn push(&mut self, block: usize, order: usize) {
+        let node = block as *mut FreeBlock;
+        let head = self.free_lists[order];
+
+        unsafe {
+            node.write(FreeBlock {
+                next: head,
+                prev: ptr::null_mut(),
+            });
+            if let Some(head) = head.as_mut() {
+                head.prev = node;
+            }
+        }
+
+        self.free_lists[order] = node;
+        self.set_free(block, order, true);
+    }
+
+    fn pop(&mut self, order: usize) -> usize {
+        let block = self.free_lists[order] as usize;
+        self.remove(block, order);
+
+        block
+    }
+
+    fn remove(&mut self, block: usize, order: usize) {
+        let node = block as *mut FreeBlock;
+
+        unsafe {
+            let (next, prev) = ((*node).next, (*node).prev);
+
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => self.free_lists[order] = next,
+            }
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
+        }
+
+        self.set_free(block, order, false);
+    }
 }
@@ -0,0 +1,96 @@
+use crate::hal;
+
+use hal_core::mm::AllocatorError;
+use hal_core::AddressRange;
+
+use core::mem::{self, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::slice;
+
+/// Hands out memory from a single range of physical memory before the physical memory manager is
+/// online, nothing is ever given back.
+pub struct EarlyAllocator {
+    range: AddressRange,
+    next: usize,
+}
+
+impl EarlyAllocator {
+    /// `range` is physical memory nobody else uses, it is accessed through the direct map.
+    pub fn new(range: AddressRange) -> Self {
+        Self {
+            range,
+            next: range.start,
+        }
+    }
+
+    /// Bytes to set aside for an [`EarlyVec`] of `capacity` items, alignment included.
+    pub fn size_for<T>(capacity: usize) -> usize {
+        capacity * mem::size_of::<T>() + mem::align_of::<T>()
+    }
+
+    pub fn alloc_vec<T: Copy>(&mut self, capacity: usize) -> Result<EarlyVec<T>, AllocatorError> {
+        let start = hal_core::mm::align_up(self.next, mem::align_of::<T>());
+        let end = start + capacity * mem::size_of::<T>();
+        if end > self.range.end {
+            return Err(AllocatorError::OutOfMemory);
+        }
+        self.next = end;
+
+        let items = unsafe {
+            slice::from_raw_parts_mut(
+                hal::mm::phys_to_virt(start) as *mut MaybeUninit<T>,
+                capacity,
+            )
+        };
+
+        Ok(EarlyVec { items, len: 0 })
+    }
+}
+
+/// A vector in memory from the [`EarlyAllocator`], it can't grow past the capacity it was
+/// allocated with.
+pub struct EarlyVec<T: Copy + 'static> {
+    items: &'static mut [MaybeUninit<T>],
+    len: usize,
+}
+
+impl<T: Copy> EarlyVec<T> {
+    pub fn push(&mut self, item: T) -> Result<(), AllocatorError> {
+        self.items
+            .get_mut(self.len)
+            .ok_or(AllocatorError::OutOfMemory)?
+            .write(item);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Remove the item at `index` and put the last one in its place.
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        let item = self[index];
+        let last = self.len - 1;
+        self.swap(index, last);
+        self.len = last;
+
+        item
+    }
+
+    /// Keep the items for good, they are never freed.
+    pub fn leak(self) -> &'static mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr() as *mut T, self.len) }
+    }
+}
+
+impl<T: Copy> Deref for EarlyVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.items.as_ptr() as *const T, self.len) }
+    }
+}
+
+impl<T: Copy> DerefMut for EarlyVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.items.as_mut_ptr() as *mut T, self.len) }
+    }
+}
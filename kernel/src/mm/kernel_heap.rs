@@ -0,0 +1,207 @@
+//! The kernel's global allocator.
+//!
+//! Small objects go to the slab caches, bigger ones get a block from the binary buddy allocator
+//! and what doesn't fit in a buddy arena is directly allocated from the physical memory manager.
+
+use super::binary_buddy_allocator::{BinaryBuddyAllocator, MAX_ORDER};
+use super::physical_memory_manager::PageOwner;
+use super::slab_allocator::{SlabCache, SLAB_SIZES};
+use crate::globals;
+use crate::hal::{self, mm::PAGE_SIZE};
+use crate::utils::lock::IrqLock;
+use hal_core::mm::{self, AllocatorError, PageAlloc};
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently handed out, rounded up to the size actually reserved for each allocation.
+    pub allocated_bytes: usize,
+    /// Number of allocations currently alive.
+    pub allocations: usize,
+    /// Pages used by the slab caches.
+    pub slab_pages: usize,
+    /// Pages reserved by the buddy allocator from the physical memory manager.
+    pub buddy_pages: usize,
+    /// Pages of the buddy allocator that are not handed out.
+    pub buddy_free_pages: usize,
+    /// Pages of allocations too big for the buddy allocator.
+    pub large_pages: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SizeClass {
+    Slab(usize),
+    Buddy(usize),
+    Large(usize),
+}
+
+impl SizeClass {
+    fn from_layout(layout: Layout) -> Self {
+        // Slabs and buddy blocks are aligned on their size, so asking for at least `align` bytes
+        // is enough to honour the alignment.
+        let size = layout.size().max(layout.align());
+
+        if let Some(index) = SLAB_SIZES.iter().position(|&slab_size| slab_size >= size) {
+            return Self::Slab(index);
+        }
+
+        let pages = mm::align_up(size, PAGE_SIZE) / PAGE_SIZE;
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+
+        if order < MAX_ORDER {
+            Self::Buddy(order)
+        } else {
+            Self::Large(pages)
+        }
+    }
+
+    fn reserved_bytes(&self) -> usize {
+        match *self {
+            Self::Slab(index) => SLAB_SIZES[index],
+            Self::Buddy(order) => PAGE_SIZE << order,
+            Self::Large(pages) => pages * PAGE_SIZE,
+        }
+    }
+}
+
+struct HeapInner {
+    buddy: BinaryBuddyAllocator,
+    slabs: [SlabCache; SLAB_SIZES.len()],
+    allocated_bytes: usize,
+    allocations: usize,
+    large_pages: usize,
+}
+
+// Safety: the raw pointers only point to memory owned by the heap, and the heap is only accessed
+// behind its lock.
+unsafe impl Send for HeapInner {}
+
+impl HeapInner {
+    const fn new() -> Self {
+        const EMPTY_CACHE: SlabCache = SlabCache::new(0);
+        let mut slabs = [EMPTY_CACHE; SLAB_SIZES.len()];
+
+        let mut i = 0;
+        while i < SLAB_SIZES.len() {
+            slabs[i] = SlabCache::new(SLAB_SIZES[i]);
+            i += 1;
+        }
+
+        Self {
+            buddy: BinaryBuddyAllocator::new(),
+            slabs,
+            allocated_bytes: 0,
+            allocations: 0,
+            large_pages: 0,
+        }
+    }
+
+    fn alloc(&mut self, layout: Layout) -> Result<*mut u8, AllocatorError> {
+        let class = SizeClass::from_layout(layout);
+
+        let ptr = match class {
+            SizeClass::Slab(index) => self.slabs[index].alloc(&mut self.buddy)?,
+            SizeClass::Buddy(order) => self.buddy.alloc(order)? as *mut u8,
+            SizeClass::Large(pages) => {
+                let alignment = layout.align().max(PAGE_SIZE);
+                let base = globals::PHYSICAL_MEMORY_MANAGER.alloc_pages_aligned(
+                    pages,
+                    alignment,
+                    PageOwner::KernelHeap,
+                )?;
+                self.large_pages += pages;
+
+                hal::mm::phys_to_virt(base) as *mut u8
+            }
+        };
+
+        self.allocated_bytes += class.reserved_bytes();
+        self.allocations += 1;
+
+        Ok(ptr)
+    }
+
+    fn dealloc(&mut self, ptr: *mut u8, layout: Layout) -> Result<(), AllocatorError> {
+        let class = SizeClass::from_layout(layout);
+
+        match class {
+            SizeClass::Slab(index) => self.slabs[index].dealloc(ptr, &mut self.buddy)?,
+            SizeClass::Buddy(order) => self.buddy.dealloc(ptr as usize, order)?,
+            SizeClass::Large(pages) => {
+                globals::PHYSICAL_MEMORY_MANAGER
+                    .dealloc(hal::mm::virt_to_phys(ptr as usize), pages)?;
+                self.large_pages -= pages;
+            }
+        }
+
+        self.allocated_bytes -= class.reserved_bytes();
+        self.allocations -= 1;
+
+        Ok(())
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            allocated_bytes: self.allocated_bytes,
+            allocations: self.allocations,
+            slab_pages: self.slabs.iter().map(|cache| cache.slab_count()).sum(),
+            buddy_pages: self.buddy.arena_count() << MAX_ORDER,
+            buddy_free_pages: self.buddy.free_pages(),
+            large_pages: self.large_pages,
+        }
+    }
+}
+
+pub struct KernelHeap {
+    inner: IrqLock<HeapInner>,
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        Self {
+            inner: IrqLock::new(HeapInner::new()),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.inner.lock(|heap| heap.stats())
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if unsafe { globals::STATE.is_earlyinit() } {
+            panic!("Something tried to allocate before earlyinit is over o_O");
+        }
+
+        assert!(layout.size() > 0);
+
+        self.inner
+            .lock(|heap| heap.alloc(layout))
+            .unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(e) = self.inner.lock(|heap| heap.dealloc(ptr, layout)) {
+            panic!(
+                "kernel heap failed to free {:p} ({:?}): {:?}",
+                ptr, layout, e
+            );
+        }
+    }
+}
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap::new();
+
+/// Usage statistics of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+    HEAP.stats()
+}
+
+#[alloc_error_handler]
+fn kernel_heap_oom(_layout: Layout) -> ! {
+    panic!("The kernel heap has oomed, this system has gobbled up all the RAM\nWe are dOOMed !!!");
+}
@@ -1,27 +1,52 @@
 mod physical_memory_manager;
-pub use physical_memory_manager::PhysicalMemoryManager;
+pub use physical_memory_manager::{
+    MemoryMap, MemoryMapEntry, MemoryMapKind, PageOwner, PhysicalMemoryManager, PlacedReservation,
+    PmmStats,
+};
+
+mod address_space;
+pub use address_space::{
+    activate_kernel_pagetable, handle_page_fault, AddressSpace, Region, RegionKind,
+};
+
+mod virtual_memory_manager;
+pub use virtual_memory_manager::{vfree, vmalloc, KernelStack, VirtualMemoryManager};
 
 mod binary_buddy_allocator;
+mod early_allocator;
+pub(crate) use early_allocator::{EarlyAllocator, EarlyVec};
+mod kernel_heap;
+mod slab_allocator;
+pub use kernel_heap::{heap_stats, HeapStats};
 
 use crate::device_tree::DeviceTree;
 use crate::globals;
 
 use crate::hal;
 use crate::Error;
-use hal_core::mm::{NullPageAllocator, PageAlloc, PageMap, Permissions, VAddr};
+use hal_core::mm::{AllocatorError, PageMap};
 use hal_core::AddressRange;
 
 use crate::drivers;
 use drivers::Driver;
 
-use arrayvec::ArrayVec;
+use alloc::vec::Vec;
 use core::iter;
+use core::ops::DerefMut;
 
 use log::debug;
 
 extern "C" {
     pub static KERNEL_START: usize;
     pub static KERNEL_END: usize;
+    static TEXT_START: usize;
+    static TEXT_END: usize;
+    static RODATA_START: usize;
+    static RODATA_END: usize;
+    static DATA_START: usize;
+    static DATA_END: usize;
+    static STACK_END: usize;
+    static STACK_START: usize;
 }
 
 pub fn is_kernel_page(base: usize) -> bool {
@@ -35,6 +60,7 @@ pub fn is_kernel_page(base: usize) -> bool {
     base >= kernel_start && base < kernel_end
 }
 
+/// Physical memory holding the kernel image.
 pub fn kernel_memory_region() -> AddressRange {
     let (start, end) = unsafe {
         (
@@ -42,118 +68,294 @@ pub fn kernel_memory_region() -> AddressRange {
             crate::utils::external_symbol_value(&KERNEL_END),
         )
     };
+    let phys_start = hal::mm::virt_to_phys(start);
 
-    AddressRange::new(start..end)
+    AddressRange::new(phys_start..phys_start + (end - start))
 }
 
+/// Whether some of the page at `base` is reserved by the device tree, statically or dynamically.
 pub fn is_reserved_page(base: usize, device_tree: &DeviceTree) -> bool {
-    let mut is_res = false;
+    let overlaps =
+        |range: AddressRange| range.start < base + hal::mm::PAGE_SIZE && base < range.end;
 
+    let mut is_res = false;
     device_tree.for_all_reserved_memory_regions(|regions| {
-        is_res = regions
-            .map(|(start, size)| (start, size)) // this is a weird hack to fix a type error.
-            .any(|(region_start, region_size)| {
-                base >= region_start && base <= (region_start + region_size)
-            })
+        for reserved in regions {
+            if overlaps(reserved.range) {
+                is_res = true;
+            }
+        }
     });
 
     is_res
+        || globals::PHYSICAL_MEMORY_MANAGER
+            .dynamic_reservations()
+            .iter()
+            .any(|placed| overlaps(placed.memory.range))
+}
+
+/// A list of disjoint ranges that [`exclude_range`] can cut holes in, a `Vec` or an [`EarlyVec`]
+/// before the kernel heap is up.
+pub(crate) trait RangeList: DerefMut<Target = [AddressRange]> {
+    fn push(&mut self, range: AddressRange) -> Result<(), AllocatorError>;
+    fn swap_remove(&mut self, index: usize) -> AddressRange;
+}
+
+impl RangeList for Vec<AddressRange> {
+    fn push(&mut self, range: AddressRange) -> Result<(), AllocatorError> {
+        Vec::push(self, range);
+        Ok(())
+    }
+
+    fn swap_remove(&mut self, index: usize) -> AddressRange {
+        Vec::swap_remove(self, index)
+    }
+}
+
+impl RangeList for EarlyVec<AddressRange> {
+    fn push(&mut self, range: AddressRange) -> Result<(), AllocatorError> {
+        EarlyVec::push(self, range)
+    }
+
+    fn swap_remove(&mut self, index: usize) -> AddressRange {
+        EarlyVec::swap_remove(self, index)
+    }
 }
 
-fn map_kernel_rwx() -> (
+/// Remove `excluded` from `ranges`, splitting the ranges it is in the middle of. The order of the
+/// ranges isn't kept.
+pub(crate) fn exclude_range<L: RangeList>(
+    ranges: &mut L,
+    excluded: AddressRange,
+) -> Result<(), AllocatorError> {
+    let mut i = 0;
+    while i < ranges.len() {
+        let range = ranges[i];
+        let before = (range.start < excluded.start)
+            .then(|| AddressRange::new(range.start..range.end.min(excluded.start)));
+        let after = (excluded.end < range.end)
+            .then(|| AddressRange::new(range.start.max(excluded.end)..range.end));
+
+        match (before, after) {
+            (Some(before), Some(after)) => {
+                ranges[i] = before;
+                ranges.push(after)?;
+            }
+            (Some(remaining), _) | (None, Some(remaining)) => ranges[i] = remaining,
+            (None, None) => {
+                ranges.swap_remove(i);
+                continue;
+            }
+        }
+
+        i += 1;
+    }
+
+    Ok(())
+}
+
+/// The sections of the kernel image as (read-only, read-write, read-execute) ranges, each of them
+/// starts on a page boundary.
+pub(crate) fn map_kernel_rwx() -> (
     impl Iterator<Item = AddressRange>,
     impl Iterator<Item = AddressRange>,
     impl Iterator<Item = AddressRange>,
 ) {
-    let page_size = hal::mm::PAGE_SIZE;
-    let kernel_start = unsafe { crate::utils::external_symbol_value(&KERNEL_START) };
-    let kernel_end = unsafe { crate::utils::external_symbol_value(&KERNEL_END) };
-    let kernel_end_align = ((kernel_end + page_size - 1) / page_size) * page_size;
+    let section = |start: &usize, end: &usize| {
+        AddressRange::new(
+            crate::utils::external_symbol_value(start)..crate::utils::external_symbol_value(end),
+        )
+    };
 
-    let rwx_entries = iter::once(AddressRange::new(kernel_start..kernel_end_align));
+    let (text, rodata, data, stack) = unsafe {
+        (
+            section(&TEXT_START, &TEXT_END),
+            section(&RODATA_START, &RODATA_END),
+            section(&DATA_START, &DATA_END),
+            section(&STACK_END, &STACK_START),
+        )
+    };
 
-    (iter::empty(), iter::empty(), rwx_entries)
+    (
+        iter::once(rodata),
+        [data, stack].into_iter(),
+        iter::once(text),
+    )
 }
 
 pub fn map_address_space<'a, I: Iterator<Item = &'a &'a dyn Driver>>(
     device_tree: &DeviceTree,
     drivers: I,
 ) -> Result<(), Error> {
-    let mut r_entries = ArrayVec::<AddressRange, 128>::new();
-    let mut rw_entries = ArrayVec::<AddressRange, 128>::new();
-    let mut rwx_entries = ArrayVec::<AddressRange, 128>::new();
-    let mut pre_allocated_entries = ArrayVec::<AddressRange, 1024>::new();
-
-    // Add entries/descriptors in the pagetable for all of accessible memory regions.
-    // That way in the future, mapping those entries won't require any memory allocations,
-    // just settings the entry to valid and filling up the bits.
+    let mut r_entries = Vec::new();
+    let mut rw_entries = Vec::new();
+    let mut rx_entries = Vec::new();
+    let mut device_entries = Vec::new();
+
+    // All of DRAM is in the direct map, mostly with large blocks, so that the pages handed out by
+    // the physical memory manager are accessible without touching the pagetable. The no-map
+    // reservations are left out, not even speculative accesses may reach them, and the kernel
+    // image is read-only.
+    let mut dram = Vec::new();
     device_tree.for_all_memory_regions(|regions| {
-        regions.for_each(|(base, size)| {
-            pre_allocated_entries
-                .try_push(AddressRange::with_size(base, size))
-                .unwrap();
-        });
+        dram.extend(
+            regions
+                .filter(|&(_, size)| size > 0)
+                .map(|(base, size)| AddressRange::with_size(base, size)),
+        )
+    });
+    let mut no_map = Vec::new();
+    device_tree.for_all_reserved_memory_regions(|reserved| {
+        no_map.extend(
+            reserved
+                .filter(|reserved| reserved.no_map)
+                .map(|reserved| reserved.range),
+        )
     });
+    no_map.extend(
+        globals::PHYSICAL_MEMORY_MANAGER
+            .dynamic_reservations()
+            .iter()
+            .filter(|placed| placed.memory.no_map)
+            .map(|placed| placed.memory.range),
+    );
+    for range in no_map {
+        debug!("leaving no-map region {:X?} out of the direct map", range);
+        let pages =
+            AddressRange::new(hal::mm::align_down(range.start)..hal::mm::align_up(range.end));
+        exclude_range(&mut dram, pages)?;
+    }
+    // The kernel image is only written through its own mapping, which is W^X. Its alias in the
+    // direct map is read-only so that it can't be used to get around that.
+    let kernel_image = kernel_memory_region();
+    let kernel_pages = AddressRange::new(
+        hal::mm::align_down(kernel_image.start)..hal::mm::align_up(kernel_image.end),
+    );
+    exclude_range(&mut dram, kernel_pages)?;
+    r_entries.push(AddressRange::new(
+        hal::mm::phys_to_virt(kernel_pages.start)..hal::mm::phys_to_virt(kernel_pages.end),
+    ));
+    rw_entries.extend(dram.into_iter().map(|range| {
+        AddressRange::new(hal::mm::phys_to_virt(range.start)..hal::mm::phys_to_virt(range.end))
+    }));
+    let dt_region = device_tree.memory_region();
     debug!(
         "adding region containing the device tree to rw entries {:X?}",
-        device_tree.memory_region()
+        dt_region
+    );
+    rw_entries.push(
+        AddressRange::with_size(hal::mm::phys_to_virt(dt_region.start), dt_region.size())
+            .round_up_to_page(hal::mm::PAGE_SIZE),
     );
-    rw_entries
-        .try_push(
-            device_tree
-                .memory_region()
-                .round_up_to_page(hal::mm::PAGE_SIZE),
-        )
-        .unwrap();
 
-    let (kernel_r, kernel_rw, kernel_rwx) = map_kernel_rwx();
+    let (kernel_r, kernel_rw, kernel_rx) = map_kernel_rwx();
     r_entries.extend(kernel_r);
     rw_entries.extend(kernel_rw);
-    rwx_entries.extend(kernel_rwx);
+    rx_entries.extend(kernel_rx);
 
     for drv in drivers {
         if let Some((base, len)) = drv.get_address_range() {
             let len = hal::mm::align_up(len);
             debug!(
-                "adding driver memory region to RW entries: [{:X}; {:X}]",
+                "adding driver memory region to device entries: [{:X}; {:X}]",
                 base,
                 base + len
             );
-            rw_entries
-                .try_push(AddressRange::with_size(base, len))
-                .unwrap();
+            device_entries.push(AddressRange::with_size(base, len));
         }
     }
 
     debug!("r_entries: {:X?}", r_entries);
     debug!("rw_entries: {:X?}", rw_entries);
-    debug!("rwx_entries: {:X?}", rwx_entries);
-    debug!("pre_allocated_entries: {:X?}", pre_allocated_entries);
+    debug!("rx_entries: {:X?}", rx_entries);
+    debug!("device_entries: {:X?}", device_entries);
 
     hal::mm::prefill_pagetable(
         r_entries.into_iter(),
         rw_entries.into_iter(),
-        rwx_entries.into_iter(),
-        pre_allocated_entries.into_iter(),
+        rx_entries.into_iter(),
+        device_entries.into_iter(),
         &globals::PHYSICAL_MEMORY_MANAGER,
     )?;
 
-    // All pmm pages are located in DRAM so they are already in the pagetable (they are part of
-    // the pre_allocated_entries).
-    // Therefore no allocations will be made, pass the NullPageAllocator.
-    globals::PHYSICAL_MEMORY_MANAGER.used_pages(|page| {
-        hal::mm::current()
-            .identity_map(
-                VAddr::new(page),
-                Permissions::READ | Permissions::WRITE,
-                &NullPageAllocator,
-            )
-            .unwrap();
-    });
-
     hal::mm::enable_paging();
+    debug!("kernel pagetable:\n{}", hal::mm::current().layout());
+
+    // The text was writable until now, make sure it isn't anymore. The probe writes back what was
+    // already there in case it doesn't fault.
+    let text_start = unsafe { crate::utils::external_symbol_value(&TEXT_START) };
+    assert!(
+        hal::irq::write_faults(text_start),
+        "the kernel text is still writable"
+    );
+    assert!(
+        hal::irq::write_faults(hal::mm::phys_to_virt(hal::mm::virt_to_phys(text_start))),
+        "the kernel text is writable through the direct map"
+    );
 
     unsafe { globals::STATE = globals::KernelState::MmuEnabledInit };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel_tests::*;
+
+    use alloc::vec;
+
+    #[test_case]
+    fn exclude_range_remove_in_the_middle(_ctx: &mut TestContext) {
+        let mut ranges = vec![AddressRange::new(0x0usize..0x1000usize)];
+        exclude_range(&mut ranges, AddressRange::new(0x500usize..0x600usize)).unwrap();
+
+        assert_eq!(
+            ranges,
+            [
+                AddressRange::new(0x0usize..0x500usize),
+                AddressRange::new(0x600usize..0x1000usize)
+            ]
+        );
+    }
+
+    #[test_case]
+    fn exclude_range_remove_beginning(_ctx: &mut TestContext) {
+        let mut ranges = vec![AddressRange::new(0x100usize..0x1000usize)];
+        exclude_range(&mut ranges, AddressRange::new(0x0usize..0x200usize)).unwrap();
+
+        assert_eq!(ranges, [AddressRange::new(0x200usize..0x1000usize)]);
+    }
+
+    #[test_case]
+    fn exclude_range_remove_ending(_ctx: &mut TestContext) {
+        let mut ranges = vec![AddressRange::new(0x100usize..0x1000usize)];
+        exclude_range(&mut ranges, AddressRange::new(0x800usize..0x1000usize)).unwrap();
+
+        assert_eq!(ranges, [AddressRange::new(0x100usize..0x800usize)]);
+    }
+
+    #[test_case]
+    fn exclude_range_overlaps_exactly(_ctx: &mut TestContext) {
+        let mut ranges = vec![AddressRange::new(0x400_000usize..0x800_000usize)];
+        exclude_range(
+            &mut ranges,
+            AddressRange::new(0x400_000usize..0x800_000usize),
+        )
+        .unwrap();
+
+        assert!(ranges.is_empty());
+    }
+
+    #[test_case]
+    fn exclude_range_overlap_with_exact_beginning(_ctx: &mut TestContext) {
+        let mut ranges = vec![AddressRange::new(0x400_000usize..0x800_000usize)];
+        exclude_range(
+            &mut ranges,
+            AddressRange::new(0x400_000usize..0x401_000usize),
+        )
+        .unwrap();
+
+        assert_eq!(ranges, [AddressRange::new(0x401_000usize..0x800_000usize)]);
+    }
+}
@@ -1,16 +1,24 @@
-use crate::device_tree::DeviceTree;
+use crate::device_tree::{DeviceTree, DynamicReservation, ReservedMemory};
 use crate::globals;
 use crate::hal;
 use crate::mm;
 use core::mem;
 use hal_core::{
-    mm::{AllocatorError, NullPageAllocator, PageAlloc, PageMap, Permissions, VAddr},
+    mm::{
+        AllocatorError, MemoryType, NullPageAllocator, PAddr, PageAlloc, PageMap, Permissions,
+        VAddr,
+    },
     AddressRange,
 };
 
 use hal::mm::PAGE_SIZE;
 
-use log::debug;
+use super::early_allocator::{EarlyAllocator, EarlyVec};
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use log::{debug, warn};
 use spin::mutex::Mutex;
 
 #[derive(Debug, PartialEq, Eq)]
@@ -20,6 +28,95 @@ pub enum PageKind {
     Free,
 }
 
+/// What an allocation is used for, shown in the memory map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageOwner {
+    /// Allocated through [`PageAlloc`], mostly by the pagetables of the HAL.
+    Untagged,
+    KernelHeap,
+    Vmalloc,
+    KernelStack,
+    /// Pages faulted in the regions of an address space.
+    AddressSpace,
+    /// Segments of a loaded executable.
+    Executable,
+}
+
+/// Counters of the physical memory manager, in pages.
+#[derive(Debug, Clone, Copy)]
+pub struct PmmStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    pub allocated_pages: usize,
+    /// Pages holding the metadata of the physical memory manager itself.
+    pub metadata_pages: usize,
+    /// Length of the biggest run of physically contiguous free pages.
+    pub largest_free_run: usize,
+    /// Number of runs of physically contiguous free pages, freed pages are merged with the runs
+    /// next to them.
+    pub free_runs: usize,
+}
+
+/// What the pages of a [`MemoryMapEntry`] are used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapKind {
+    /// The kernel image, the device tree blob and the reserved regions are left out of the
+    /// physical memory manager.
+    KernelImage,
+    DeviceTree,
+    Reserved {
+        no_map: bool,
+    },
+    Metadata,
+    Free,
+    Allocated(PageOwner),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub range: AddressRange,
+    pub kind: MemoryMapKind,
+}
+
+/// The memory regions found in the device tree and what each part of them is used for, sorted by
+/// address.
+#[derive(Debug)]
+pub struct MemoryMap {
+    pub regions: Vec<AddressRange>,
+    pub entries: Vec<MemoryMapEntry>,
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in self.regions.iter() {
+            writeln!(
+                f,
+                "memory region [{:#014x}; {:#014x}[ ({} KiB)",
+                region.start,
+                region.end,
+                region.size() / 1024
+            )?;
+
+            for entry in self
+                .entries
+                .iter()
+                .filter(|entry| region.contains(entry.range.start))
+            {
+                writeln!(
+                    f,
+                    "  [{:#014x}; {:#014x}[ {:>8} pages  {:?}",
+                    entry.range.start,
+                    entry.range.end,
+                    entry.range.count_pages(PAGE_SIZE),
+                    entry.kind
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
 /// Holds data about each physical page in the system.
 #[derive(Debug)]
 pub struct PhysicalPage {
@@ -28,6 +125,17 @@ pub struct PhysicalPage {
 
     /// Page is last of a contiguous allocation of pages.
     last: bool,
+
+    /// Number of mappings sharing an allocated page, see [`PhysicalMemoryManager::share_page`].
+    refcount: usize,
+    /// Only meaningful on allocated pages.
+    owner: PageOwner,
+
+    /// Number of pages in the free run, only meaningful on the first and last pages of a run.
+    run_pages: usize,
+    /// Links of the free list the run is in, only meaningful on the first page of a run.
+    next_run: usize,
+    prev_run: usize,
 }
 
 impl PhysicalPage {
@@ -39,11 +147,19 @@ impl PhysicalPage {
         self.kind == PageKind::Allocated
     }
 
-    fn set_allocated(&mut self) {
+    fn set_allocated(&mut self, owner: PageOwner) {
         self.kind = PageKind::Allocated;
+        self.refcount = 1;
+        self.owner = owner;
+    }
+
+    fn set_free(&mut self) {
+        self.kind = PageKind::Free;
+        self.last = false;
+        self.refcount = 0;
     }
 
-    fn _is_last(&self) -> bool {
+    fn is_last(&self) -> bool {
         self.last
     }
 
@@ -52,25 +168,295 @@ impl PhysicalPage {
     }
 }
 
+/// There is one list for each power of two, list `n` holds the free runs of `[2^n; 2^(n+1)[`
+/// pages.
+const FREE_LIST_COUNT: usize = usize::BITS as usize;
+
+/// Marks the end of a free list.
+const NO_PAGE: usize = usize::MAX;
+
+fn floor_log2(n: usize) -> usize {
+    (usize::BITS - 1 - n.leading_zeros()) as usize
+}
+
+fn ceil_log2(n: usize) -> usize {
+    n.next_power_of_two().trailing_zeros() as usize
+}
+
+/// A dynamic reservation of the device tree, once the memory for it has been set aside.
+#[derive(Debug, Clone, Copy)]
+pub struct PlacedReservation {
+    pub name: &'static str,
+    pub memory: ReservedMemory,
+}
+
+/// The metadata of all pages, sorted by address, and the lists of runs of free pages.
+///
+/// A free run is a maximal range of free pages that are physically contiguous, its first and last
+/// pages know the length of the run so that freed pages can be merged with their neighbours in
+/// constant time.
+#[derive(Debug)]
+struct PmmInner {
+    metadata: &'static mut [PhysicalPage],
+    free_runs: [usize; FREE_LIST_COUNT],
+    /// Bit `n` is set if `free_runs[n]` isn't empty.
+    non_empty_lists: usize,
+    reservations: &'static [PlacedReservation],
+    /// Memory from the early allocator, it holds `reservations`.
+    early_memory: Option<AddressRange>,
+}
+
+impl PmmInner {
+    fn is_contiguous(&self, index: usize, next_index: usize) -> bool {
+        next_index == index + 1
+            && self.metadata[index].base + PAGE_SIZE == self.metadata[next_index].base
+    }
+
+    fn insert_run(&mut self, head: usize, page_count: usize) {
+        let order = floor_log2(page_count);
+        let next = self.free_runs[order];
+
+        self.metadata[head].run_pages = page_count;
+        self.metadata[head + page_count - 1].run_pages = page_count;
+        self.metadata[head].next_run = next;
+        self.metadata[head].prev_run = NO_PAGE;
+        if next != NO_PAGE {
+            self.metadata[next].prev_run = head;
+        }
+
+        self.free_runs[order] = head;
+        self.non_empty_lists |= 1 << order;
+    }
+
+    fn remove_run(&mut self, head: usize) {
+        let order = floor_log2(self.metadata[head].run_pages);
+        let (next, prev) = (self.metadata[head].next_run, self.metadata[head].prev_run);
+
+        if prev == NO_PAGE {
+            self.free_runs[order] = next;
+        } else {
+            self.metadata[prev].next_run = next;
+        }
+        if next != NO_PAGE {
+            self.metadata[next].prev_run = prev;
+        }
+
+        if self.free_runs[order] == NO_PAGE {
+            self.non_empty_lists &= !(1 << order);
+        }
+    }
+
+    fn build_free_lists(&mut self) {
+        let mut i = 0;
+        while i < self.metadata.len() {
+            if self.metadata[i].is_used() {
+                i += 1;
+                continue;
+            }
+
+            let head = i;
+            while i + 1 < self.metadata.len()
+                && !self.metadata[i + 1].is_used()
+                && self.is_contiguous(i, i + 1)
+            {
+                i += 1;
+            }
+
+            self.insert_run(head, i - head + 1);
+            i += 1;
+        }
+    }
+
+    /// Find a free run able to hold `page_count` pages aligned on `alignment`.
+    /// Returns the index of the first page of the run and the index of the first page to allocate.
+    fn find_run(&self, page_count: usize, alignment: usize) -> Option<(usize, usize)> {
+        if alignment <= PAGE_SIZE {
+            // All the runs in those lists are big enough, just take the first one.
+            let min_order = ceil_log2(page_count);
+            let candidates = self
+                .non_empty_lists
+                .checked_shr(min_order as u32)
+                .unwrap_or(0);
+
+            if candidates != 0 {
+                let head = self.free_runs[min_order + candidates.trailing_zeros() as usize];
+                return Some((head, head));
+            }
+        }
+
+        // Otherwise the runs of the lists that might fit need to be looked at one by one.
+        for order in floor_log2(page_count)..FREE_LIST_COUNT {
+            let mut head = self.free_runs[order];
+            while head != NO_PAGE {
+                let run = &self.metadata[head];
+                let skipped_pages =
+                    (hal_core::mm::align_up(run.base, alignment) - run.base) / PAGE_SIZE;
+
+                if skipped_pages + page_count <= run.run_pages {
+                    return Some((head, head + skipped_pages));
+                }
+
+                head = run.next_run;
+            }
+        }
+
+        None
+    }
+
+    fn alloc(
+        &mut self,
+        page_count: usize,
+        alignment: usize,
+        owner: PageOwner,
+    ) -> Result<usize, AllocatorError> {
+        let (head, first) = self
+            .find_run(page_count, alignment)
+            .ok_or(AllocatorError::OutOfMemory)?;
+        let run_end = head + self.metadata[head].run_pages;
+        let end = first + page_count;
+
+        // Give back what's left on both sides of the allocation.
+        self.remove_run(head);
+        if first > head {
+            self.insert_run(head, first - head);
+        }
+        if end < run_end {
+            self.insert_run(end, run_end - end);
+        }
+
+        self.metadata[first..end]
+            .iter_mut()
+            .for_each(|page| page.set_allocated(owner));
+        self.metadata[end - 1].set_last();
+
+        Ok(self.metadata[first].base)
+    }
+
+    fn page_index(&self, base: usize) -> Result<usize, AllocatorError> {
+        self.metadata
+            .binary_search_by_key(&base, |page| page.base)
+            .map_err(|_| AllocatorError::InvalidAddress(base))
+    }
+
+    /// Returns the metadata of `base`, which must be a single page allocation.
+    fn single_page(&mut self, base: usize) -> Result<&mut PhysicalPage, AllocatorError> {
+        let index = self.page_index(base)?;
+        let page = &mut self.metadata[index];
+
+        if !page.is_allocated() {
+            return Err(AllocatorError::DoubleFree(base));
+        }
+        if !page.is_last() {
+            return Err(AllocatorError::PartialFree(base));
+        }
+
+        Ok(page)
+    }
+
+    /// Check that the `page_count` pages at `base` are exactly an allocation, returns the index of
+    /// its first page.
+    fn check_allocation(&self, base: usize, page_count: usize) -> Result<usize, AllocatorError> {
+        let first_page_index = self.page_index(base)?;
+
+        if !self.metadata[first_page_index].is_allocated() {
+            return Err(AllocatorError::DoubleFree(base));
+        }
+
+        // The page before us must not be part of the same allocation, otherwise we are trying to
+        // free the tail of an allocation.
+        if first_page_index > 0 {
+            let previous = &self.metadata[first_page_index - 1];
+            if previous.is_allocated() && !previous.is_last() && previous.base + PAGE_SIZE == base {
+                return Err(AllocatorError::PartialFree(base));
+            }
+        }
+
+        if page_count == 0 || first_page_index + page_count > self.metadata.len() {
+            return Err(AllocatorError::PartialFree(base));
+        }
+        let end = first_page_index + page_count;
+
+        let allocation = &self.metadata[first_page_index..end];
+        for (i, page) in allocation.iter().enumerate() {
+            if !page.is_allocated() {
+                return Err(AllocatorError::DoubleFree(page.base));
+            }
+
+            if page.base != base + i * PAGE_SIZE {
+                return Err(AllocatorError::PartialFree(base));
+            }
+
+            // Only the last page of the range may (and must) have the last marker.
+            if page.is_last() != (i == page_count - 1) {
+                return Err(AllocatorError::PartialFree(base));
+            }
+        }
+
+        Ok(first_page_index)
+    }
+
+    /// The pages holding the metadata and the allocated ones.
+    fn used_pages<F: FnMut(usize)>(&self, f: F) {
+        let metadata = &self.metadata;
+
+        let metadata_start = hal::mm::virt_to_phys((&metadata[0] as *const PhysicalPage) as usize);
+        let metadata_last =
+            hal::mm::virt_to_phys((&metadata[metadata.len() - 1] as *const PhysicalPage) as usize);
+
+        let metadata_pages = (metadata_start..=metadata_last).step_by(PAGE_SIZE);
+        let allocated_pages = metadata
+            .iter()
+            .filter(|page| page.is_allocated())
+            .map(|page| page.base);
+
+        metadata_pages.chain(allocated_pages).for_each(f);
+    }
+
+    fn dealloc(&mut self, base: usize, page_count: usize) -> Result<(), AllocatorError> {
+        let first_page_index = self.check_allocation(base, page_count)?;
+        let end = first_page_index + page_count;
+
+        self.metadata[first_page_index..end]
+            .iter_mut()
+            .for_each(|page| page.set_free());
+
+        // Merge with the free runs right before and after us.
+        let (mut head, mut run_end) = (first_page_index, end);
+        if head > 0 && !self.metadata[head - 1].is_used() && self.is_contiguous(head - 1, head) {
+            let previous_head = head - self.metadata[head - 1].run_pages;
+            self.remove_run(previous_head);
+            head = previous_head;
+        }
+        if run_end < self.metadata.len()
+            && !self.metadata[run_end].is_used()
+            && self.is_contiguous(run_end - 1, run_end)
+        {
+            let next_run_pages = self.metadata[run_end].run_pages;
+            self.remove_run(run_end);
+            run_end += next_run_pages;
+        }
+
+        self.insert_run(head, run_end - head);
+
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct PhysicalMemoryManager {
-    metadata: Mutex<&'static mut [PhysicalPage]>,
+    inner: Mutex<PmmInner>,
 }
 
 impl PhysicalMemoryManager {
-    fn count_pages(regions: &[Option<AddressRange>]) -> usize {
-        let total_memory_bytes: usize = regions
-            .iter()
-            .filter_map(|maybe_region| maybe_region.map(|region| region.size()))
-            .sum();
+    fn count_pages(regions: &[AddressRange]) -> usize {
+        let total_memory_bytes: usize = regions.iter().map(|region| region.size()).sum();
 
         total_memory_bytes / PAGE_SIZE
     }
 
-    fn find_large_region(regions: &[Option<AddressRange>], minimum_size: usize) -> Option<usize> {
+    fn find_large_region(regions: &[AddressRange], minimum_size: usize) -> Option<usize> {
         regions
             .iter()
-            .flatten()
             .find(|region| region.size() >= minimum_size)
             .map(|region| region.start)
     }
@@ -94,96 +480,177 @@ impl PhysicalMemoryManager {
             kind,
             base: phys_addr,
             last: false,
+            refcount: 0,
+            owner: PageOwner::Untagged,
+            run_pages: 0,
+            next_run: NO_PAGE,
+            prev_run: NO_PAGE,
         }
     }
 
-    fn exclude_range<const MAX_REGIONS: usize>(
-        regions: &mut [Option<AddressRange>; MAX_REGIONS],
-        excluded: AddressRange,
-    ) {
-        let (excl_start, excl_end) = (excluded.start, excluded.end);
-
-        assert!(excl_start < excl_end);
-
-        for i in 0..MAX_REGIONS {
-            if regions[i].is_none() {
-                continue;
-            }
-            let region = regions[i].unwrap();
+    /// Call `f` with the ranges the device tree keeps away from the allocator.
+    fn for_all_static_exclusions<F: FnMut(AddressRange)>(device_tree: &DeviceTree, mut f: F) {
+        f(mm::kernel_memory_region());
+        f(device_tree.memory_region());
 
-            if region.start == excl_start && region.end == excl_end {
-                // Perfect overlap between the region to be excluded and the current region, just remove the region.
-                regions[i] = None;
-            } else if (region.start < excl_start && excl_start < region.end)
-                && (region.start < excl_end && excl_end < region.end)
-            {
-                // Region to be excluded is the middle of the current region.
-                let new_region = AddressRange {
-                    start: excl_end,
-                    end: region.end,
-                };
-                regions[i] = Some(AddressRange::new(region.start..excl_start));
-
-                // The exclusion in the middle causes a split of the current region, put the new region (the end part) somewhere there is a none.
-                *regions
-                    .iter_mut()
-                    .find(|maybe_region| maybe_region.is_none())
-                    .expect("regions array is too small, increase MAX_REGIONS") = Some(new_region);
-            } else if region.contains(excl_end) {
-                // Region to be removed is at the left (at the beginning) of the current region.
-                regions[i] = Some(AddressRange::new(excl_end..region.end));
-            } else if region.contains(excl_start) {
-                // Region to be removed is at the right (at the end) of the current region.
-                regions[i] = Some(AddressRange::new(region.start..excl_start));
-            }
-        }
+        // Reusable regions are kept too, nothing could give them back to their device otherwise.
+        device_tree.for_all_reserved_memory_regions(|reserved_regions| {
+            reserved_regions.for_each(|reserved| f(reserved.range))
+        });
     }
 
-    fn available_memory_regions<const MAX_REGIONS: usize>(
-        device_tree: &DeviceTree,
-    ) -> [Option<AddressRange>; MAX_REGIONS] {
-        // First put all regions in the array.
-        let mut all_regions = [None; MAX_REGIONS];
+    /// Find `size` bytes of memory outside of the static exclusions, before there is anywhere to
+    /// keep track of the free regions.
+    fn find_early_memory(device_tree: &DeviceTree, size: usize) -> Option<AddressRange> {
+        let mut found = None;
+
         device_tree.for_all_memory_regions(|regions| {
-            regions.enumerate().for_each(|(i, (base, size))| {
-                if i == MAX_REGIONS - 1 {
-                    panic!(
-                        "found more regions in the device tree than this has been compiled to fit"
-                    );
+            found = regions.filter(|&(_, len)| len > 0).find_map(|(base, len)| {
+                let mut start = hal::mm::align_up(base);
+
+                while start + size <= base + len {
+                    let candidate = AddressRange::with_size(start, size);
+                    let mut overlapping = None;
+                    Self::for_all_static_exclusions(device_tree, |excluded| {
+                        if excluded.start < candidate.end && candidate.start < excluded.end {
+                            overlapping = Some(excluded);
+                        }
+                    });
+
+                    match overlapping {
+                        None => return Some(candidate),
+                        Some(excluded) => start = hal::mm::align_up(excluded.end),
+                    }
                 }
 
-                all_regions[i] = Some(AddressRange {
-                    start: base,
-                    end: base + size,
-                });
-            });
+                None
+            })
         });
 
-        Self::exclude_range(&mut all_regions, mm::kernel_memory_region());
+        found
+    }
 
-        Self::exclude_range(&mut all_regions, device_tree.memory_region());
+    /// Find room for `reservation` in `regions`, inside of its alloc-ranges if it has some.
+    fn place_reservation(
+        regions: &[AddressRange],
+        reservation: &DynamicReservation,
+    ) -> Option<AddressRange> {
+        let size = hal::mm::align_up(reservation.size);
+        // The binding wants a power of two.
+        let alignment = reservation.alignment.max(PAGE_SIZE);
+
+        let fit = |start: usize, end: usize| {
+            let start = hal_core::mm::align_up(start, alignment);
+            (start < end && end - start >= size).then(|| AddressRange::with_size(start, size))
+        };
 
-        device_tree.for_all_reserved_memory_regions(|reserved_regions| {
-            reserved_regions.for_each(|(base, size)| {
-                Self::exclude_range(&mut all_regions, AddressRange::with_size(base, size))
+        regions
+            .iter()
+            .find_map(|region| match reservation.alloc_ranges() {
+                None => fit(region.start, region.end),
+                Some(mut allowed) => allowed.find_map(|allowed| {
+                    fit(region.start.max(allowed.start), region.end.min(allowed.end))
+                }),
             })
+    }
+
+    /// The memory regions of the device tree without the static exclusions and the dynamic
+    /// reservations, which are placed along the way. Both live in memory from the early
+    /// allocator, taken from the first range that is free.
+    fn available_memory_regions(
+        device_tree: &DeviceTree,
+    ) -> Result<
+        (
+            EarlyVec<AddressRange>,
+            EarlyVec<PlacedReservation>,
+            AddressRange,
+        ),
+        AllocatorError,
+    > {
+        let mut memory_regions = 0;
+        device_tree.for_all_memory_regions(|regions| memory_regions = regions.count());
+        let mut exclusions = 0;
+        Self::for_all_static_exclusions(device_tree, |_| exclusions += 1);
+        let mut dynamic_reservations = 0;
+        device_tree.for_all_dynamic_reservations(|reservations| {
+            dynamic_reservations = reservations.count()
         });
 
-        // Re-align the regions, for exemple things we exclude are not always aligned to a page boundary.
-        all_regions.iter_mut().for_each(|maybe_region| {
-            if let Some(region) = maybe_region {
-                region.start = hal::mm::align_down(region.start);
-                region.end = hal::mm::align_up(region.end);
+        // Each exclusion splits a region in two at most, the early memory is excluded as well.
+        let capacity = memory_regions + exclusions + dynamic_reservations + 1;
+        let early_size = hal::mm::align_up(
+            EarlyAllocator::size_for::<AddressRange>(capacity)
+                + EarlyAllocator::size_for::<PlacedReservation>(dynamic_reservations),
+        );
+        let early_memory = Self::find_early_memory(device_tree, early_size)
+            .ok_or(AllocatorError::NotEnoughMemoryForMetadata)?;
+        let mut early_allocator = EarlyAllocator::new(early_memory);
 
-                *maybe_region = if region.size() > 0 {
-                    Some(*region)
-                } else {
-                    None
-                };
+        let mut all_regions = early_allocator.alloc_vec(capacity)?;
+        let mut placed = early_allocator.alloc_vec(dynamic_reservations)?;
+
+        let mut pushed = Ok(());
+        device_tree.for_all_memory_regions(|regions| {
+            pushed = regions
+                .filter(|&(_, size)| size > 0)
+                .try_for_each(|(base, size)| all_regions.push(AddressRange::with_size(base, size)))
+        });
+        pushed?;
+
+        let mut excluded = Ok(());
+        Self::for_all_static_exclusions(device_tree, |range| {
+            if excluded.is_ok() {
+                excluded = mm::exclude_range(&mut all_regions, range);
             }
         });
+        excluded?;
+        mm::exclude_range(&mut all_regions, early_memory)?;
+
+        // Shrink the regions to whole pages, what we exclude isn't always aligned to a page boundary
+        // and the pages it partially covers can't be handed out.
+        let mut i = 0;
+        while i < all_regions.len() {
+            let (start, end) = (
+                hal::mm::align_up(all_regions[i].start),
+                hal::mm::align_down(all_regions[i].end),
+            );
+
+            if start < end {
+                all_regions[i] = AddressRange::new(start..end);
+                i += 1;
+            } else {
+                all_regions.swap_remove(i);
+            }
+        }
+
+        let mut result = Ok(());
+        device_tree.for_all_dynamic_reservations(|reservations| {
+            result = reservations
+                .filter(|reservation| reservation.size > 0)
+                .try_for_each(|reservation| {
+                    let range = match Self::place_reservation(&all_regions, &reservation) {
+                        Some(range) => range,
+                        None => {
+                            warn!("couldn't place the reservation {}", reservation.name);
+                            return Ok(());
+                        }
+                    };
+
+                    debug!("placed reservation {} at {:X?}", reservation.name, range);
+                    mm::exclude_range(&mut all_regions, range)?;
+                    placed.push(PlacedReservation {
+                        name: reservation.name,
+                        memory: ReservedMemory {
+                            range,
+                            no_map: reservation.no_map,
+                            reusable: reservation.reusable,
+                        },
+                    })
+                })
+        });
+        result?;
 
-        all_regions
+        Ok((all_regions, placed, early_memory))
     }
 
     pub const fn new() -> Self {
@@ -195,26 +662,33 @@ impl PhysicalMemoryManager {
         };
 
         Self {
-            metadata: Mutex::new(metadata),
+            inner: Mutex::new(PmmInner {
+                metadata,
+                free_runs: [NO_PAGE; FREE_LIST_COUNT],
+                non_empty_lists: 0,
+                reservations: &[],
+                early_memory: None,
+            }),
         }
     }
 
     /// Initialize a [`PageAllocator`] from the device tree.
     pub fn init_from_device_tree(&self, device_tree: &DeviceTree) -> Result<(), AllocatorError> {
-        let available_regions = Self::available_memory_regions::<10>(device_tree);
-
-        assert!(
-            available_regions
-                .iter()
-                .flatten()
-                .all(
-                    |region| region.start == hal::mm::align_up(region.start)
-                        && region.end == hal::mm::align_up(region.end)
-                ),
-            "Expected region bounds to be aligned to the page size (won't be possible to allocate pages otherwise)"
-        );
+        let (mut available_regions, reservations, early_memory) =
+            Self::available_memory_regions(device_tree)?;
+
+        // Keep the metadata sorted by address, so that a page can be found with a binary search.
+        available_regions.sort_unstable_by_key(|region| region.start);
+
+        // Pages couldn't be allocated from a region that doesn't start and end on a page boundary.
+        if let Some(region) = available_regions.iter().find(|region| {
+            region.start != hal::mm::align_up(region.start)
+                || region.end != hal::mm::align_up(region.end)
+        }) {
+            return Err(AllocatorError::UnalignedRegion(*region));
+        }
 
-        for (i, reg) in available_regions.iter().flatten().enumerate() {
+        for (i, reg) in available_regions.iter().enumerate() {
             debug!("region {}: {:X?}", i, reg);
         }
 
@@ -225,12 +699,15 @@ impl PhysicalMemoryManager {
         let metadata_addr = Self::find_large_region(&available_regions, metadata_size)
             .ok_or(AllocatorError::NotEnoughMemoryForMetadata)?;
 
-        let metadata: &mut [PhysicalPage] =
-            unsafe { core::slice::from_raw_parts_mut(metadata_addr as *mut _, page_count) };
+        let metadata: &mut [PhysicalPage] = unsafe {
+            core::slice::from_raw_parts_mut(
+                hal::mm::phys_to_virt(metadata_addr) as *mut _,
+                page_count,
+            )
+        };
 
-        let physical_pages = available_regions
+        let mut physical_pages = available_regions
             .iter()
-            .flatten()
             .flat_map(|region| region.iter_pages(PAGE_SIZE))
             .map(|base| {
                 Self::phys_addr_to_physical_page(
@@ -240,151 +717,349 @@ impl PhysicalMemoryManager {
                 )
             });
 
-        let mut count = 0;
-        for (i, page) in physical_pages.enumerate() {
-            metadata[i] = page;
-            count += 1;
+        // One page for each entry of the metadata, anything else means the pages were miscounted.
+        for slot in metadata.iter_mut() {
+            *slot = physical_pages
+                .next()
+                .ok_or(AllocatorError::NotEnoughMemoryForMetadata)?;
+        }
+        if physical_pages.next().is_some() {
+            return Err(AllocatorError::NotEnoughMemoryForMetadata);
         }
-        assert!(count == page_count);
 
-        *self.metadata.lock() = metadata;
+        let mut inner = self.inner.lock();
+        inner.metadata = metadata;
+        inner.reservations = reservations.leak();
+        inner.early_memory = Some(early_memory);
+        inner.build_free_lists();
 
         Ok(())
     }
 
-    pub fn alloc_pages(&self, page_count: usize) -> Result<usize, AllocatorError> {
-        let mut consecutive_pages: usize = 0;
-        let mut first_page_index: usize = 0;
-        let mut last_page_base: usize = 0;
+    /// Allocate `page_count` physically contiguous pages on behalf of `owner`.
+    pub fn alloc_pages(
+        &self,
+        page_count: usize,
+        owner: PageOwner,
+    ) -> Result<usize, AllocatorError> {
+        self.alloc_pages_aligned(page_count, PAGE_SIZE, owner)
+    }
+
+    /// Same as [`Self::alloc_pages`] but the first page is aligned on `alignment` bytes.
+    pub fn alloc_pages_aligned(
+        &self,
+        page_count: usize,
+        alignment: usize,
+        owner: PageOwner,
+    ) -> Result<usize, AllocatorError> {
+        assert!(alignment.is_power_of_two());
+        assert!(page_count > 0);
 
-        let mut metadata = self.metadata.lock();
+        let base = self.inner.lock().alloc(page_count, alignment, owner)?;
+        Self::map_in_direct_map(base, page_count);
 
-        for (i, page) in metadata.iter().enumerate() {
-            if consecutive_pages == 0 {
-                first_page_index = i;
-                last_page_base = page.base;
-            }
+        Ok(base)
+    }
 
-            if page.is_used() {
-                consecutive_pages = 0;
+    /// Give back `page_count` pages starting at `base` to the allocator.
+    /// The range must exactly match a previous allocation, the `last` marker of the pages is used
+    /// to check that. Once the MMU is on, the pages are unmapped from the direct map so that any
+    /// use-after-free faults.
+    pub fn dealloc_pages(&self, base: usize, page_count: usize) -> Result<(), AllocatorError> {
+        // Nothing is unmapped for a bogus range, the pages are still ours until they are freed
+        // below: none of them can become one of the tables the unmapping may need.
+        self.inner.lock().check_allocation(base, page_count)?;
+        Self::unmap_from_direct_map(base, page_count, self)?;
+
+        self.inner.lock().dealloc(base, page_count)
+    }
+
+    /// Map back the pages of a new allocation that were unmapped from the direct map when they
+    /// were last freed.
+    fn map_in_direct_map(base: usize, page_count: usize) {
+        if !unsafe { globals::STATE.is_mmu_enabled() } {
+            return;
+        }
+
+        let pagetable = hal::mm::current();
+        for page in AddressRange::with_size(base, page_count * PAGE_SIZE).iter_pages(PAGE_SIZE) {
+            let va = VAddr::new(hal::mm::phys_to_virt(page));
+            if pagetable.translate(va).is_some() {
                 continue;
             }
 
-            if consecutive_pages > 0 && page.base != last_page_base + PAGE_SIZE {
-                consecutive_pages = 0;
-                continue;
+            // The tables of an unmapped page are kept, nothing needs to be allocated.
+            pagetable
+                .map(
+                    va,
+                    PAddr::new(page),
+                    Permissions::READ | Permissions::WRITE,
+                    MemoryType::NormalCacheable,
+                    &NullPageAllocator,
+                )
+                .expect("failed to map an allocation back in the direct map");
+        }
+    }
+
+    /// Invalidate the direct map entries of pages about to be freed, the blocks they are part of
+    /// are split with tables taken from `allocator`.
+    fn unmap_from_direct_map(
+        base: usize,
+        page_count: usize,
+        allocator: &impl PageAlloc,
+    ) -> Result<(), AllocatorError> {
+        if !unsafe { globals::STATE.is_mmu_enabled() } {
+            return Ok(());
+        }
+
+        let range = AddressRange::with_size(hal::mm::phys_to_virt(base), page_count * PAGE_SIZE);
+        match hal::mm::current().add_invalid_entries(range, allocator) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // The pages stay allocated, they have to be usable.
+                Self::map_in_direct_map(base, page_count);
+
+                match e {
+                    hal_core::Error::Alloc(e) => Err(e),
+                    e => panic!("failed to unmap freed pages from the direct map: {:?}", e),
+                }
             }
+        }
+    }
+
+    /// Take one more reference on the page at `base`, which must have been allocated on its own.
+    /// It is only freed once [`Self::release_page`] dropped all of them, the reference taken by
+    /// the allocation included.
+    pub fn share_page(&self, base: usize) -> Result<(), AllocatorError> {
+        self.inner.lock().single_page(base)?.refcount += 1;
+
+        Ok(())
+    }
+
+    /// Drop a reference on the page at `base`, returns whether it was the last one and the page
+    /// was freed.
+    pub fn release_page(&self, base: usize) -> Result<bool, AllocatorError> {
+        let mut inner = self.inner.lock();
+        let page = inner.single_page(base)?;
+
+        page.refcount -= 1;
+        if page.refcount > 0 {
+            return Ok(false);
+        }
 
-            consecutive_pages += 1;
-            last_page_base = page.base;
+        // Freed before the lock is dropped, a concurrent share_page finds the page free instead of
+        // taking a reference on it. The tables of the direct map come from the locked inner.
+        if let Err(e) = Self::unmap_from_direct_map(base, 1, &LockedPmm(Mutex::new(&mut *inner))) {
+            // Still mapped and allocated, the reference is still there too.
+            inner.single_page(base)?.refcount = 1;
+            return Err(e);
+        }
+        inner.dealloc(base, 1)?;
+
+        Ok(true)
+    }
+
+    pub fn stats(&self) -> PmmStats {
+        let inner = self.inner.lock();
 
-            if consecutive_pages == page_count {
-                metadata[first_page_index..=i]
-                    .iter_mut()
-                    .for_each(|page| page.set_allocated());
-                metadata[i].set_last();
+        let count = |kind: PageKind| {
+            inner
+                .metadata
+                .iter()
+                .filter(|page| page.kind == kind)
+                .count()
+        };
+        let largest_free_run = match inner.non_empty_lists {
+            0 => 0,
+            lists => {
+                // Only the runs of the highest non-empty list can be the biggest.
+                let mut head = inner.free_runs[floor_log2(lists)];
+                let mut largest = 0;
+                while head != NO_PAGE {
+                    largest = largest.max(inner.metadata[head].run_pages);
+                    head = inner.metadata[head].next_run;
+                }
+                largest
+            }
+        };
 
-                return Ok(metadata[first_page_index].base);
+        let mut free_runs = 0;
+        for &list in inner.free_runs.iter() {
+            let mut head = list;
+            while head != NO_PAGE {
+                free_runs += 1;
+                head = inner.metadata[head].next_run;
             }
         }
 
-        Err(AllocatorError::OutOfMemory)
+        PmmStats {
+            total_pages: inner.metadata.len(),
+            free_pages: count(PageKind::Free),
+            allocated_pages: count(PageKind::Allocated),
+            metadata_pages: count(PageKind::Metadata),
+            largest_free_run,
+            free_runs,
+        }
     }
-}
 
-impl PageAlloc for PhysicalMemoryManager {
-    fn alloc(&self, page_count: usize) -> Result<usize, AllocatorError> {
-        // If there is a kernel pagetable, identity map the pages.
-        let first_page = self.alloc_pages(page_count)?;
-
-        if unsafe { globals::STATE.is_mmu_enabled() } {
-            // The mmu is enabled, therefore we already mapped all DRAM into the kernel's pagetable
-            // as invalid entries.
-            // Pagetable must only modify existing entries and not allocate.
-            hal::mm::current()
-                .identity_map_range(
-                    VAddr::new(first_page),
-                    page_count,
-                    Permissions::READ | Permissions::WRITE,
-                    &NullPageAllocator,
+    /// Describe what each part of the memory regions of `device_tree` is used for, pages next to
+    /// each other used for the same thing are merged in a single entry.
+    pub fn memory_map(&self, device_tree: &DeviceTree) -> MemoryMap {
+        let mut regions = Vec::new();
+        device_tree.for_all_memory_regions(|dt_regions| {
+            regions.extend(dt_regions.map(|(base, size)| AddressRange::with_size(base, size)))
+        });
+
+        let mut entries = Vec::new();
+        push_entry(
+            &mut entries,
+            mm::kernel_memory_region(),
+            MemoryMapKind::KernelImage,
+        );
+        push_entry(
+            &mut entries,
+            device_tree.memory_region(),
+            MemoryMapKind::DeviceTree,
+        );
+        device_tree.for_all_reserved_memory_regions(|reserved| {
+            reserved.for_each(|reserved| {
+                push_entry(
+                    &mut entries,
+                    reserved.range,
+                    MemoryMapKind::Reserved {
+                        no_map: reserved.no_map,
+                    },
                 )
-                .unwrap();
+            })
+        });
+        let early_memory = self.inner.lock().early_memory;
+        if let Some(early_memory) = early_memory {
+            push_entry(&mut entries, early_memory, MemoryMapKind::Metadata);
+        }
+        for placed in self.dynamic_reservations() {
+            push_entry(
+                &mut entries,
+                placed.memory.range,
+                MemoryMapKind::Reserved {
+                    no_map: placed.memory.no_map,
+                },
+            );
         }
 
-        Ok(first_page)
-    }
+        entries.extend(self.pages_usage());
+        entries.sort_unstable_by_key(|entry| entry.range.start);
 
-    fn dealloc(&self, _base: usize, _page_count: usize) -> Result<(), AllocatorError> {
-        // TODO:
-        //  - if MMU is on, unmap the page
-        //  - set as free
-        log::warn!("PMM dealloc not yet implemented...");
-        Ok(())
+        MemoryMap { regions, entries }
     }
 
-    fn used_pages<F: FnMut(usize)>(&self, f: F) {
-        let metadata = self.metadata.lock();
+    /// What the pages handed out by the allocator are used for, without the ranges that were
+    /// excluded from it. Pages next to each other used for the same thing are merged.
+    pub fn pages_usage(&self) -> Vec<MemoryMapEntry> {
+        let mut entries = Vec::new();
+
+        // The kernel heap grows with pages from here, the entries can't be allocated while the
+        // lock is held: they are only written in the capacity reserved beforehand.
+        loop {
+            entries.clear();
+
+            let mut complete = true;
+            let inner = self.inner.lock();
+            for page in inner.metadata.iter() {
+                let kind = match page.kind {
+                    PageKind::Metadata => MemoryMapKind::Metadata,
+                    PageKind::Free => MemoryMapKind::Free,
+                    PageKind::Allocated => MemoryMapKind::Allocated(page.owner),
+                };
+                let range = AddressRange::with_size(page.base, PAGE_SIZE);
 
-        let metadata_start = (&metadata[0] as *const PhysicalPage) as usize;
-        let metadata_last = (&metadata[metadata.len() - 1] as *const PhysicalPage) as usize;
+                if !can_merge(&entries, range, kind) && entries.len() == entries.capacity() {
+                    complete = false;
+                    break;
+                }
+                push_entry(&mut entries, range, kind);
+            }
 
-        let metadata_pages = (metadata_start..=metadata_last).step_by(PAGE_SIZE);
-        let allocated_pages = metadata
-            .iter()
-            .filter(|page| page.is_allocated())
-            .map(|page| page.base);
+            if complete {
+                return entries;
+            }
 
-        metadata_pages.chain(allocated_pages).for_each(f);
+            drop(inner);
+            entries.reserve(entries.capacity().max(16));
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::kernel_tests::*;
+    /// The dynamic reservations of the device tree, placed when the allocator was initialized.
+    pub fn dynamic_reservations(&self) -> &'static [PlacedReservation] {
+        self.inner.lock().reservations
+    }
 
-    #[test_case]
-    fn exclude_range_remove_in_the_middle(_ctx: &mut TestContext) {
-        let mut ranges = [Some(AddressRange::new(0x0..0x1000)), None];
-        PhysicalMemoryManager::exclude_range(&mut ranges, (0x500, 0x600));
+    /// The memory set aside for the dynamic reservation `name` of the device tree.
+    pub fn dynamic_reservation(&self, name: &str) -> Option<ReservedMemory> {
+        self.dynamic_reservations()
+            .iter()
+            .find(|placed| placed.name == name)
+            .map(|placed| placed.memory)
+    }
 
-        assert_eq!(ranges[0], Some(AddressRange::new(0x0..0x500)));
-        assert_eq!(ranges[1], Some(AddressRange::new(0x600, 0x1000)));
+    /// Number of references on the page at `base`, 1 if it isn't shared.
+    pub fn page_refcount(&self, base: usize) -> Result<usize, AllocatorError> {
+        Ok(self.inner.lock().single_page(base)?.refcount)
     }
+}
 
-    #[test_case]
-    fn exclude_range_remove_beginning(_ctx: &mut TestContext) {
-        let mut ranges = [Some(AddressRange::new(0x100..0x1000)), None];
-        PhysicalMemoryManager::exclude_range(&mut ranges, (0x0, 0x200));
+fn can_merge(entries: &[MemoryMapEntry], range: AddressRange, kind: MemoryMapKind) -> bool {
+    entries.last().map_or(false, |last| {
+        last.kind == kind && last.range.end == range.start
+    })
+}
 
-        assert_eq!(ranges[0], Some(AddressRange::new(0x200..0x1000)));
-        assert!(ranges[1].is_none());
+/// Append `range` to `entries`, extending the last entry if it ends where `range` starts and has the
+/// same kind.
+fn push_entry(entries: &mut Vec<MemoryMapEntry>, range: AddressRange, kind: MemoryMapKind) {
+    if can_merge(entries, range, kind) {
+        if let Some(last) = entries.last_mut() {
+            last.range.end = range.end;
+        }
+    } else {
+        entries.push(MemoryMapEntry { range, kind });
     }
+}
 
-    #[test_case]
-    fn exclude_range_remove_ending(_ctx: &mut TestContext) {
-        let mut ranges = [Some(AddressRange::new(0x100..0x1000)), None];
-        PhysicalMemoryManager::exclude_range(&mut ranges, (0x800, 0x1000));
+impl PageAlloc for PhysicalMemoryManager {
+    // All of DRAM is in the direct map of the kernel's pagetable, the pages are accessible through
+    // `hal::mm::phys_to_virt` as soon as they are allocated and until they are freed.
+    fn alloc(&self, page_count: usize) -> Result<usize, AllocatorError> {
+        self.alloc_pages(page_count, PageOwner::Untagged)
+    }
 
-        assert_eq!(ranges[0], Some(AddressRange::new(0x100..0x800)));
-        assert!(ranges[1].is_none());
+    fn dealloc(&self, base: usize, page_count: usize) -> Result<(), AllocatorError> {
+        self.dealloc_pages(base, page_count)
     }
 
-    #[test_case]
-    fn exclude_range_overlaps_exactly(_ctx: &mut TestContext) {
-        let mut ranges = [Some(AddressRange::new(0x400_000..0x800_000)), None];
-        PhysicalMemoryManager::exclude_range(&mut ranges, (0x400_000, 0x800_000));
+    fn used_pages<F: FnMut(usize)>(&self, f: F) {
+        self.inner.lock().used_pages(f)
+    }
+}
 
-        assert!(ranges[0].is_none());
-        assert!(ranges[1].is_none());
+/// Hands out the tables of the direct map from a [`PmmInner`] whose lock is already held.
+struct LockedPmm<'a>(Mutex<&'a mut PmmInner>);
+
+impl PageAlloc for LockedPmm<'_> {
+    fn alloc(&self, page_count: usize) -> Result<usize, AllocatorError> {
+        let base = self
+            .0
+            .lock()
+            .alloc(page_count, PAGE_SIZE, PageOwner::Untagged)?;
+        PhysicalMemoryManager::map_in_direct_map(base, page_count);
+
+        Ok(base)
     }
 
-    #[test_case]
-    fn exclude_range_overlap_with_exact_beginning(_ctx: &mut TestContext) {
-        let mut ranges = [Some(AddressRange::new(0x400_000..0x800_000)), None];
-        PhysicalMemoryManager::exclude_range(&mut ranges, (0x400_000, 0x401_000));
+    fn dealloc(&self, base: usize, page_count: usize) -> Result<(), AllocatorError> {
+        self.0.lock().dealloc(base, page_count)
+    }
 
-        assert_eq!(ranges[0], Some(AddressRange::new(0x401_000..0x800_000)));
-        assert!(ranges[1].is_none());
+    fn used_pages<F: FnMut(usize)>(&self, f: F) {
+        self.0.lock().used_pages(f)
     }
 }
@@ -0,0 +1,165 @@
+//! Caches of small, fixed-size objects carved out of single pages of the buddy allocator.
+//!
+//! Each slab is one page starting with a [`SlabHeader`], followed by objects of the size of the
+//! cache. Objects are aligned on their size, so finding the slab of an object is only a matter of
+//! aligning its address down to the page size.
+
+use super::binary_buddy_allocator::BinaryBuddyAllocator;
+use crate::hal::mm::PAGE_SIZE;
+use hal_core::mm::{self, AllocatorError};
+
+use core::mem;
+use core::ptr;
+
+/// Object sizes of the slab caches, anything bigger is served by the buddy allocator directly.
+pub const SLAB_SIZES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct SlabHeader {
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+pub struct SlabCache {
+    object_size: usize,
+    /// Slabs with at least one free object.
+    partial: *mut SlabHeader,
+    /// Slabs with all of their objects in use.
+    full: *mut SlabHeader,
+    slab_count: usize,
+}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+            slab_count: 0,
+        }
+    }
+
+    pub fn slab_count(&self) -> usize {
+        self.slab_count
+    }
+
+    fn first_object_offset(&self) -> usize {
+        mm::align_up(mem::size_of::<SlabHeader>(), self.object_size)
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (PAGE_SIZE - self.first_object_offset()) / self.object_size
+    }
+
+    pub fn alloc(&mut self, buddy: &mut BinaryBuddyAllocator) -> Result<*mut u8, AllocatorError> {
+        if self.partial.is_null() {
+            self.grow(buddy)?;
+        }
+
+        let slab = self.partial;
+
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+
+            if (*slab).free.is_null() {
+                Self::unlink(&mut self.partial, slab);
+                Self::link(&mut self.full, slab);
+            }
+
+            Ok(object as *mut u8)
+        }
+    }
+
+    /// Give back an object allocated from this cache, the slab is released to the buddy
+    /// allocator when none of its objects are in use anymore.
+    pub fn dealloc(
+        &mut self,
+        object: *mut u8,
+        buddy: &mut BinaryBuddyAllocator,
+    ) -> Result<(), AllocatorError> {
+        let slab = (object as usize & !(PAGE_SIZE - 1)) as *mut SlabHeader;
+        let object = object as *mut FreeObject;
+
+        unsafe {
+            let was_full = (*slab).free.is_null();
+
+            object.write(FreeObject { next: (*slab).free });
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+
+            if was_full {
+                Self::unlink(&mut self.full, slab);
+                Self::link(&mut self.partial, slab);
+            }
+
+            if (*slab).in_use == 0 {
+                Self::unlink(&mut self.partial, slab);
+                self.slab_count -= 1;
+                buddy.dealloc(slab as usize, 0)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn grow(&mut self, buddy: &mut BinaryBuddyAllocator) -> Result<(), AllocatorError> {
+        let page = buddy.alloc(0)?;
+        let slab = page as *mut SlabHeader;
+
+        // Chain all the objects of the new slab together.
+        let mut free = ptr::null_mut();
+        for i in (0..self.objects_per_slab()).rev() {
+            let object =
+                (page + self.first_object_offset() + i * self.object_size) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = object;
+        }
+
+        unsafe {
+            slab.write(SlabHeader {
+                next: ptr::null_mut(),
+                prev: ptr::null_mut(),
+                free,
+                in_use: 0,
+            });
+        }
+
+        Self::link(&mut self.partial, slab);
+        self.slab_count += 1;
+
+        Ok(())
+    }
+
+    fn link(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = *head;
+            if let Some(old_head) = head.as_mut() {
+                old_head.prev = slab;
+            }
+        }
+
+        *head = slab;
+    }
+
+    fn unlink(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+        unsafe {
+            let (next, prev) = ((*slab).next, (*slab).prev);
+
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => *head = next,
+            }
+            if let Some(next) = next.as_mut() {
+                next.prev = prev;
+            }
+        }
+    }
+}
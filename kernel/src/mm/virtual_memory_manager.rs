@@ -0,0 +1,197 @@
+use crate::globals;
+use crate::hal;
+use crate::Error;
+
+use super::PageOwner;
+use hal_core::mm::{AllocatorError, MemoryType, PAddr, PageAlloc, PageMap, Permissions, VAddr};
+use hal_core::AddressRange;
+
+use hal::mm::PAGE_SIZE;
+
+use alloc::vec::Vec;
+use spin::mutex::Mutex;
+
+/// Hands out ranges of the part of the upper half the kernel keeps for its own mappings,
+/// between [`hal::mm::KERNEL_VIRT_START`] and [`hal::mm::KERNEL_VIRT_END`].
+///
+/// Only the reserved ranges are tracked, sorted by address, the gaps between them are free.
+#[derive(Debug)]
+pub struct VirtualMemoryManager {
+    start: usize,
+    end: usize,
+    reserved: Mutex<Vec<AddressRange>>,
+}
+
+impl VirtualMemoryManager {
+    pub const fn new(start: usize, end: usize) -> Self {
+        Self {
+            start,
+            end,
+            reserved: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Reserve `size` bytes of virtual addresses aligned on `alignment`, nothing is mapped.
+    pub fn reserve(&self, size: usize, alignment: usize) -> Result<AddressRange, AllocatorError> {
+        assert!(alignment.is_power_of_two() && alignment >= PAGE_SIZE);
+        let size = hal::mm::align_up(size);
+
+        let mut reserved = self.reserved.lock();
+
+        let mut gap_start = self.start;
+        for i in 0..=reserved.len() {
+            let gap_end = reserved.get(i).map_or(self.end, |range| range.start);
+            let start = hal_core::mm::align_up(gap_start, alignment);
+
+            if start < gap_end && gap_end - start >= size {
+                let range = AddressRange::with_size(start, size);
+                reserved.insert(i, range);
+
+                return Ok(range);
+            }
+
+            if let Some(range) = reserved.get(i) {
+                gap_start = range.end;
+            }
+        }
+
+        Err(AllocatorError::OutOfMemory)
+    }
+
+    /// Give back the range starting at `start` and return it, whatever is still mapped in it stays
+    /// mapped.
+    pub fn release(&self, start: usize) -> Result<AddressRange, AllocatorError> {
+        let mut reserved = self.reserved.lock();
+
+        let index = reserved
+            .binary_search_by_key(&start, |range| range.start)
+            .map_err(|_| AllocatorError::InvalidAddress(start))?;
+
+        Ok(reserved.remove(index))
+    }
+}
+
+/// Map `pages` in the kernel pagetable starting at `start`, all of them are given back if one
+/// fails.
+fn map_pages(start: usize, pages: &[usize]) -> Result<(), Error> {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    for (i, &page) in pages.iter().enumerate() {
+        let mapped = hal::mm::current().map(
+            VAddr::new(start + i * PAGE_SIZE),
+            PAddr::new(page),
+            Permissions::READ | Permissions::WRITE,
+            MemoryType::NormalCacheable,
+            pmm,
+        );
+
+        if let Err(e) = mapped {
+            unmap_pages(start, i);
+            pages[i..].iter().for_each(|&page| {
+                let _ = pmm.dealloc(page, 1);
+            });
+            return Err(e.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmap and give back the `page_count` pages mapped at `start`.
+fn unmap_pages(start: usize, page_count: usize) {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    for i in 0..page_count {
+        if let Some(page) = hal::mm::current().unmap(VAddr::new(start + i * PAGE_SIZE), pmm) {
+            let _ = pmm.dealloc(page.val, 1);
+        }
+    }
+}
+
+/// Allocate and map `page_count` pages at `start`.
+fn populate(start: usize, page_count: usize, owner: PageOwner) -> Result<(), Error> {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    let mut pages = Vec::with_capacity(page_count);
+    for _ in 0..page_count {
+        match pmm.alloc_pages(1, owner) {
+            Ok(page) => pages.push(page),
+            Err(e) => {
+                pages.into_iter().for_each(|page| {
+                    let _ = pmm.dealloc(page, 1);
+                });
+                return Err(e.into());
+            }
+        }
+    }
+
+    map_pages(start, &pages)
+}
+
+/// Allocate `size` bytes of memory that is virtually contiguous, the pages behind it don't have
+/// to be physically contiguous. Returns the virtual address of the allocation.
+pub fn vmalloc(size: usize) -> Result<usize, Error> {
+    let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+    let range = vmm.reserve(size, PAGE_SIZE)?;
+
+    if let Err(e) = populate(
+        range.start,
+        range.count_pages(PAGE_SIZE),
+        PageOwner::Vmalloc,
+    ) {
+        let _ = vmm.release(range.start);
+        return Err(e);
+    }
+
+    Ok(range.start)
+}
+
+/// Unmap and free the memory returned by [`vmalloc`] at `addr`.
+pub fn vfree(addr: usize) -> Result<(), Error> {
+    let range = globals::VIRTUAL_MEMORY_MANAGER.release(addr)?;
+    unmap_pages(range.start, range.count_pages(PAGE_SIZE));
+
+    Ok(())
+}
+
+/// A kernel stack with an unmapped guard page below it, running over the stack faults instead of
+/// corrupting what lies below.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// The whole reservation, guard page included.
+    range: AddressRange,
+}
+
+impl KernelStack {
+    pub fn new(page_count: usize) -> Result<Self, Error> {
+        let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+        let range = vmm.reserve((page_count + 1) * PAGE_SIZE, PAGE_SIZE)?;
+
+        if let Err(e) = populate(range.start + PAGE_SIZE, page_count, PageOwner::KernelStack) {
+            let _ = vmm.release(range.start);
+            return Err(e);
+        }
+
+        Ok(Self { range })
+    }
+
+    /// The initial stack pointer, the stack grows down from there.
+    pub fn top(&self) -> usize {
+        self.range.end
+    }
+
+    /// The lowest usable address, the guard page ends there.
+    pub fn bottom(&self) -> usize {
+        self.range.start + PAGE_SIZE
+    }
+
+    pub fn guard_page(&self) -> AddressRange {
+        AddressRange::with_size(self.range.start, PAGE_SIZE)
+    }
+
+    /// Unmap and free the stack, nothing may be running on it anymore.
+    pub fn destroy(self) {
+        unmap_pages(self.bottom(), self.range.count_pages(PAGE_SIZE) - 1);
+        let _ = globals::VIRTUAL_MEMORY_MANAGER.release(self.range.start);
+    }
+}
@@ -8,6 +8,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     error!("\x1b[31mkernel panic\x1b[0m: {}", info);
 
     error!("hal panic info: {:X?}", hal::panic_info());
+    error!("kernel slide: {:#x}", hal_core::kaslr::slide());
 
     loop {
         unsafe { asm!("wfi") }
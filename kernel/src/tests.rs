@@ -3,14 +3,25 @@ use log::{debug, info, trace};
 use core::slice;
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::device_tree::DeviceTree;
+use crate::drivers::{ns16550::Ns16550, Console, Driver};
 use crate::executable::elf::Elf;
 use crate::globals;
 use crate::hal::{self, mm::PAGE_SIZE};
-use hal_core::mm::{PageAlloc, PageMap, Permissions};
+use crate::irq;
+use crate::mm;
+use crate::Error;
+use hal_core::irq::Trigger;
+use hal_core::mm::{MemoryType, PAddr, PageAlloc, PageMap, Permissions, VAddr};
+use hal_core::AddressRange;
+
+use alloc::{boxed::Box, vec, vec::Vec};
 
 use align_data::include_aligned;
 use align_data::Align4K;
 
+use spin::Once;
+
 pub enum TestResult {
     Success,
     Failure,
@@ -26,6 +37,14 @@ const TESTS: &[Test] = &[
         name: "timer interrupts",
         test: test_timer_interrupt,
     },
+    Test {
+        name: "irq handlers are registered per line",
+        test: test_irq_registry,
+    },
+    Test {
+        name: "pending lines are claimed and completed",
+        test: test_irq_claim_complete,
+    },
     Test {
         name: "pagetable does remap",
         test: test_pagetable_remap,
@@ -34,10 +53,92 @@ const TESTS: &[Test] = &[
         name: "basic elf loader",
         test: test_elf_loader_basic,
     },
+    Test {
+        name: "physical pages are freed",
+        test: test_pmm_dealloc,
+    },
+    Test {
+        name: "kernel heap frees and aligns",
+        test: test_kernel_heap,
+    },
+    Test {
+        name: "pagetable translates, protects and unmaps",
+        test: test_pagetable_unmap,
+    },
+    Test {
+        name: "remapping a live page is seen right away",
+        test: test_pagetable_remap_live,
+    },
+    Test {
+        name: "pagetable maps and splits blocks",
+        test: test_pagetable_blocks,
+    },
+    Test {
+        name: "pagetable walker coalesces and checks mappings",
+        test: test_pagetable_walker,
+    },
+    Test {
+        name: "address spaces are isolated",
+        test: test_address_spaces,
+    },
+    Test {
+        name: "anonymous regions are faulted in zeroed",
+        test: test_demand_zero,
+    },
+    Test {
+        name: "duplicated address spaces copy on write",
+        test: test_copy_on_write,
+    },
+    Test {
+        name: "vmalloc and kernel stacks with guard pages",
+        test: test_kernel_virtual_memory,
+    },
+    Test {
+        name: "physical memory stats and owner tags",
+        test: test_pmm_stats,
+    },
+    Test {
+        name: "the kernel runs in the paging mode it picked",
+        test: test_paging_mode,
+    },
+    Test {
+        name: "irq chips are picked by compatible",
+        test: test_irq_chip_compatible,
+    },
+    Test {
+        name: "ns16550 receives bytes through its interrupt",
+        test: test_ns16550_rx,
+    },
+    Test {
+        name: "kaslr seed and relocations",
+        test: test_kaslr,
+    },
+    Test {
+        name: "early allocator holds many memory regions",
+        test: test_early_allocator,
+    },
+    Test {
+        name: "reserved memory is neither allocated nor mapped",
+        test: test_reserved_memory,
+    },
+    Test {
+        name: "trap frame changes take effect on return",
+        test: test_trap_frame,
+    },
 ];
 
-pub fn launch() -> TestResult {
+/// The device tree the kernel booted with, for the tests looking at what it describes.
+static DEVICE_TREE: Once<DeviceTree> = Once::new();
+
+fn device_tree() -> &'static DeviceTree {
+    DEVICE_TREE
+        .get()
+        .expect("the tests were launched without a device tree")
+}
+
+pub fn launch(device_tree: DeviceTree) -> TestResult {
     let mut res = TestResult::Success;
+    DEVICE_TREE.call_once(|| device_tree);
 
     info!("Launching tests...");
     for (i, test) in TESTS.iter().enumerate() {
@@ -94,12 +195,93 @@ fn test_timer_interrupt() -> TestResult {
     }
 }
 
+fn test_irq_registry() -> TestResult {
+    // No device of the qemu virt machines is wired to this line.
+    const LINE: u32 = 40;
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    let registered = irq::register(LINE, Trigger::Level, |line| {
+        HANDLED.fetch_add(line as usize, Ordering::Relaxed);
+    })
+    .is_ok();
+    let refused = matches!(
+        irq::register(LINE, Trigger::Level, |_| {}),
+        Err(Error::IrqLineInUse(LINE))
+    );
+
+    irq::dispatch(LINE);
+    let dispatched = HANDLED.load(Ordering::Relaxed) == LINE as usize;
+
+    let unregistered = irq::unregister(LINE).is_ok()
+        && matches!(irq::unregister(LINE), Err(Error::NoIrqHandler(LINE)));
+    // Without a handler the line is only disabled.
+    irq::dispatch(LINE);
+
+    if registered
+        && refused
+        && dispatched
+        && unregistered
+        && HANDLED.load(Ordering::Relaxed) == LINE as usize
+    {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+fn test_irq_claim_complete() -> TestResult {
+    // No device of the qemu virt machines is wired to this line.
+    const LINE: u32 = 40;
+    static HANDLED: AtomicUsize = AtomicUsize::new(0);
+
+    let chip = hal::irq::irq_chip().unwrap();
+    irq::register(LINE, Trigger::Edge, |_| {
+        HANDLED.fetch_add(1, Ordering::Relaxed);
+    })
+    .unwrap();
+
+    // A line that wasn't completed isn't signalled again, the second interrupt only gets through
+    // if the first one was.
+    let mut handled = true;
+    let mut injected = true;
+    for count in 1..=2 {
+        if chip.set_pending(LINE).is_err() {
+            injected = false;
+            break;
+        }
+        handled &= (0..10_000_000).any(|_| HANDLED.load(Ordering::Relaxed) == count);
+    }
+
+    irq::unregister(LINE).unwrap();
+
+    if !injected {
+        // The controller only takes interrupts from the devices, the uart raises them instead:
+        // its level triggered line is only signalled again for the next byte once the previous
+        // interrupt was completed.
+        return match ns16550_loopback(b"goose") {
+            Some(received) if received == b"goose" => TestResult::Success,
+            _ => {
+                info!("the interrupt controller can't be made to raise an interrupt");
+                TestResult::Failure
+            }
+        };
+    }
+
+    if handled {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
 fn test_pagetable_remap() -> TestResult {
     info!("Testing the remapping capabilities of our pagetable...");
 
-    let page_src = globals::PHYSICAL_MEMORY_MANAGER.alloc(1).unwrap();
-    let page_src = unsafe { slice::from_raw_parts_mut(page_src as *mut u8, PAGE_SIZE) };
-    let dst_addr = 0x0450_0000;
+    let src_addr = globals::PHYSICAL_MEMORY_MANAGER.alloc(1).unwrap();
+    let page_src =
+        unsafe { slice::from_raw_parts_mut(hal::mm::phys_to_virt(src_addr) as *mut u8, PAGE_SIZE) };
+    let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+    let dst_addr = vmm.reserve(PAGE_SIZE, PAGE_SIZE).unwrap().start;
     let page_dst = unsafe { slice::from_raw_parts(dst_addr as *const u8, hal::mm::PAGE_SIZE) };
     let deadbeef = [0xDE, 0xAD, 0xBE, 0xEF];
 
@@ -111,8 +293,9 @@ fn test_pagetable_remap() -> TestResult {
     hal::mm::current()
         .map(
             hal_core::mm::VAddr::new(dst_addr),
-            hal_core::mm::PAddr::new(page_src.as_ptr() as usize),
+            hal_core::mm::PAddr::new(src_addr),
             Permissions::READ | Permissions::WRITE,
+            MemoryType::NormalCacheable,
             &globals::PHYSICAL_MEMORY_MANAGER,
         )
         .unwrap();
@@ -126,21 +309,968 @@ fn test_pagetable_remap() -> TestResult {
 
     info!("Remapping works");
 
+    hal::mm::current()
+        .unmap(VAddr::new(dst_addr), &globals::PHYSICAL_MEMORY_MANAGER)
+        .unwrap();
+    vmm.release(dst_addr).unwrap();
+    globals::PHYSICAL_MEMORY_MANAGER
+        .dealloc(src_addr, 1)
+        .unwrap();
+
+    TestResult::Success
+}
+
+fn test_pmm_dealloc() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    let before = pmm.stats();
+    let base = pmm.alloc(4).unwrap();
+    // Most likely taken right next to `base`, whatever their place its run has to be merged back
+    // with the free runs around it.
+    let around = [pmm.alloc(4).unwrap(), pmm.alloc(4).unwrap()];
+
+    // Only freeing the whole allocation is allowed.
+    if pmm.dealloc(base, 2).is_ok() || pmm.dealloc(base + PAGE_SIZE, 3).is_ok() {
+        return TestResult::Failure;
+    }
+
+    for block in around {
+        pmm.dealloc(block, 4).unwrap();
+    }
+    pmm.dealloc(base, 4).unwrap();
+
+    if pmm.dealloc(base, 4).is_ok() {
+        return TestResult::Failure;
+    }
+
+    // The free runs are back to what they were: the freed pages were all merged.
+    let after = pmm.stats();
+    if after.free_pages != before.free_pages
+        || after.free_runs != before.free_runs
+        || after.largest_free_run != before.largest_free_run
+    {
+        debug!("pmm before: {:?}, after: {:?}", before, after);
+        return TestResult::Failure;
+    }
+
+    TestResult::Success
+}
+
+fn test_kernel_heap() -> TestResult {
+    #[repr(align(512))]
+    struct Aligned([u8; 200]);
+
+    let before = mm::heap_stats();
+
+    {
+        let small: Vec<Box<usize>> = (0..1000).map(Box::new).collect();
+        let big = vec![0xAAu8; 3 * PAGE_SIZE];
+        let huge = vec![0x55u8; 1024 * PAGE_SIZE];
+        let aligned = Box::new(Aligned([0; 200]));
+
+        debug!("heap while in use: {:?}", mm::heap_stats());
+
+        if (&*aligned as *const Aligned as usize) % 512 != 0 {
+            return TestResult::Failure;
+        }
+
+        if small.iter().enumerate().any(|(i, val)| **val != i)
+            || aligned.0.iter().any(|&b| b != 0)
+            || big.iter().any(|&b| b != 0xAA)
+            || huge.iter().any(|&b| b != 0x55)
+        {
+            return TestResult::Failure;
+        }
+    }
+
+    let after = mm::heap_stats();
+    debug!("heap after drop: {:?}", after);
+
+    if after.allocated_bytes != before.allocated_bytes || after.large_pages != before.large_pages {
+        return TestResult::Failure;
+    }
+
+    TestResult::Success
+}
+
+fn test_pagetable_unmap() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+    let page = pmm.alloc(1).unwrap();
+    let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+    let va = VAddr::new(vmm.reserve(PAGE_SIZE, PAGE_SIZE).unwrap().start);
+
+    hal::mm::current()
+        .map(
+            va,
+            PAddr::new(page),
+            Permissions::READ | Permissions::WRITE,
+            MemoryType::NormalCacheable,
+            pmm,
+        )
+        .unwrap();
+
+    match hal::mm::current().translate(VAddr::new(va.val + 0x123)) {
+        Some((pa, perms))
+            if pa.val == page + 0x123 && perms == Permissions::READ | Permissions::WRITE => {}
+        _ => return TestResult::Failure,
+    }
+
+    hal::mm::current()
+        .protect(
+            AddressRange::with_size(va.val, PAGE_SIZE),
+            Permissions::READ,
+            pmm,
+        )
+        .unwrap();
+    match hal::mm::current().translate(va) {
+        Some((_, perms)) if perms == Permissions::READ => {}
+        _ => return TestResult::Failure,
+    }
+
+    match hal::mm::current().unmap(va, pmm) {
+        Some(pa) if pa.val == page => {}
+        _ => return TestResult::Failure,
+    }
+    if hal::mm::current().translate(va).is_some() || hal::mm::current().unmap(va, pmm).is_some() {
+        return TestResult::Failure;
+    }
+
+    pmm.dealloc(page, 1).unwrap();
+    vmm.release(va.val).unwrap();
+
+    TestResult::Success
+}
+
+fn test_pagetable_remap_live() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+    let pages = [pmm.alloc(1).unwrap(), pmm.alloc(1).unwrap()];
+    let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+    let va = VAddr::new(vmm.reserve(PAGE_SIZE, PAGE_SIZE).unwrap().start);
+
+    for (i, &page) in pages.iter().enumerate() {
+        unsafe { (hal::mm::phys_to_virt(page) as *mut usize).write_volatile(i) };
+    }
+
+    // The first read caches the translation, the remap has to evict it for the second one.
+    for (i, &page) in pages.iter().enumerate() {
+        hal::mm::current()
+            .map(
+                va,
+                PAddr::new(page),
+                Permissions::READ,
+                MemoryType::NormalCacheable,
+                pmm,
+            )
+            .unwrap();
+
+        if unsafe { (va.val as *const usize).read_volatile() } != i {
+            return TestResult::Failure;
+        }
+    }
+
+    hal::mm::current().unmap(va, pmm).unwrap();
+    for page in pages {
+        pmm.dealloc(page, 1).unwrap();
+    }
+    vmm.release(va.val).unwrap();
+
+    TestResult::Success
+}
+
+/// The smallest block a pagetable entry above the last level can map.
+fn smallest_block<P: PageMap>(_: &P) -> usize {
+    P::BLOCK_SIZES[1]
+}
+
+fn test_pagetable_blocks() -> TestResult {
+    let block_size = smallest_block(hal::mm::current());
+
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+    let block = pmm
+        .alloc_pages_aligned(block_size / PAGE_SIZE, block_size, mm::PageOwner::Untagged)
+        .unwrap();
+    let page = pmm.alloc(1).unwrap();
+    let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+    let va = VAddr::new(vmm.reserve(block_size, block_size).unwrap().start);
+
+    hal::mm::current()
+        .map_block(
+            va,
+            PAddr::new(block),
+            block_size,
+            Permissions::READ | Permissions::WRITE,
+            MemoryType::NormalCacheable,
+            pmm,
+        )
+        .unwrap();
+
+    match hal::mm::current().translate(VAddr::new(va.val + block_size - 8)) {
+        Some((pa, _)) if pa.val == block + block_size - 8 => {}
+        _ => return TestResult::Failure,
+    }
+
+    // Remapping a page in the middle of the block splits it, the rest keeps its mapping.
+    let middle = va.val + block_size / 2;
+    unsafe { (middle as *mut usize).write_volatile(0xB10C) };
+    unsafe { ((middle - PAGE_SIZE) as *mut usize).write_volatile(0xB10C) };
+    hal::mm::current()
+        .map(
+            VAddr::new(middle),
+            PAddr::new(page),
+            Permissions::READ | Permissions::WRITE,
+            MemoryType::NormalCacheable,
+            pmm,
+        )
+        .unwrap();
+    unsafe { (middle as *mut usize).write_volatile(0xBA6E) };
+
+    let neighbour = unsafe { ((middle - PAGE_SIZE) as *const usize).read_volatile() };
+    let in_block =
+        unsafe { (hal::mm::phys_to_virt(block + block_size / 2) as *const usize).read_volatile() };
+    let in_page = unsafe { (hal::mm::phys_to_virt(page) as *const usize).read_volatile() };
+    if in_block != 0xB10C || in_page != 0xBA6E || neighbour != 0xB10C {
+        return TestResult::Failure;
+    }
+
+    for addr in (va.val..va.val + block_size).step_by(PAGE_SIZE) {
+        hal::mm::current().unmap(VAddr::new(addr), pmm).unwrap();
+    }
+    pmm.dealloc(page, 1).unwrap();
+    pmm.dealloc(block, block_size / PAGE_SIZE).unwrap();
+    vmm.release(va.val).unwrap();
+
+    TestResult::Success
+}
+
+fn test_pagetable_walker() -> TestResult {
+    let (mut r, mut rw, mut rx) = mm::map_kernel_rwx();
+    let rw_perms = Permissions::READ | Permissions::WRITE;
+    let rx_perms = Permissions::READ | Permissions::EXECUTE;
+    let kernel_mapped = r.all(|range| hal::mm::current().is_mapped(range, Permissions::READ))
+        && rw.all(|range| hal::mm::current().is_mapped(range, rw_perms))
+        && rx.all(|range| hal::mm::current().is_mapped(range, rx_perms));
+    if !kernel_mapped {
+        return TestResult::Failure;
+    }
+
+    // Two physically contiguous pages mapped one by one come out of the walk as a single run.
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+    let vmm = &globals::VIRTUAL_MEMORY_MANAGER;
+    let pages = pmm.alloc_pages(2, mm::PageOwner::Untagged).unwrap();
+    let range = vmm.reserve(3 * PAGE_SIZE, PAGE_SIZE).unwrap();
+    for i in 0..2 {
+        hal::mm::current()
+            .map(
+                VAddr::new(range.start + i * PAGE_SIZE),
+                PAddr::new(pages + i * PAGE_SIZE),
+                rw_perms,
+                MemoryType::NormalCacheable,
+                pmm,
+            )
+            .unwrap();
+    }
+
+    let mut runs = Vec::new();
+    hal::mm::current().walk_coalesced(&mut |mapping| {
+        if range.contains(mapping.va.val) {
+            runs.push(mapping);
+        }
+    });
+    let coalesced = matches!(
+        runs.as_slice(),
+        [run] if run.va.val == range.start && run.pa.val == pages && run.size == 2 * PAGE_SIZE
+            && run.perms == rw_perms && run.mem_type == MemoryType::NormalCacheable
+    );
+    let checked = hal::mm::current().is_mapped(
+        AddressRange::with_size(range.start, 2 * PAGE_SIZE),
+        rw_perms,
+    ) && !hal::mm::current().is_mapped(range, rw_perms)
+        && !hal::mm::current().is_mapped(
+            AddressRange::with_size(range.start, PAGE_SIZE),
+            Permissions::READ,
+        );
+
+    for i in 0..2 {
+        hal::mm::current().unmap(VAddr::new(range.start + i * PAGE_SIZE), pmm);
+    }
+    pmm.dealloc(pages, 2).unwrap();
+    vmm.release(range.start).unwrap();
+
+    if !coalesced || !checked {
+        return TestResult::Failure;
+    }
+
+    TestResult::Success
+}
+
+fn test_address_spaces() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+    let va = VAddr::new(0x0490_0000);
+
+    let spaces: Vec<(hal::mm::AddressSpace, usize)> = (0..2)
+        .map(|i| {
+            let page = pmm.alloc(1).unwrap();
+            unsafe { (hal::mm::phys_to_virt(page) as *mut usize).write_volatile(i) };
+
+            let mut space = hal::mm::AddressSpace::new(pmm).unwrap();
+            space
+                .map(va, PAddr::new(page), Permissions::READ, pmm)
+                .unwrap();
+
+            (space, page)
+        })
+        .collect();
+
+    // Go back and forth so that both translations end up in the TLB.
+    let mut seen = [usize::MAX; 2];
+    for _ in 0..2 {
+        for (i, (space, _)) in spaces.iter().enumerate() {
+            space.activate();
+            seen[i] = unsafe { (va.val as *const usize).read_volatile() };
+        }
+    }
+    hal::mm::activate_kernel_pagetable();
+
+    let kernel_sees_it = hal::mm::current().translate(va).is_some();
+
+    for (space, page) in spaces {
+        space.destroy(pmm);
+        pmm.dealloc(page, 1).unwrap();
+    }
+
+    if seen != [0, 1] || kernel_sees_it {
+        return TestResult::Failure;
+    }
+
     TestResult::Success
 }
 
+fn test_demand_zero() -> TestResult {
+    let region = mm::Region {
+        range: AddressRange::with_size(0x04a0_0000, 2 * PAGE_SIZE),
+        perms: Permissions::READ | Permissions::WRITE,
+        kind: mm::RegionKind::Anonymous,
+    };
+    let overlapping = mm::Region {
+        range: AddressRange::with_size(0x04a0_0000 + PAGE_SIZE, 2 * PAGE_SIZE),
+        ..region
+    };
+
+    let mut address_space = mm::AddressSpace::new().unwrap();
+    address_space.add_region(region).unwrap();
+    if address_space.add_region(overlapping).is_ok() {
+        return TestResult::Failure;
+    }
+
+    address_space.activate();
+    let words = region.range.start as *mut usize;
+    // The read faults the first page in, the write the second one.
+    let zeroed = unsafe { words.add(1).read_volatile() } == 0;
+    unsafe { words.add(PAGE_SIZE / 8 + 1).write_volatile(0xDEAD) };
+    let written = unsafe { words.add(PAGE_SIZE / 8 + 1).read_volatile() } == 0xDEAD;
+    mm::activate_kernel_pagetable();
+
+    let both_mapped = region.range.iter_pages(PAGE_SIZE).all(|page| {
+        address_space
+            .pagetable()
+            .translate(VAddr::new(page))
+            .is_some()
+    });
+    address_space.destroy();
+
+    if zeroed && written && both_mapped {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+fn test_copy_on_write() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+    let va = VAddr::new(0x04b0_0000);
+    let word = va.val as *mut usize;
+
+    let mut parent = mm::AddressSpace::new().unwrap();
+    parent
+        .add_region(mm::Region {
+            range: AddressRange::with_size(va.val, PAGE_SIZE),
+            perms: Permissions::READ | Permissions::WRITE,
+            kind: mm::RegionKind::Anonymous,
+        })
+        .unwrap();
+    parent.activate();
+    unsafe { word.write_volatile(0xC0FFEE) };
+
+    let mut child = parent.duplicate().unwrap();
+    let (shared, perms) = child.pagetable().translate(va).unwrap();
+    let is_shared = parent.pagetable().translate(va).unwrap().0.val == shared.val
+        && perms.contains(Permissions::COPY_ON_WRITE)
+        && !perms.contains(Permissions::WRITE)
+        && pmm.page_refcount(shared.val).unwrap() == 2;
+
+    // The child writes to a copy, the parent still sees the original.
+    child.activate();
+    let child_saw = unsafe { word.read_volatile() };
+    unsafe { word.write_volatile(0xBEEF) };
+    let child_wrote = unsafe { word.read_volatile() };
+    let child_copied = child.pagetable().translate(va).unwrap().0.val != shared.val;
+
+    // The parent is the last one using the page, it gets it back without a copy.
+    parent.activate();
+    let parent_saw = unsafe { word.read_volatile() };
+    unsafe { word.write_volatile(0xF00D) };
+    let parent_kept = parent.pagetable().translate(va).unwrap().0.val == shared.val
+        && pmm.page_refcount(shared.val).unwrap() == 1;
+    mm::activate_kernel_pagetable();
+
+    child.destroy();
+    parent.destroy();
+
+    if is_shared
+        && child_saw == 0xC0FFEE
+        && child_wrote == 0xBEEF
+        && child_copied
+        && parent_saw == 0xC0FFEE
+        && parent_kept
+    {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+fn test_kernel_virtual_memory() -> TestResult {
+    const SIZE: usize = 3 * PAGE_SIZE + 8;
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    // Created before the allocation, it sees the kernel mappings made since all the same.
+    let space = hal::mm::AddressSpace::new(pmm).unwrap();
+
+    let addr = mm::vmalloc(SIZE).unwrap();
+    let memory = unsafe { slice::from_raw_parts_mut(addr as *mut u8, SIZE) };
+    memory.fill(0x5A);
+    let filled = memory.iter().all(|&byte| byte == 0x5A);
+
+    space.activate();
+    let shared = unsafe { ((addr + SIZE - 1) as *const u8).read_volatile() } == 0x5A;
+    hal::mm::activate_kernel_pagetable();
+    space.destroy(pmm);
+
+    mm::vfree(addr).unwrap();
+    let freed =
+        hal::mm::current().translate(VAddr::new(addr)).is_none() && mm::vfree(addr).is_err();
+
+    let stack = mm::KernelStack::new(4).unwrap();
+    let top = (stack.top() - 8) as *mut usize;
+    unsafe { top.write_volatile(0x57AC) };
+    let usable = unsafe { top.read_volatile() } == 0x57AC
+        && hal::mm::current()
+            .translate(VAddr::new(stack.bottom()))
+            .is_some();
+    let guarded = hal::mm::current()
+        .translate(VAddr::new(stack.guard_page().start))
+        .is_none();
+    stack.destroy();
+
+    if filled && shared && freed && usable && guarded {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+fn test_pmm_stats() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    let before = pmm.stats();
+    let base = pmm.alloc_pages(3, mm::PageOwner::Vmalloc).unwrap();
+    let during = pmm.stats();
+    let tagged = pmm.pages_usage().iter().any(|entry| {
+        entry.kind == mm::MemoryMapKind::Allocated(mm::PageOwner::Vmalloc)
+            && entry.range.start <= base
+            && entry.range.end >= base + 3 * PAGE_SIZE
+    });
+    pmm.dealloc(base, 3).unwrap();
+    let after = pmm.stats();
+
+    debug!("pmm while in use: {:?}", during);
+
+    let counted = during.total_pages
+        == during.free_pages + during.allocated_pages + during.metadata_pages
+        && during.free_pages + 3 == before.free_pages
+        && during.allocated_pages == before.allocated_pages + 3
+        && after.free_pages == before.free_pages
+        && after.largest_free_run > 0;
+
+    if counted && tagged {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+#[cfg(target_arch = "riscv64")]
+fn test_paging_mode() -> TestResult {
+    use hal::mm::PagingMode;
+
+    let satp: u64;
+    unsafe { core::arch::asm!("csrr {}, satp", out(reg) satp) };
+    let active = match satp >> 60 {
+        8 => Some(PagingMode::Sv39),
+        9 => Some(PagingMode::Sv48),
+        10 => Some(PagingMode::Sv57),
+        _ => None,
+    };
+    let picked = hal::mm::paging_mode();
+
+    // No cpu may be asked for more than it says it implements.
+    let supported = device_tree()
+        .cpus_mmu_types()
+        .filter_map(PagingMode::from_mmu_type)
+        .all(|mode| picked <= mode);
+
+    debug!("satp: {:#x}, picked {:?}", satp, picked);
+
+    if active == Some(picked) && supported {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn test_paging_mode() -> TestResult {
+    // The translation regime of aarch64 doesn't come in modes to pick from.
+    TestResult::Success
+}
+
+fn test_irq_chip_compatible() -> TestResult {
+    let blob = FdtBuilder::default()
+        .begin_node("")
+        .property_cells("#address-cells", &[2])
+        .property_cells("#size-cells", &[2])
+        .begin_node("interrupt-controller@c000000")
+        .property("compatible", b"acme,unknown-intc\0")
+        .property("interrupt-controller", &[])
+        .property_cells("#interrupt-cells", &[3])
+        .property_cells("reg", &[0, 0x0c00_0000, 0, 0x1000])
+        .end_node()
+        .end_node()
+        .build();
+    let fdt = fdt::Fdt::new(&blob).unwrap();
+    let node = fdt.find_node("/interrupt-controller@c000000").unwrap();
+
+    let chip_address = || {
+        hal::irq::irq_chip()
+            .ok()
+            .map(|chip| chip as *const dyn hal_core::irq::IrqChip as *const u8)
+    };
+    let before = chip_address();
+    let rejected = matches!(
+        hal::irq::init_irq_chip(&node, &fdt, &globals::PHYSICAL_MEMORY_MANAGER),
+        Err(hal_core::Error::NoMatchingIrqChip)
+    );
+    // The chip in use is left alone.
+    let kept = before.is_some() && chip_address() == before;
+
+    if rejected && kept {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+/// Receive `bytes` through the interrupt of the NS16550 console looped back on itself, None if the
+/// console is another kind of uart.
+fn ns16550_loopback(bytes: &[u8]) -> Option<Vec<u8>> {
+    let console = device_tree().console_node()?;
+    let compatible = console.compatible().map(|compatible| compatible.first());
+    if compatible != Some("ns16550a") {
+        return None;
+    }
+    let base = console.reg().unwrap().next().unwrap().starting_address as usize;
+    let line = console
+        .property("interrupts")
+        .and_then(|prop| prop.as_usize())
+        .unwrap() as u32;
+
+    // Take the line over from the board's driver, the bytes received from now on go to this one.
+    let _ = irq::unregister(line);
+    let uart: &'static Ns16550 = Box::leak(Box::new(Ns16550::with_rx_interrupt(
+        hal::mm::phys_to_virt(base),
+        line,
+    )));
+    uart.init_irq().unwrap();
+
+    // Nothing may be logged while the uart is looped back, it would be received too. There is no
+    // receive fifo: each byte is picked up by the handler before the next one is sent.
+    uart.set_loopback(true);
+    let mut received = Vec::with_capacity(bytes.len());
+    for &byte in bytes {
+        uart.write(core::str::from_utf8(&[byte]).unwrap());
+        if let Some(byte) = (0..1_000_000).find_map(|_| uart.read()) {
+            received.push(byte);
+        }
+    }
+    uart.set_loopback(false);
+
+    debug!("ns16550 received {:?}", received);
+
+    Some(received)
+}
+
+fn test_ns16550_rx() -> TestResult {
+    match ns16550_loopback(b"goose") {
+        // The board has another kind of uart, nothing to test.
+        None => TestResult::Success,
+        Some(received) if received == b"goose" => TestResult::Success,
+        Some(_) => TestResult::Failure,
+    }
+}
+
+/// Writes a device tree blob for the tests, nodes and properties in the order they are added.
+#[derive(Default)]
+struct FdtBuilder {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtBuilder {
+    const BEGIN_NODE: u32 = 1;
+    const END_NODE: u32 = 2;
+    const PROP: u32 = 3;
+    const END: u32 = 9;
+
+    fn push_padded(&mut self, bytes: &[u8]) {
+        self.structure.extend_from_slice(bytes);
+        self.structure
+            .resize(hal_core::mm::align_up(self.structure.len(), 4), 0);
+    }
+
+    fn begin_node(mut self, name: &str) -> Self {
+        self.structure
+            .extend_from_slice(&Self::BEGIN_NODE.to_be_bytes());
+        self.push_padded(name.as_bytes());
+        // The nul of the name, it is padded all the same when the name fills a word.
+        if name.len() % 4 == 0 {
+            self.structure.extend_from_slice(&[0; 4]);
+        }
+        self
+    }
+
+    fn end_node(mut self) -> Self {
+        self.structure
+            .extend_from_slice(&Self::END_NODE.to_be_bytes());
+        self
+    }
+
+    fn property(mut self, name: &str, value: &[u8]) -> Self {
+        let name_offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+
+        self.structure.extend_from_slice(&Self::PROP.to_be_bytes());
+        self.structure
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.structure.extend_from_slice(&name_offset.to_be_bytes());
+        self.push_padded(value);
+        self
+    }
+
+    fn property_cells(self, name: &str, cells: &[u32]) -> Self {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value)
+    }
+
+    fn build(mut self) -> Vec<u8> {
+        const HEADER_SIZE: usize = 40;
+        // Only the terminating entry of the memory reservation block.
+        const RESERVATIONS_SIZE: usize = 16;
+
+        self.structure.extend_from_slice(&Self::END.to_be_bytes());
+        let structure_offset = HEADER_SIZE + RESERVATIONS_SIZE;
+        let strings_offset = structure_offset + self.structure.len();
+        let total_size = strings_offset + self.strings.len();
+
+        let header = [
+            0xd00d_feed,
+            total_size as u32,
+            structure_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            17,
+            16,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut fdt: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        fdt.resize(structure_offset, 0);
+        fdt.extend(self.structure);
+        fdt.extend(self.strings);
+
+        fdt
+    }
+}
+
+/// A device tree blob with the `kaslr-seed` and `rng-seed` properties in `/chosen` and a decoy
+/// `kaslr-seed` in another node.
+fn kaslr_device_tree(kaslr_seed: u64, rng_seed: u64) -> Vec<u8> {
+    FdtBuilder::default()
+        .begin_node("")
+        .begin_node("decoy")
+        .property("kaslr-seed", &(!kaslr_seed).to_be_bytes())
+        .end_node()
+        .begin_node("chosen")
+        .property("rng-seed", &rng_seed.to_be_bytes())
+        .property("kaslr-seed", &kaslr_seed.to_be_bytes())
+        .end_node()
+        .end_node()
+        .build()
+}
+
+fn test_kaslr() -> TestResult {
+    // FNV-1a of the big-endian bytes, like the seed is mixed at boot.
+    let hash = |value: u64| {
+        value
+            .to_be_bytes()
+            .iter()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, &byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            })
+    };
+
+    let mut fdt = kaslr_device_tree(0x1234_5678_9abc_def0, 0x0bad_cafe);
+    let fdt_addr = fdt.as_mut_ptr() as usize;
+    let seed = unsafe { hal_core::kaslr::device_tree_seed(fdt_addr) };
+    // The kaslr-seed is zeroed once used, it is still preferred over the rng-seed.
+    let reseed = unsafe { hal_core::kaslr::device_tree_seed(fdt_addr) };
+    let seeded = seed == hash(0x1234_5678_9abc_def0) && reseed == hash(0);
+
+    let mut fdt = kaslr_device_tree(0, 0);
+    fdt[0] = 0;
+    let no_seed = unsafe {
+        hal_core::kaslr::device_tree_seed(0) == 0
+            && hal_core::kaslr::device_tree_seed(fdt.as_mut_ptr() as usize) == 0
+    };
+
+    const LINK_START: usize = 0xffff_ffff_8000_0000;
+    const RELATIVE: u32 = 3;
+    let slide = hal_core::kaslr::slide_for_seed(seed, LINK_START);
+    let slid = slide % hal_core::kaslr::SLIDE_ALIGN == 0 && LINK_START.checked_add(slide).is_some();
+
+    let mut image = [0u64; 3];
+    let rela = |offset: usize, info: u32, addend: usize| hal_core::kaslr::Rela {
+        offset: (LINK_START + offset) as u64,
+        info: info as u64,
+        addend: addend as u64,
+    };
+    let relas = [
+        rela(0, RELATIVE, LINK_START + 0x1000),
+        rela(16, RELATIVE, LINK_START + 0x2000),
+        // Not a relative relocation, left alone.
+        rela(8, RELATIVE + 1, LINK_START + 0x3000),
+    ];
+    let relas_range = relas.as_ptr_range();
+    unsafe {
+        hal_core::kaslr::apply_relocations(
+            image.as_mut_ptr() as usize,
+            LINK_START,
+            (relas_range.start as usize, relas_range.end as usize),
+            RELATIVE,
+            hal_core::kaslr::SLIDE_ALIGN,
+        )
+    };
+    let relocated = image
+        == [
+            (LINK_START + hal_core::kaslr::SLIDE_ALIGN + 0x1000) as u64,
+            0,
+            (LINK_START + hal_core::kaslr::SLIDE_ALIGN + 0x2000) as u64,
+        ];
+
+    if seeded && no_seed && slid && relocated {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+fn test_early_allocator() -> TestResult {
+    // Way more than the 10 regions the allocator used to be limited to, each of them is split.
+    const REGIONS: usize = 64;
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    let size = mm::EarlyAllocator::size_for::<AddressRange>(2 * REGIONS);
+    let page_count = hal::mm::align_up(size) / PAGE_SIZE;
+    let base = pmm.alloc(page_count).unwrap();
+    // Exactly the size asked for, nothing else fits in it.
+    let mut early_allocator = mm::EarlyAllocator::new(AddressRange::with_size(base, size));
+
+    let mut regions: mm::EarlyVec<AddressRange> = early_allocator.alloc_vec(2 * REGIONS).unwrap();
+    for i in 0..REGIONS {
+        regions
+            .push(AddressRange::with_size(i * 0x10000, 0x10000))
+            .unwrap();
+    }
+    for i in 0..REGIONS {
+        mm::exclude_range(
+            &mut regions,
+            AddressRange::with_size(i * 0x10000 + 0x4000, 0x1000),
+        )
+        .unwrap();
+    }
+    regions.sort_unstable_by_key(|region| region.start);
+
+    let split = regions.len() == 2 * REGIONS
+        && regions.chunks_exact(2).enumerate().all(|(i, pair)| {
+            pair[0] == AddressRange::new(i * 0x10000..i * 0x10000 + 0x4000)
+                && pair[1] == AddressRange::new(i * 0x10000 + 0x5000..(i + 1) * 0x10000)
+        });
+    let full = regions
+        .push(AddressRange::new(0x1000_0000usize..0x1000_1000usize))
+        .is_err()
+        && early_allocator.alloc_vec::<AddressRange>(1).is_err();
+
+    pmm.dealloc(base, page_count).unwrap();
+
+    if split && full {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
+fn test_reserved_memory() -> TestResult {
+    let pmm = &globals::PHYSICAL_MEMORY_MANAGER;
+
+    let mut reserved = Vec::new();
+    device_tree().for_all_reserved_memory_regions(|regions| reserved.extend(regions));
+    reserved.extend(
+        pmm.dynamic_reservations()
+            .iter()
+            .map(|placed| placed.memory),
+    );
+
+    debug!("reserved memory: {:?}", reserved);
+
+    let usage = pmm.pages_usage();
+    let handed_out = reserved.iter().any(|reserved| {
+        usage.iter().any(|entry| {
+            entry.range.start < reserved.range.end && reserved.range.start < entry.range.end
+        })
+    });
+
+    let mapped = reserved
+        .iter()
+        .filter(|reserved| reserved.no_map)
+        .flat_map(|reserved| {
+            reserved
+                .range
+                .round_up_to_page(PAGE_SIZE)
+                .iter_pages(PAGE_SIZE)
+        })
+        .any(|page| {
+            hal::mm::current()
+                .translate(VAddr::new(hal::mm::phys_to_virt(page)))
+                .is_some()
+        });
+
+    if !handed_out && !mapped {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
+
 fn test_elf_loader_basic() -> TestResult {
     static TEST_BIN: &[u8] = include_aligned!(Align4K, env!("CARGO_BIN_FILE_TESTS"));
 
+    let mut address_space = hal::mm::AddressSpace::new(&globals::PHYSICAL_MEMORY_MANAGER).unwrap();
+
     let test_bin = Elf::from_bytes(TEST_BIN);
     debug!("[OK] Elf from_bytes {}", env!("CARGO_BIN_FILE_TESTS"));
-    test_bin.load().unwrap();
+    test_bin.load(&mut address_space).unwrap();
     debug!("[OK] Elf loaded");
     let entry_point: extern "C" fn() -> u8 =
         unsafe { core::mem::transmute(test_bin.get_entry_point()) };
     debug!("[OK] Elf loaded, entry point is {:?}", entry_point);
+    address_space.activate();
     entry_point();
+    hal::mm::activate_kernel_pagetable();
     debug!("[OK] Returned for Elf");
 
+    address_space.destroy(&globals::PHYSICAL_MEMORY_MANAGER);
+
     TestResult::Success
 }
+
+/// The value the breakpoint handler of [`test_trap_frame`] hands back.
+const BREAKPOINT_RESULT: u64 = 0x600d;
+
+/// Runs a breakpoint with the result register at 0 and returns it along with how much the stack
+/// pointer moved down, restoring it.
+#[cfg(target_arch = "riscv64")]
+fn breakpoint() -> (u64, usize) {
+    let (result, moved): (u64, usize);
+    unsafe {
+        core::arch::asm!(
+            // The handler skips 4 bytes, not a compressed c.ebreak.
+            ".option push",
+            ".option norvc",
+            "mv {before}, sp",
+            "ebreak",
+            "sub {moved}, {before}, sp",
+            "mv sp, {before}",
+            ".option pop",
+            before = out(reg) _,
+            moved = out(reg) moved,
+            inout("a0") 0u64 => result,
+        );
+    }
+    (result, moved)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn breakpoint() -> (u64, usize) {
+    let (result, moved): (u64, usize);
+    unsafe {
+        core::arch::asm!(
+            "mov {before}, sp",
+            "brk #0",
+            "mov {moved}, sp",
+            "sub {moved}, {before}, {moved}",
+            "mov sp, {before}",
+            before = out(reg) _,
+            moved = out(reg) moved,
+            inout("x0") 0u64 => result,
+        );
+    }
+    (result, moved)
+}
+
+fn test_trap_frame() -> TestResult {
+    hal::irq::set_breakpoint_handler(|frame| {
+        // Past the breakpoint, it would trap again otherwise.
+        frame.pc += 4;
+
+        #[cfg(target_arch = "riscv64")]
+        {
+            frame.regs[10] = BREAKPOINT_RESULT;
+            frame.regs[2] -= 16;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            frame.regs[0] = BREAKPOINT_RESULT;
+            frame.sp -= 16;
+        }
+    });
+
+    let (result, moved) = breakpoint();
+    debug!("breakpoint result: {:#x}, sp moved by {}", result, moved);
+
+    if result == BREAKPOINT_RESULT && moved == 16 {
+        TestResult::Success
+    } else {
+        TestResult::Failure
+    }
+}
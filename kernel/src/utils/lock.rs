@@ -21,3 +21,28 @@ impl<T> Lock<T> {
 
 unsafe impl<T> Send for Lock<T> where T: Sized + Send {}
 unsafe impl<T> Sync for Lock<T> where T: Sized + Send {}
+
+/// A spin lock that also masks interrupts on the current core while it is held, so it can safely
+/// be taken from both regular code and interrupt handlers.
+pub struct IrqLock<T: Sized> {
+    data: spin::Mutex<T>,
+}
+
+impl<T> IrqLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            data: spin::Mutex::new(data),
+        }
+    }
+
+    pub fn lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let state = crate::hal::cpu::save_and_mask_interrupts();
+
+        let res = f(&mut self.data.lock());
+
+        // Only the mask bits are put back, whatever the caller had enabled or not stays so.
+        crate::hal::cpu::restore_interrupts(state);
+
+        res
+    }
+}
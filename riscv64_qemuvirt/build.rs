@@ -1,4 +1,18 @@
 fn main() {
     println!("cargo:rerun-if-changed=src/riscv64_qemuvirt.ld");
     println!("cargo:rustc-link-arg=-Triscv64_qemuvirt/src/riscv64_qemuvirt.ld");
+
+    // Relocations are kept in the image for `_start` to apply, they are also applied by the
+    // linker so that the image holds the link addresses until then.
+    if std::env::var_os("CARGO_FEATURE_KASLR").is_some() {
+        for arg in [
+            "--pie",
+            "--no-dynamic-linker",
+            "-znotext",
+            "-znorelro",
+            "--apply-dynamic-relocs",
+        ] {
+            println!("cargo:rustc-link-arg={}", arg);
+        }
+    }
 }
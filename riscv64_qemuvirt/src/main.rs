@@ -10,13 +10,16 @@ use kernel::drivers::ns16550::*;
 use log::info;
 
 pub const UART_ADDR: usize = 0x1000_0000;
-pub const UART_INTERRUPT_NUMBER: u16 = 10;
+pub const UART_INTERRUPT_NUMBER: u32 = 10;
 
 const LAUNCH_TESTS: bool = cfg!(feature = "launch_tests");
 
 #[no_mangle]
 extern "C" fn k_main(_core_id: usize, device_tree_ptr: usize) -> ! {
-    static NS16550: Ns16550 = Ns16550::new(UART_ADDR);
+    static NS16550: Ns16550 = Ns16550::with_rx_interrupt(
+        kernel::hal::mm::phys_to_virt(UART_ADDR),
+        UART_INTERRUPT_NUMBER,
+    );
     kernel::kernel_console::set_earlyinit_console(&NS16550);
 
     kernel::kernel_console::init_logging().unwrap();
@@ -25,6 +28,21 @@ extern "C" fn k_main(_core_id: usize, device_tree_ptr: usize) -> ! {
 
     kernel::hal::irq::init_exception_handlers();
 
-    let device_tree = kernel::device_tree::DeviceTree::new(device_tree_ptr).unwrap();
+    let device_tree =
+        kernel::device_tree::DeviceTree::new(kernel::hal::mm::phys_to_virt(device_tree_ptr))
+            .unwrap();
+    // Must be known before the kernel pagetable is built.
+    if device_tree.cpus_have_isa_extension("svpbmt") {
+        kernel::hal::mm::enable_svpbmt();
+    }
+    // The smallest mode of all the cpus, the boot hart is probed if one of them doesn't say.
+    let paging_mode = device_tree
+        .cpus_mmu_types()
+        .map(kernel::hal::mm::PagingMode::from_mmu_type)
+        .min()
+        .flatten()
+        .unwrap_or_else(kernel::hal::mm::probe_paging_mode);
+    info!("using {:?} paging", paging_mode);
+    kernel::hal::mm::set_paging_mode(paging_mode);
     kernel::generic_main::generic_main::<LAUNCH_TESTS>(device_tree, &[&NS16550]);
 }